@@ -0,0 +1,8 @@
+use nestools::binaries::nametablec;
+use std::env;
+use std::process;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    process::exit(nametablec::main(&args[0], &args[1..]));
+}
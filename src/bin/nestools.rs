@@ -0,0 +1,8 @@
+use nestools::binaries::nestools;
+use std::env;
+use std::process;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    process::exit(nestools::main(&args[0], &args[1..]));
+}
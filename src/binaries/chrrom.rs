@@ -0,0 +1,151 @@
+//! The CHR-ROM tool, for pulling the CHR data out of an iNES or NES 2.0 ROM file, or replacing
+//! the CHR data of an existing ROM with new data, such as the output of `spritesheetc`.
+//!
+//! ```sh
+//! $ chrrom -h
+//! Usage: chrrom [options]
+//!
+//! Options:
+//!     -i, --input FILE    input file. Defaults to stdin.
+//!     -o, --output FILE   output file name. Defaults to stdout.
+//!     -d, --depfile FILE  write a Makefile-style dependency file for the output
+//!     -q, --quiet         do not print anything but errors
+//!         --check         validate the input and report all problems, without writing
+//!                         any output
+//!     -h, --help          print this help menu
+//!     -b, --bank BANK     the CHR bank to extract, or the first bank to replace when
+//!                         injecting. Extracts all banks if not specified.
+//!     -r, --inject FILE   CHR file to inject into the input ROM. The modified ROM is
+//!                         written to the output.
+//! ```
+//!
+//! The input is always a `.nes` file.  Without `--inject`, the CHR-ROM banks are extracted and
+//! written to the output as raw CHR data, one 8KiB pattern table per bank.  With `--inject`, the
+//! given CHR data (which must be a whole number of 8KiB banks) replaces the ROM's CHR data
+//! starting at `--bank` (or the first bank), and the full modified ROM is written to the output.
+//! The injected data must fit in the CHR-ROM size given by the ROM's header; the header is never
+//! changed.
+//!
+//! This is also available as `nestools chr`.  Unless quiet, a short summary of the ROM header is
+//! printed to stderr.  With `--check`, the ROM (and the injected file, if any) is validated
+//! without writing anything.
+
+use std::fs::File;
+use std::io::Read;
+
+use getopts::{Matches, Options};
+
+use super::{Error, Shared};
+
+use crate::rom::Rom;
+
+/// Config type, built from command line or however you'd like.
+pub struct Config {
+    /// Options shared by all programs.  The input is the ROM file.  The output is either the
+    /// extracted CHR data or the modified ROM.
+    pub shared: Shared,
+
+    /// The bank to extract, or the first bank to replace
+    pub bank: Option<String>,
+
+    /// The CHR file to inject
+    pub inject: Option<String>,
+}
+
+impl Config {
+    /// Add the options specific to this program
+    pub fn options(opts: &mut Options) {
+        opts.optopt("b", "bank", "the CHR bank to extract, or the first bank to replace when injecting. Extracts all banks if not specified.", "BANK");
+        opts.optopt("r", "inject", "CHR file to inject into the input ROM. The modified ROM is written to the output.", "FILE");
+    }
+
+    /// Build the config from parsed matches
+    pub fn from_matches(matches: &Matches, shared: Shared) -> Config {
+        Config {
+            shared,
+            bank: matches.opt_str("b"),
+            inject: matches.opt_str("r"),
+        }
+    }
+}
+
+/// Parse the command line and run.  `args` does not include the program name.  Returns the exit
+/// code.
+pub fn main(program: &str, args: &[String]) -> i32 {
+    super::main(program, args, Config::options, Config::from_matches, run)
+}
+
+/// Entry point for actual running.  Propagates all errors upward.
+pub fn run(config: Config) -> Result<(), Error> {
+    let bank: Option<usize> = match config.bank {
+        Some(ref bank) => match bank.parse() {
+            Ok(bank) => Some(bank),
+            Err(err) => return Err(Error::new("Error parsing bank number", err)),
+        },
+        None => None,
+    };
+
+    let mut bytes = Vec::new();
+    config.shared.open_input("ROM")?.read_to_end(&mut bytes)?;
+
+    let mut rom = match Rom::parse(&bytes) {
+        Ok(rom) => rom,
+        Err(err) => return Err(Error::new("Error parsing ROM", err)),
+    };
+
+    config.shared.note(&format!(
+        "{}: {} mapper {}, {} bytes PRG-ROM, {} bytes CHR-ROM ({} banks)",
+        config.shared.program,
+        if rom.header.nes2 { "NES 2.0" } else { "iNES" },
+        rom.header.mapper,
+        rom.header.prg_rom_size,
+        rom.header.chr_rom_size,
+        rom.chr_banks()));
+
+    match config.inject {
+        Some(ref filename) => {
+            let mut chr = Vec::new();
+            if let Err(err) = File::open(filename).and_then(|mut file| file.read_to_end(&mut chr)) {
+                return Err(Error::new("Error reading CHR file", err));
+            }
+            if let Err(err) = rom.replace_chr(bank.unwrap_or(0), &chr) {
+                return Err(Error::new("Error injecting CHR data", err));
+            }
+            if config.shared.check {
+                return Ok(());
+            }
+
+            let mut output = config.shared.open_output("ROM")?;
+            if let Err(err) = rom.write(&mut output) {
+                return Err(Error::new("Error writing ROM", err));
+            }
+            config.shared.write_depfile(&[filename])?;
+        },
+        None => {
+            let pattern_tables = match bank {
+                Some(bank) => match rom.chr_bank(bank) {
+                    Ok(pattern_table) => vec![pattern_table],
+                    Err(err) => return Err(Error::new("Error extracting CHR bank", err)),
+                },
+                None => rom.pattern_tables(),
+            };
+            if pattern_tables.is_empty() {
+                return Err(Error::new("Error extracting CHR data", crate::rom::Error::SizeError(
+                    String::from("ROM has no CHR-ROM, it uses CHR-RAM"))));
+            }
+            if config.shared.check {
+                return Ok(());
+            }
+
+            let mut output = config.shared.open_output("CHR")?;
+            for pattern_table in &pattern_tables {
+                if let Err(err) = pattern_table.write(&mut output) {
+                    return Err(Error::new("Error writing pattern table", err));
+                }
+            }
+            config.shared.write_depfile(&[])?;
+        },
+    }
+
+    Ok(())
+}
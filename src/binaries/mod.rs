@@ -4,14 +4,28 @@
 //! only uses the actual binary entry point to parse the command line and print errors.  Each
 //! module within this module describes a full binary program and all of its functionality. The
 //! list of submodules here is a good summary of the binaries shipped by this package.
+//!
+//! All of the programs are also available as subcommands of the single [`nestools`](nestools/index.html)
+//! binary.  The options shared by all of them are described by [`Shared`](struct.Shared.html).
 
+pub mod chrrom;
+pub mod nametablec;
+pub mod nestools;
+pub mod palettec;
+pub mod romc;
 pub mod spritesheetc;
 pub mod stagec;
 
 use std::error;
 use std::fmt;
 use std::convert::From;
-use std::io;
+use std::fs::File;
+use std::io::{self, stdin, stdout, Read, Write};
+
+use getopts::{Matches, Options};
+
+use crate::sprites::PatternTable;
+use crate::sprites::serialize::SheetPatternTable;
 
 /// Simple centralized error type for easier handling.
 #[derive(Debug)]
@@ -45,3 +59,242 @@ impl fmt::Display for Error {
         write!(f, "{}", self.description)
     }
 }
+
+/// The language of a generated header file, which decides the form of its include guard.
+#[derive(Clone, Copy)]
+pub enum Language {
+    C,
+    Asm,
+}
+
+/// A generated header file, with its include guard opened.  The guard is named after the program
+/// and the file name, like `STAGEC_BUILD_STAGE_H`.  Write the contents through it, then call
+/// `finish` to close the guard.
+pub struct HeaderFile {
+    file: File,
+    guard: String,
+    language: Language,
+}
+
+impl HeaderFile {
+    /// Create the file and write the opening include guard.
+    pub fn create(filename: &str, program: &str, language: Language) -> io::Result<HeaderFile> {
+        let name = filename.replace(".", "_").replace("/", "_").replace("\\", "_").to_uppercase();
+        let guard = format!("{}_{}", program, name.trim_matches('_'));
+        let mut file = File::create(filename)?;
+        match language {
+            Language::C => {
+                writeln!(file, "#ifndef {}", guard)?;
+                writeln!(file, "#define {}", guard)?;
+            },
+            Language::Asm => {
+                writeln!(file, ".ifndef {}", guard)?;
+                writeln!(file, "{} = 1", guard)?;
+            },
+        }
+        Ok(HeaderFile { file, guard, language })
+    }
+
+    /// Close the include guard and sync the file.
+    pub fn finish(mut self) -> io::Result<()> {
+        match self.language {
+            Language::C => writeln!(self.file, "#endif /* {} */", self.guard)?,
+            Language::Asm => writeln!(self.file, ".endif ; {}", self.guard)?,
+        }
+        self.file.sync_all()
+    }
+}
+
+impl Write for HeaderFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Load a pattern table from a spritesheetc input file, along with the files it depends on: the
+/// file itself and every png file used by its sheets.
+pub fn load_pattern_table(filename: &str) -> Result<(PatternTable, Vec<String>), Error> {
+    let file = match File::open(filename) {
+        Ok(file) => file,
+        Err(err) => return Err(Error::new("Error opening sprites YAML file", err)),
+    };
+    let sheet_pattern_table: SheetPatternTable = match serde_yaml::from_reader(file) {
+        Ok(table) => table,
+        Err(err) => return Err(Error::new("Error loading YAML", err)),
+    };
+
+    let mut dependencies = vec![String::from(filename)];
+    for file in sheet_pattern_table.sheets().filter_map(|sheet| sheet.file()) {
+        if !dependencies.iter().any(|existing| existing == file) {
+            dependencies.push(String::from(file));
+        }
+    }
+
+    match PatternTable::from_sheet_pattern_table(sheet_pattern_table) {
+        Ok(table) => Ok((table, dependencies)),
+        Err(err) => Err(Error::new("Error building pattern table", err)),
+    }
+}
+
+/// Options shared by every program.
+///
+/// ```text
+///     -i, --input FILE    input file. Defaults to stdin.
+///     -o, --output FILE   output file name. Defaults to stdout.
+///     -d, --depfile FILE  write a Makefile-style dependency file for the output
+///     -q, --quiet         do not print anything but errors
+///         --check         validate the input and report all problems, without writing
+///                         any output
+///     -h, --help          print this help menu
+/// ```
+#[derive(Default)]
+pub struct Shared {
+    /// The name the program was run as, like `spritesheetc` or `nestools sprites`, for messages
+    pub program: String,
+    pub input: Option<String>,
+    pub output: Option<String>,
+    pub depfile: Option<String>,
+    pub quiet: bool,
+    pub check: bool,
+}
+
+impl Shared {
+    /// Add the shared options to a getopts Options
+    pub fn options(opts: &mut Options) {
+        opts.optopt("i", "input", "input file.  Defaults to stdin.", "FILE");
+        opts.optopt("o", "output", "output file name. Defaults to stdout.", "FILE");
+        opts.optopt("d", "depfile", "write a Makefile-style dependency file for the output", "FILE");
+        opts.optflag("q", "quiet", "do not print anything but errors");
+        opts.optflag("", "check", "validate the input and report all problems, without writing any output");
+        opts.optflag("h", "help", "print this help menu");
+    }
+
+    /// Pull the shared options out of parsed matches
+    pub fn from_matches(program: &str, matches: &Matches) -> Shared {
+        Shared {
+            program: String::from(program),
+            input: matches.opt_str("i"),
+            output: matches.opt_str("o"),
+            depfile: matches.opt_str("d"),
+            quiet: matches.opt_present("q"),
+            check: matches.opt_present("check"),
+        }
+    }
+
+    /// Open the input file, or stdin if none was given.  `kind` is used for the error message.
+    pub fn open_input(&self, kind: &str) -> Result<Box<dyn Read>, Error> {
+        match self.input {
+            Some(ref filename) => match File::open(filename) {
+                Ok(file) => Ok(Box::new(file)),
+                Err(err) => Err(Error::new(&format!("Error opening input {} file", kind), err)),
+            },
+            None => Ok(Box::new(stdin())),
+        }
+    }
+
+    /// Open the output file, or stdout if none was given.  `kind` is used for the error message.
+    pub fn open_output(&self, kind: &str) -> Result<Box<dyn Write>, Error> {
+        match self.output {
+            Some(ref filename) => match File::create(filename) {
+                Ok(file) => Ok(Box::new(file)),
+                Err(err) => Err(Error::new(&format!("Error opening output {} file", kind), err)),
+            },
+            None => Ok(Box::new(stdout())),
+        }
+    }
+
+    /// Write the dependency file, if one was requested.  The input file is always listed as a
+    /// dependency, followed by any extra dependencies given.
+    pub fn write_depfile(&self, dependencies: &[&str]) -> Result<(), Error> {
+        let filename = match self.depfile {
+            Some(ref filename) => filename,
+            None => return Ok(()),
+        };
+        let target = match self.output {
+            Some(ref output) => output,
+            None => return Err(Error {
+                description: String::from("A dependency file needs an output file to be specified"),
+            }),
+        };
+
+        fn escape(path: &str) -> String {
+            path.replace(' ', "\\ ").replace('#', "\\#").replace('$', "$$")
+        }
+
+        let mut line = format!("{}:", escape(target));
+        for dependency in self.input.iter().map(String::as_str).chain(dependencies.iter().cloned()) {
+            line.push(' ');
+            line.push_str(&escape(dependency));
+        }
+
+        let result = File::create(filename).and_then(|mut file| {
+            writeln!(file, "{}", line)?;
+            file.sync_all()
+        });
+        if let Err(err) = result {
+            return Err(Error::new("Error writing dependency file", err));
+        }
+        Ok(())
+    }
+
+    /// Report the problems found in check mode.  Each problem is printed to stderr, and an error
+    /// is returned if there were any.
+    pub fn report_problems(&self, problems: &[String]) -> Result<(), Error> {
+        let name = self.input.as_deref().unwrap_or("<stdin>");
+        for problem in problems {
+            eprintln!("{}: {}", name, problem);
+        }
+        if problems.is_empty() {
+            self.note(&format!("{}: no problems found", name));
+            Ok(())
+        } else {
+            Err(Error {
+                description: format!("{} problem(s) found in {}", problems.len(), name),
+            })
+        }
+    }
+
+    /// Print an informational message to stderr, unless quiet
+    pub fn note(&self, message: &str) {
+        if !self.quiet {
+            eprintln!("{}", message);
+        }
+    }
+}
+
+/// Common entry point for all programs.  Parses the command line with the shared options and the
+/// program's own, handles help and errors, and returns the exit code.
+///
+/// `args` does not include the program name.
+pub fn main<C>(program: &str, args: &[String], options: fn(&mut Options), config: fn(&Matches, Shared) -> C, run: fn(C) -> Result<(), Error>) -> i32 {
+    let mut opts = Options::new();
+    Shared::options(&mut opts);
+    options(&mut opts);
+
+    let brief = format!("Usage: {} [options]", program);
+
+    let matches = match opts.parse(args) {
+        Ok(m) => { m }
+        Err(f) => {
+            eprintln!("{}", f);
+            eprint!("{}", opts.usage(&brief));
+            return 1;
+        }
+    };
+
+    if matches.opt_present("h") {
+        print!("{}", opts.usage(&brief));
+        return 0;
+    }
+
+    let shared = Shared::from_matches(program, &matches);
+    if let Err(output) = run(config(&matches, shared)) {
+        eprintln!("ERROR: {}", output);
+        return 1;
+    }
+    0
+}
@@ -0,0 +1,218 @@
+//! The nametable compiler, for compiling a full-screen background image into background tiles, a
+//! nametable, and an attribute table.
+//!
+//! ```sh
+//! $ nametablec -h
+//! Usage: nametablec [options]
+//!
+//! Options:
+//!     -i, --input FILE    input file. Defaults to stdin.
+//!     -o, --output FILE   output file name. Defaults to stdout.
+//!     -d, --depfile FILE  write a Makefile-style dependency file for the output
+//!     -q, --quiet         do not print anything but errors
+//!         --check         validate the input and report all problems, without writing
+//!                         any output
+//!     -h, --help          print this help menu
+//!     -t, --attributes FILE
+//!                         output attribute table file name. Not generated if not
+//!                         specified.
+//!     -r, --char FILE     output NES char file name, with the background tiles
+//!                         merged in. Not generated if not specified.
+//!     -s, --sprites FILE  spritesheetc input yaml description file to merge the
+//!                         background tiles into. Starts from an empty pattern table
+//!                         if not specified.
+//!     -g, --page PAGE     the pattern table page to put the background tiles in,
+//!                         either left or right. Defaults to left.
+//!     -n, --name NAME     the name for the background tiles. Defaults to BACKGROUND.
+//!     -l, --stage FILE    stagec input yaml description file whose background_palette
+//!                         is used to detect palettes in a truecolor input image.
+//!     -c, --header FILE   output C header file name.  Not generated if not specified.
+//!     -a, --asm FILE      output asm header file name.  Not generated if not specified.
+//!     -p, --prefix PREFIX the prefix for the header defines.  Defaults to blank.
+//! ```
+//!
+//! The input is an indexed png file that is a whole number of 256x240 screens, as described in
+//! [`nestools::nametable`](../../nametable/index.html).  The output is the nametable of every
+//! screen, 960 bytes each, ordered left-to-right and then top-to-bottom.  The attribute tables are
+//! written in the same order, 64 bytes each.
+//!
+//! If `--stage` is given, the input is instead a truecolor png file.  The palette of every 16x16
+//! area is detected from the colors it uses and the stage's `background_palette`, and areas that
+//! use colors from more than one palette (or from no palette at all) are reported by their pixel
+//! coordinates.
+//!
+//! The background tiles are deduplicated and merged into the chosen page of the pattern table
+//! built from `--sprites` (or an empty one), after that page's sheets.  Any tile identical to a
+//! tile already in the page is reused instead of added.  New tiles are named `{NAME}_{TILE}`, and
+//! the C and ASM headers are exactly those `spritesheetc` writes for the merged pattern table.
+//!
+//! This is also available as `nestools nametable`.
+
+use std::fs::File;
+use std::io::{Read, Write};
+
+use getopts::{Matches, Options};
+
+use super::{load_pattern_table, Error, Shared};
+use super::spritesheetc::{write_asm_header, write_c_header};
+
+use crate::nametable::Background;
+use crate::sprites::{Page, PatternTable};
+use crate::stage::serialize::Stage;
+
+/// Config type, built from command line or however you'd like.
+pub struct Config {
+    /// Options shared by all programs.  The input is the png file, and the output is the
+    /// nametables.
+    pub shared: Shared,
+    pub attributes: Option<String>,
+    pub chr: Option<String>,
+    pub sprites: Option<String>,
+    pub page: Option<String>,
+    pub name: String,
+    pub stage: Option<String>,
+    pub header: Option<String>,
+    pub asm: Option<String>,
+    pub prefix: String,
+}
+
+impl Config {
+    /// Add the options specific to this program
+    pub fn options(opts: &mut Options) {
+        opts.optopt("t", "attributes", "output attribute table file name. Not generated if not specified.", "FILE");
+        opts.optopt("r", "char", "output NES char file name, with the background tiles merged in. Not generated if not specified.", "FILE");
+        opts.optopt("s", "sprites", "spritesheetc input yaml description file to merge the background tiles into. Starts from an empty pattern table if not specified.", "FILE");
+        opts.optopt("g", "page", "the pattern table page to put the background tiles in, either left or right. Defaults to left.", "PAGE");
+        opts.optopt("n", "name", "the name for the background tiles. Defaults to BACKGROUND.", "NAME");
+        opts.optopt("l", "stage", "stagec input yaml description file whose background_palette is used to detect palettes in a truecolor input image.", "FILE");
+        opts.optopt("c", "header", "output C header file name", "FILE");
+        opts.optopt("a", "asm", "output asm header file name", "FILE");
+        opts.optopt("p", "prefix", "the prefix for the header defines", "PREFIX");
+    }
+
+    /// Build the config from parsed matches
+    pub fn from_matches(matches: &Matches, shared: Shared) -> Config {
+        Config {
+            shared,
+            attributes: matches.opt_str("t"),
+            chr: matches.opt_str("r"),
+            sprites: matches.opt_str("s"),
+            page: matches.opt_str("g"),
+            name: matches.opt_str("n").unwrap_or_else(|| String::from("BACKGROUND")),
+            stage: matches.opt_str("l"),
+            header: matches.opt_str("c"),
+            asm: matches.opt_str("a"),
+            prefix: matches.opt_str("p").unwrap_or_default(),
+        }
+    }
+}
+
+/// Parse the command line and run.  `args` does not include the program name.  Returns the exit
+/// code.
+pub fn main(program: &str, args: &[String]) -> i32 {
+    super::main(program, args, Config::options, Config::from_matches, run)
+}
+
+/// Load the background palette from a stage file, along with the palettes file it uses, if any.
+fn load_background_palette(filename: &str) -> Result<([u8; 16], Option<String>), Error> {
+    let file = match File::open(filename) {
+        Ok(file) => file,
+        Err(err) => return Err(Error::new("Error opening stage YAML file", err)),
+    };
+    let mut stage: Stage = match serde_yaml::from_reader(file) {
+        Ok(stage) => stage,
+        Err(err) => return Err(Error::new("Error loading YAML", err)),
+    };
+    if let Err(err) = stage.load_palettes() {
+        return Err(Error::new("Error loading palettes", err));
+    }
+    match stage.background_palette.colors() {
+        Some(&colors) => Ok((colors, stage.palettes)),
+        None => Err(Error::new("Error loading palettes", crate::palette::Error::FormatError(
+            String::from("background palette was not loaded")))),
+    }
+}
+
+/// Entry point for actual running.  Propagates all errors upward.
+pub fn run(config: Config) -> Result<(), Error> {
+    let page = match config.page.as_deref() {
+        None | Some("left") => Page::Left,
+        Some("right") => Page::Right,
+        Some(page) => return Err(Error::new("Error parsing page", crate::sprites::Error::FormatError(
+            format!("page must be left or right, got {}", page)))),
+    };
+
+    let mut bytes = Vec::new();
+    config.shared.open_input("png")?.read_to_end(&mut bytes)?;
+
+    let mut stage_dependencies = Vec::new();
+    let background = match config.stage {
+        Some(ref filename) => {
+            let (background_palette, palettes) = load_background_palette(filename)?;
+            stage_dependencies.push(filename.clone());
+            stage_dependencies.extend(palettes);
+            Background::decode_rgb(&bytes, &background_palette)
+        },
+        None => Background::decode(&bytes),
+    };
+    let background = match background {
+        Ok(background) => background,
+        Err(err) => return Err(Error::new("Error loading background", err)),
+    };
+
+    let (mut pattern_table, mut dependencies) = match config.sprites {
+        Some(ref filename) => load_pattern_table(filename)?,
+        None => (PatternTable::from_bytes(&[]), Vec::new()),
+    };
+    dependencies.extend(stage_dependencies);
+
+    let indices = match background.merge_into(&mut pattern_table, page, &config.name, config.shared.input.as_deref()) {
+        Ok(indices) => indices,
+        Err(err) => return Err(Error::new("Error merging background tiles", err)),
+    };
+
+    if config.shared.check {
+        return Ok(());
+    }
+
+    let mut output = config.shared.open_output("nametable")?;
+    output.write_all(&background.nametables(&indices))?;
+
+    if let Some(ref filename) = config.attributes {
+        if let Err(err) = File::create(filename).and_then(|mut file| file.write_all(&background.attribute_tables())) {
+            return Err(Error::new("Error writing attribute table", err));
+        }
+    }
+
+    if let Some(ref filename) = config.chr {
+        if let Err(err) = File::create(filename).and_then(|mut file| pattern_table.write(&mut file)) {
+            return Err(Error::new("Error writing pattern table", err));
+        }
+    }
+
+    if let Some(ref filename) = config.asm {
+        if let Err(err) = write_asm_header(filename, &config.prefix, &pattern_table) {
+            return Err(Error::new("Error writing ASM header", err));
+        }
+    }
+
+    if let Some(ref filename) = config.header {
+        if let Err(err) = write_c_header(filename, &config.prefix, &pattern_table) {
+            return Err(Error::new("Error writing C header", err));
+        }
+    }
+
+    let dependencies: Vec<&str> = dependencies.iter().map(String::as_str).collect();
+    config.shared.write_depfile(&dependencies)?;
+
+    let added = pattern_table.sheets.last().map(|sheet| sheet.count).unwrap_or(0);
+    config.shared.note(&format!(
+        "{}: {}x{} screens, {} unique tiles added to the {} page",
+        config.shared.program,
+        background.screens_wide(),
+        background.screens_high(),
+        added,
+        page));
+
+    Ok(())
+}
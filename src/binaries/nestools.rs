@@ -0,0 +1,102 @@
+//! The unified binary, which exposes every program as a subcommand.
+//!
+//! ```sh
+//! $ nestools -h
+//! Usage: nestools COMMAND [options]
+//!
+//! Commands:
+//!     sprites     compile sprite sheets into a pattern table and C/ASM headers
+//!     stage       compile a stage description into a binary stage
+//!     chr         extract CHR-ROM from or inject CHR into an iNES ROM file
+//!     rom         assemble PRG, CHR, and a header configuration into an iNES ROM file
+//!     nametable   compile a full-screen background image into tiles, nametables, and
+//!                 attribute tables
+//!     palette     compile named palette sets into binary palettes and C/ASM headers
+//! ```
+//!
+//! Each subcommand takes exactly the same options as its standalone program, including the
+//! [shared options](../struct.Shared.html), so `nestools sprites -i sheets.yaml -o sheets.chr` is
+//! the same as `spritesheetc -i sheets.yaml -o sheets.chr`.  `nestools COMMAND -h` prints the
+//! options for a single command.
+
+use super::{chrrom, nametablec, palettec, romc, spritesheetc, stagec};
+
+/// A single subcommand.
+pub struct Command {
+    /// The name used on the command line
+    pub name: &'static str,
+
+    /// A one-line description for the usage message
+    pub description: &'static str,
+
+    /// The program's main function
+    pub main: fn(&str, &[String]) -> i32,
+}
+
+/// All subcommands, in the order they are listed in the usage message.
+pub const COMMANDS: &[Command] = &[
+    Command {
+        name: "sprites",
+        description: "compile sprite sheets into a pattern table and C/ASM headers",
+        main: spritesheetc::main,
+    },
+    Command {
+        name: "stage",
+        description: "compile a stage description into a binary stage",
+        main: stagec::main,
+    },
+    Command {
+        name: "chr",
+        description: "extract CHR-ROM from or inject CHR into an iNES ROM file",
+        main: chrrom::main,
+    },
+    Command {
+        name: "rom",
+        description: "assemble PRG, CHR, and a header configuration into an iNES ROM file",
+        main: romc::main,
+    },
+    Command {
+        name: "nametable",
+        description: "compile a full-screen background image into tiles, nametables, and attribute tables",
+        main: nametablec::main,
+    },
+    Command {
+        name: "palette",
+        description: "compile named palette sets into binary palettes and C/ASM headers",
+        main: palettec::main,
+    },
+];
+
+fn usage(program: &str) -> String {
+    let mut output = format!("Usage: {} COMMAND [options]\n\nCommands:\n", program);
+    for command in COMMANDS {
+        output.push_str(&format!("    {:<12}{}\n", command.name, command.description));
+    }
+    output
+}
+
+/// Parse the command line and run the chosen subcommand.  `args` does not include the program
+/// name.  Returns the exit code.
+pub fn main(program: &str, args: &[String]) -> i32 {
+    let name = match args.first() {
+        Some(name) => name,
+        None => {
+            eprint!("{}", usage(program));
+            return 1;
+        },
+    };
+
+    if name == "-h" || name == "--help" || name == "help" {
+        print!("{}", usage(program));
+        return 0;
+    }
+
+    match COMMANDS.iter().find(|command| command.name == name) {
+        Some(command) => (command.main)(&format!("{} {}", program, command.name), &args[1..]),
+        None => {
+            eprintln!("Unknown command: {}", name);
+            eprint!("{}", usage(program));
+            1
+        },
+    }
+}
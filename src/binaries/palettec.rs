@@ -0,0 +1,200 @@
+//! The palette compiler, for compiling named palette sets into 32-byte binary palettes and C/ASM
+//! headers.
+//!
+//! ```sh
+//! $ palettec -h
+//! Usage: palettec [options]
+//!
+//! Options:
+//!     -i, --input FILE    input file. Defaults to stdin.
+//!     -o, --output FILE   output file name. Defaults to stdout.
+//!     -d, --depfile FILE  write a Makefile-style dependency file for the output
+//!     -q, --quiet         do not print anything but errors
+//!         --check         validate the input and report all problems, without writing
+//!                         any output
+//!     -h, --help          print this help menu
+//!     -c, --header FILE   output C header file name.  Not generated if not specified.
+//!     -a, --asm FILE      output asm header file name.  Not generated if not specified.
+//!     -p, --prefix PREFIX the prefix for the header defines.  Defaults to blank.
+//!     -r, --preview FILE  output png file previewing every palette. Not generated if
+//!                         not specified.
+//! ```
+//!
+//! The input is a YAML list of
+//! [`PaletteDescription`](../../palette/serialize/struct.PaletteDescription.html)s.  Colors may be
+//! given as names (see [`nestools::palette`](../../palette/index.html)), as `$0F` or `0x0F`
+//! strings, or as plain numbers.  An example might look like this:
+//!
+//! ```yaml
+//! - name: OVERWORLD
+//!   universal: sky_blue
+//!   background:
+//!     - [white, light_gray, $16]
+//!     - [dark_green, green, light_lime]
+//!   sprite:
+//!     - [black, red, pale_orange]
+//! - name: CAVE
+//!   universal: black
+//!   background:
+//!     - [dark_gray, gray, white]
+//! ```
+//!
+//! Each palette is 3 colors, with color 0 filled in, or all 4.  Color 0 of a background palette
+//! defaults to the universal color, and color 0 of a sprite palette defaults to color 0 of the
+//! matching background palette, because $3F10, $3F14, $3F18, and $3F1C are mirrors of $3F00,
+//! $3F04, $3F08, and $3F0C.  Giving a color 0 that breaks that mirroring is an error.  Palettes
+//! that are not given at all are filled with the universal color.
+//!
+//! The output is every palette set, 32 bytes each, in order, ready to be copied to $3F00.  The
+//! headers define each set's name to its index, and `{NAME}_OFFSET` to its offset in the output.
+//! The preview image has a row for each set, with a 16x16 swatch for every color.
+//!
+//! This is also available as `nestools palette`.
+
+use std::io::{self, Write};
+
+use getopts::{Matches, Options};
+
+use super::{Error, HeaderFile, Language, Shared};
+
+use crate::palette::{self, Palette, PALETTE_SIZE};
+use crate::palette::serialize::PaletteDescription;
+
+/// Config type, built from command line or however you'd like.
+pub struct Config {
+    /// Options shared by all programs.  The output is the binary palettes.
+    pub shared: Shared,
+    pub header: Option<String>,
+    pub asm: Option<String>,
+    pub prefix: String,
+    pub preview: Option<String>,
+}
+
+impl Config {
+    /// Add the options specific to this program
+    pub fn options(opts: &mut Options) {
+        opts.optopt("c", "header", "output C header file name", "FILE");
+        opts.optopt("a", "asm", "output asm header file name", "FILE");
+        opts.optopt("p", "prefix", "the prefix for the header defines", "PREFIX");
+        opts.optopt("r", "preview", "output png file previewing every palette. Not generated if not specified.", "FILE");
+    }
+
+    /// Build the config from parsed matches
+    pub fn from_matches(matches: &Matches, shared: Shared) -> Config {
+        Config {
+            shared,
+            header: matches.opt_str("c"),
+            asm: matches.opt_str("a"),
+            prefix: matches.opt_str("p").unwrap_or_default(),
+            preview: matches.opt_str("r"),
+        }
+    }
+}
+
+/// Parse the command line and run.  `args` does not include the program name.  Returns the exit
+/// code.
+pub fn main(program: &str, args: &[String]) -> i32 {
+    super::main(program, args, Config::options, Config::from_matches, run)
+}
+
+/// Write out the C header file.
+fn write_c_header(filename: &str, prefix: &str, palettes: &[Palette]) -> Result<(), io::Error> {
+    let mut file = HeaderFile::create(filename, "PALETTEC", Language::C)?;
+    for (index, palette) in palettes.iter().enumerate() {
+        writeln!(file, "#define {}{} {}", prefix, palette.name, index)?;
+        writeln!(file, "#define {}{}_OFFSET {}", prefix, palette.name, index * PALETTE_SIZE)?;
+    }
+
+    file.finish()
+}
+
+/// Write out the ASM header file.
+fn write_asm_header(filename: &str, prefix: &str, palettes: &[Palette]) -> Result<(), io::Error> {
+    let mut file = HeaderFile::create(filename, "PALETTEC", Language::Asm)?;
+    for (index, palette) in palettes.iter().enumerate() {
+        writeln!(file, "{}{} = {}", prefix, palette.name, index)?;
+        writeln!(file, "{}{}_OFFSET = {}", prefix, palette.name, index * PALETTE_SIZE)?;
+    }
+
+    file.finish()
+}
+
+/// Write the preview png, with a row of 16x16 swatches for each palette.
+fn write_preview(filename: &str, palettes: &[Palette]) -> Result<(), ::lodepng::Error> {
+    const SWATCH: usize = 16;
+    let width = PALETTE_SIZE * SWATCH;
+    let height = palettes.len() * SWATCH;
+    let mut buffer = Vec::with_capacity(width * height * 3);
+    for palette in palettes {
+        for _ in 0..SWATCH {
+            for &color in palette.colors.iter() {
+                for _ in 0..SWATCH {
+                    buffer.extend_from_slice(&palette::rgb(color));
+                }
+            }
+        }
+    }
+    ::lodepng::encode24_file(filename, &buffer, width, height)
+}
+
+/// Entry point for actual running.  Propagates all errors upward.
+pub fn run(config: Config) -> Result<(), Error> {
+    let input = config.shared.open_input("YAML")?;
+
+    let descriptions: Vec<PaletteDescription> = match serde_yaml::from_reader(input) {
+        Ok(descriptions) => descriptions,
+        Err(err) => return Err(Error::new("Error loading YAML", err)),
+    };
+
+    let mut problems: Vec<String> = descriptions.iter().flat_map(PaletteDescription::check).collect();
+    for (index, description) in descriptions.iter().enumerate() {
+        if descriptions[..index].iter().any(|other| other.name == description.name) {
+            problems.push(format!("palette name {} is used more than once", description.name));
+        }
+    }
+
+    if config.shared.check {
+        return config.shared.report_problems(&problems);
+    }
+
+    if let Some(problem) = problems.first() {
+        return Err(Error::new("Invalid palette", palette::Error::FormatError(problem.clone())));
+    }
+
+    let mut palettes = Vec::new();
+    for description in &descriptions {
+        match description.build() {
+            Ok(palette) => palettes.push(palette),
+            Err(err) => return Err(Error::new("Error building palette", err)),
+        }
+    }
+
+    let mut output = config.shared.open_output("palette")?;
+    for palette in &palettes {
+        output.write_all(&palette.colors)?;
+    }
+
+    if let Some(ref filename) = config.asm {
+        if let Err(err) = write_asm_header(filename, &config.prefix, &palettes) {
+            return Err(Error::new("Error writing ASM header", err));
+        }
+    }
+
+    if let Some(ref filename) = config.header {
+        if let Err(err) = write_c_header(filename, &config.prefix, &palettes) {
+            return Err(Error::new("Error writing C header", err));
+        }
+    }
+
+    if let Some(ref filename) = config.preview {
+        if let Err(err) = write_preview(filename, &palettes) {
+            return Err(Error::new("Error writing preview", err));
+        }
+    }
+
+    config.shared.write_depfile(&[])?;
+
+    config.shared.note(&format!("{}: {} palettes", config.shared.program, palettes.len()));
+
+    Ok(())
+}
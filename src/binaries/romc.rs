@@ -0,0 +1,143 @@
+//! The ROM compiler, for assembling PRG data, CHR data, and a header configuration into a full
+//! iNES or NES 2.0 ROM file.
+//!
+//! ```sh
+//! $ romc -h
+//! Usage: romc [options]
+//!
+//! Options:
+//!     -i, --input FILE    input file. Defaults to stdin.
+//!     -o, --output FILE   output file name. Defaults to stdout.
+//!     -d, --depfile FILE  write a Makefile-style dependency file for the output
+//!     -q, --quiet         do not print anything but errors
+//!         --check         validate the input and report all problems, without writing
+//!                         any output
+//!     -h, --help          print this help menu
+//! ```
+//!
+//! The input is a YAML file deserializable into a
+//! [`RomDescription`](../../rom/serialize/struct.RomDescription.html).  An example might look
+//! like this:
+//!
+//! ```yaml
+//! mapper: 4
+//! nes2: true
+//! mirroring: vertical
+//! battery: true
+//! prg_nvram: 8192
+//! prg:
+//!   - build/game.prg
+//! chr:
+//!   - sprites: sprites.yaml
+//!   - file: build/title.chr
+//! ```
+//!
+//! PRG files are concatenated in order, as is the CHR data.  Each `sprites` entry is a
+//! `spritesheetc` manifest compiled into a single 8KiB bank, and each `file` entry is raw CHR data
+//! included as-is.  If no CHR is given, the ROM uses CHR-RAM.  The header's ROM sizes are filled
+//! in from the actual data, and checked against the limits of the mapper for the common mappers
+//! (NROM, MMC1, UxROM, CNROM, MMC3, AxROM, MMC2, and GxROM).
+//!
+//! ## Attributes
+//!
+//! * `mapper`
+//!     * The iNES mapper number
+//! * `submapper`
+//!     * The NES 2.0 submapper number.  Defaults to 0.
+//! * `nes2`
+//!     * Whether to write a NES 2.0 header.  Defaults to false.
+//! * `mirroring`
+//!     * `horizontal`, `vertical`, or `four_screen`.  Defaults to `horizontal`.
+//! * `battery`
+//!     * Whether the cartridge has battery-backed memory.  Defaults to false.
+//! * `timing`
+//!     * `ntsc`, `pal`, `multi`, or `dendy`.  Defaults to `ntsc`.  `multi` and `dendy` need `nes2`.
+//! * `prg_ram`, `prg_nvram`, `chr_ram`, `chr_nvram`
+//!     * NES 2.0 RAM sizes in bytes.  Each must be 0 or a power of two of at least 128, and
+//!       anything but 0 needs `nes2`.
+//! * `trainer`
+//!     * An optional 512-byte trainer file
+//! * `prg`
+//!     * The list of PRG files
+//! * `chr`
+//!     * The list of CHR data
+//!
+//! This is also available as `nestools rom`.  The dependency file lists the input file, every PRG
+//! and CHR file, and every png file used by the sprite sheet manifests.
+
+use getopts::{Matches, Options};
+
+use super::{Error, Shared};
+
+use crate::rom::serialize::RomDescription;
+
+/// Config type, built from command line or however you'd like.
+pub struct Config {
+    /// Options shared by all programs.  The output is the ROM file.
+    pub shared: Shared,
+}
+
+impl Config {
+    /// Add the options specific to this program
+    pub fn options(_opts: &mut Options) {
+    }
+
+    /// Build the config from parsed matches
+    pub fn from_matches(_matches: &Matches, shared: Shared) -> Config {
+        Config {
+            shared,
+        }
+    }
+}
+
+/// Parse the command line and run.  `args` does not include the program name.  Returns the exit
+/// code.
+pub fn main(program: &str, args: &[String]) -> i32 {
+    super::main(program, args, Config::options, Config::from_matches, run)
+}
+
+/// Entry point for actual running.  Propagates all errors upward.
+pub fn run(config: Config) -> Result<(), Error> {
+    let input = config.shared.open_input("YAML")?;
+
+    let description: RomDescription = match serde_yaml::from_reader(input) {
+        Ok(description) => description,
+        Err(err) => return Err(Error::new("Error loading YAML", err)),
+    };
+
+    let rom = match description.build() {
+        Ok(rom) => rom,
+        Err(err) => return Err(Error::new("Error building ROM", err)),
+    };
+
+    let mut problems = rom.header.check();
+    if let Err(err) = rom.header.to_bytes() {
+        problems.push(err.to_string());
+    }
+
+    if config.shared.check {
+        return config.shared.report_problems(&problems);
+    }
+
+    if let Some(problem) = problems.first() {
+        return Err(Error::new("Invalid ROM", crate::rom::Error::SizeError(problem.clone())));
+    }
+
+    let mut output = config.shared.open_output("ROM")?;
+    if let Err(err) = rom.write(&mut output) {
+        return Err(Error::new("Error writing ROM", err));
+    }
+
+    let dependencies = description.dependencies();
+    let dependencies: Vec<&str> = dependencies.iter().map(String::as_str).collect();
+    config.shared.write_depfile(&dependencies)?;
+
+    config.shared.note(&format!(
+        "{}: mapper {}, {} bytes PRG-ROM, {} bytes CHR-ROM",
+        config.shared.program,
+        rom.header.mapper,
+        rom.header.prg_rom_size,
+        rom.header.chr_rom_size));
+
+    Ok(())
+}
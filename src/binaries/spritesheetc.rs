@@ -7,14 +7,39 @@
 //! Usage: spritesheetc [options]
 //! 
 //! Options:
-//!     -i, --input FILE    input yaml description file. Defaults to stdin.
-//!     -o, --char FILE     output NES char file name. Defaults to stdout.
+//!     -i, --input FILE    input file. Defaults to stdin.
+//!     -o, --output FILE   output file name. Defaults to stdout.
+//!     -d, --depfile FILE  write a Makefile-style dependency file for the output
+//!     -q, --quiet         do not print anything but errors
+//!         --check         validate the input and report all problems, without writing
+//!                         any output
+//!     -h, --help          print this help menu
+//!         --char FILE     output NES char file name. Same as --output, kept for
+//!                         compatibility
 //!     -c, --header FILE   output C header file name.  Not generated if not specified.
 //!     -a, --asm FILE      output asm header file name.  Not generated if not specified.
 //!     -p, --prefix PREFIX the prefix for the header defines.  Defaults to blank.
-//!     -h, --help          print this help menu
+//!     -s, --stats         write tile usage statistics to the output instead of the CHR
+//!     -j, --json          write the statistics as JSON
+//!     -m, --metatiles FILE
+//!                         output YAML file with the stage metatiles of every
+//!                         Metatile sheet. Not generated if not specified.
+//!         --upload-asm FILE
+//!                         output ca65 source file with CHR-RAM upload routines.
+//!                         Not generated if not specified.
+//!         --upload-c FILE output C source file with CHR-RAM upload routines. Not
+//!                         generated if not specified.
+//!         --chr-ram       include the tile data in the upload source files
+//!         --segment SEGMENT
+//!                         the segment for the tile data with --chr-ram. Defaults
+//!                         to RODATA.
 //! ```
 //!
+//! This is also available as `nestools sprites`.  The dependency file lists the input file and
+//! every png file used by a sheet.  With `--check`, every sheet and png file is loaded and
+//! validated, all problems (bad images, palette indices, page overflow, duplicate names) are
+//! printed, and nothing is written.
+//!
 //! The format of the input file should be a YAML file that is deserializable by serde_yaml.  An
 //! example input might look something like this: 
 //!
@@ -57,6 +82,54 @@
 //! order, tightly-packed, and the remaining tiles are filled with blanks.  All file paths are
 //! processed relative to your current working directory.
 //!
+//! # Shared sheets
+//!
+//! Besides `left` and `right`, a `shared` (or `auto`) list may be given, for sheets that can live
+//! in either page.  After the fixed sheets are loaded, each shared sheet is assigned to one page
+//! or the other, so that both pages stay within 256 tiles and the pages are filled as evenly as
+//! possible.  Each shared sheet is still kept contiguous, and is placed after the fixed sheets of
+//! its page, in the order given.  The chosen page shows up as the usual `SHEET` part of its
+//! generated names.
+//!
+//! ```yaml
+//! left:
+//!   - type: Simple
+//!     file: font.png
+//!     height: 4
+//!     name: font
+//!     width: 16
+//! shared:
+//!   - type: Simple
+//!     file: hud.png
+//!     height: 2
+//!     name: hud
+//!     width: 8
+//! ```
+//!
+//! # Statistics
+//!
+//! With `--stats`, no CHR or headers are written.  Instead, a report of the tile budget is written
+//! to the output: tiles used and free in each page, the position and size of every sheet, the
+//! largest sheets, tiles within a page that are identical and could be merged, and fully blank
+//! tiles that were pulled from png files.  Adding `--json` writes the same report as a JSON
+//! object.  When the report goes to a file, the tiles used in each page are also printed, unless
+//! quiet.
+//!
+//! # CHR-RAM
+//!
+//! For boards with CHR-RAM, `--upload-asm` and `--upload-c` write source files with routines that
+//! copy tiles into the PPU through $2006 and $2007.  Each page gets a routine that uploads the
+//! whole page, named `{PREFIX}upload_{SHEET}`, and each sheet gets one that uploads just its
+//! tiles, named `{PREFIX}upload_{SHEET}_{NAME}`.  The routines take no arguments and must only be
+//! called while rendering is disabled.  In C, they are all `void` functions taking `void`.
+//!
+//! The tile data is laid out exactly like the output file, which is also exactly how it is laid
+//! out in the PPU.  The routines read it from `{PREFIX}chr_data`, which is imported (or declared
+//! `extern` in C) so that the output file can be included with `.incbin` wherever you like.  With
+//! `--chr-ram`, the data is instead written into the source files themselves, as `.byte` tables
+//! (or a C array) in the segment given by `--segment`.  The assembly routines also reserve 3
+//! bytes of zero page.
+//!
 //! # Types
 //!
 //! Each type is specified in the individual sprite's `type` attribute.  All types have a `file`
@@ -161,7 +234,7 @@
 //!     * The width of the sheet in tiles (that is, in `pixels / 8`)
 //! * `slices`
 //!     * An array of arrays.  Each inner array is a list of numbers each specifying an individual
-//!         tile in the sheet (indexed in the usual left-to-right, top-to-bottom manner)
+//!       tile in the sheet (indexed in the usual left-to-right, top-to-bottom manner)
 //!
 //! The total width of the sheet in pixels is therefore `8 * frame_width * frames`
 //!
@@ -172,32 +245,114 @@
 //! including the number of sub-arrays in `slices`, in the order specified, and `SLICETILE` ranges
 //! from `0` up to but not including the size of the slice in question, also in the order
 //! specified.
+//!
+//! ## Metatile
+//!
+//! A sprite type for the 16x16 blocks that make up stage metatiles.  The sheet is divided into
+//! blocks, numbered left-to-right, top-to-bottom, and each block is pulled in as its four tiles:
+//! top-left, top-right, bottom-left, and bottom-right.  Like a background, the png file may use
+//! palette indices up to 15, where `index / 4` is the palette and `index % 4` the color.  The
+//! palette of each block is guessed as the most common palette among its pixels that are not color
+//! 0.
+//!
+//! With `--metatiles`, every block of every Metatile sheet is written to a YAML file in exactly
+//! the shape of a stage's `metatiles` list, ready to be pasted into a stage description.  Each
+//! metatile is named `{NAME}_{BLOCK}`, and uses the block's tiles and guessed palette.
+//!
+//! ### Attributes
+//!
+//! * `height`
+//!     * The height of the sheet in blocks (that is, in `pixels / 16`)
+//! * `width`
+//!     * The width of the sheet in blocks (that is, in `pixels / 16`)
+//! * `symbols`
+//!     * Optional.  A string with the stage data symbol of each block, in order.  Defaults to the
+//!       digits, then the lowercase and uppercase letters.
+//!
+//! ### Name
+//!
+//! This sprite's parts in the files generated will be
+//! `{PREFIX}{SHEET}_{NAME}_{BLOCK}_{CORNER}`, where `BLOCK` ranges from `0` up to but not
+//! including `height * width`, and `CORNER` is one of `TL`, `TR`, `BL`, and `BR`.  The guessed
+//! palette of each block is also defined, as `{PREFIX}{SHEET}_{NAME}_{BLOCK}_PALETTE`.
 
-use std::io::{self, stdin, stdout, Read, Write};
+use std::io::{self, Write};
 use std::fs::File;
 
+use getopts::{Matches, Options};
+
 use crate::sprites::serialize;
-use crate::sprites::PatternTable;
-use super::Error;
+use crate::sprites::{Page, PatternTable};
+use crate::sprites::stats::Stats;
+use crate::stage::serialize::{Metatile, TileRef};
+use super::{Error, HeaderFile, Language, Shared};
 
 /// Config type, built from command line or however you'd like.
 pub struct Config {
-    pub input: Option<String>,
-    pub chr: Option<String>,
+    /// Options shared by all programs.  The output is the NES char file.
+    pub shared: Shared,
     pub header: Option<String>,
     pub asm: Option<String>,
     pub prefix: String,
+    pub stats: bool,
+    pub json: bool,
+    pub metatiles: Option<String>,
+    pub upload_asm: Option<String>,
+    pub upload_c: Option<String>,
+
+    /// The segment to put the tile data in, if it should be included in the upload source files
+    pub chr_ram: Option<String>,
+}
+
+impl Config {
+    /// Add the options specific to this program
+    pub fn options(opts: &mut Options) {
+        opts.optopt("", "char", "output NES char file name. Same as --output, kept for compatibility", "FILE");
+        opts.optopt("c", "header", "output C header file name", "FILE");
+        opts.optopt("a", "asm", "output asm header file name", "FILE");
+        opts.optopt("p", "prefix", "the prefix for the header defines", "PREFIX");
+        opts.optflag("s", "stats", "write tile usage statistics to the output instead of the CHR");
+        opts.optflag("j", "json", "write the statistics as JSON");
+        opts.optopt("m", "metatiles", "output YAML file with the stage metatiles of every Metatile sheet. Not generated if not specified.", "FILE");
+        opts.optopt("", "upload-asm", "output ca65 source file with CHR-RAM upload routines. Not generated if not specified.", "FILE");
+        opts.optopt("", "upload-c", "output C source file with CHR-RAM upload routines. Not generated if not specified.", "FILE");
+        opts.optflag("", "chr-ram", "include the tile data in the upload source files");
+        opts.optopt("", "segment", "the segment for the tile data with --chr-ram. Defaults to RODATA.", "SEGMENT");
+    }
+
+    /// Build the config from parsed matches
+    pub fn from_matches(matches: &Matches, mut shared: Shared) -> Config {
+        if shared.output.is_none() {
+            shared.output = matches.opt_str("char");
+        }
+        Config {
+            shared,
+            header: matches.opt_str("c"),
+            asm: matches.opt_str("a"),
+            prefix: matches.opt_str("p").unwrap_or_default(),
+            stats: matches.opt_present("s"),
+            json: matches.opt_present("j"),
+            metatiles: matches.opt_str("m"),
+            upload_asm: matches.opt_str("upload-asm"),
+            upload_c: matches.opt_str("upload-c"),
+            chr_ram: if matches.opt_present("chr-ram") {
+                Some(matches.opt_str("segment").unwrap_or_else(|| String::from("RODATA")))
+            } else {
+                None
+            },
+        }
+    }
 }
 
-/// Write out the C header file.  This is used as an easy grouping mechanism in order to catch all
-/// possible IO errors and report them with a helpful error message.
-pub fn write_c_header(filename: &String, prefix: &str, pattern_table: &PatternTable) -> Result<(), io::Error> {
-    let mut file = File::create(filename)?;
+/// Parse the command line and run.  `args` does not include the program name.  Returns the exit
+/// code.
+pub fn main(program: &str, args: &[String]) -> i32 {
+    super::main(program, args, Config::options, Config::from_matches, run)
+}
 
-    let guard_string: String = filename.replace(".", "_").replace("/", "_").replace("\\", "_").to_uppercase();
-    let guard_string_fixed: String = guard_string.trim_matches('_').to_string();
-    writeln!(file, "#ifndef SPRITESHEETC_{}", guard_string_fixed)?;
-    writeln!(file, "#define SPRITESHEETC_{}", guard_string_fixed)?;
+/// Write out the C header file.
+pub fn write_c_header(filename: &str, prefix: &str, pattern_table: &PatternTable) -> Result<(), io::Error> {
+    let mut file = HeaderFile::create(filename, "SPRITESHEETC", Language::C)?;
     for (index, tile) in pattern_table.left.iter().enumerate() {
         if let Some(ref name) = tile.name {
             writeln!(file, "#define {prefix}LEFT_{name} {index}",
@@ -214,20 +369,23 @@ pub fn write_c_header(filename: &String, prefix: &str, pattern_table: &PatternTa
                      index = index)?;
         }
     }
-    writeln!(file, "#endif /* SPRITESHEETC_{} */", guard_string_fixed)?;
+    for sheet in &pattern_table.sheets {
+        for (block, metatile) in sheet.metatiles.iter().enumerate() {
+            writeln!(file, "#define {prefix}{page}_{name}_{block}_PALETTE {palette}",
+                     prefix = prefix,
+                     page = sheet.page,
+                     name = sheet.name,
+                     block = block,
+                     palette = metatile.palette)?;
+        }
+    }
 
-    file.sync_all()
+    file.finish()
 }
 
-/// Write out the ASM header file.  This is used as an easy grouping mechanism in order to catch
-/// all possible IO errors and report them with a helpful error message.
-pub fn write_asm_header(filename: &String, prefix: &str, pattern_table: &PatternTable) -> Result<(), io::Error> {
-    let mut file = File::create(filename)?;
-
-    let guard_string: String = filename.replace(".", "_").replace("/", "_").replace("\\", "_").to_uppercase();
-    let guard_string_fixed: String = guard_string.trim_matches('_').to_string();
-    writeln!(file, ".ifndef SPRITESHEETC_{}", guard_string_fixed)?;
-    writeln!(file, "SPRITESHEETC_{} = 1", guard_string_fixed)?;
+/// Write out the ASM header file.
+pub fn write_asm_header(filename: &str, prefix: &str, pattern_table: &PatternTable) -> Result<(), io::Error> {
+    let mut file = HeaderFile::create(filename, "SPRITESHEETC", Language::Asm)?;
     for (index, tile) in pattern_table.left.iter().enumerate() {
         if let Some(ref name) = tile.name {
             writeln!(file, "{prefix}LEFT_{name} = {index}",
@@ -244,41 +402,369 @@ pub fn write_asm_header(filename: &String, prefix: &str, pattern_table: &Pattern
                      index = index)?;
         }
     }
-    writeln!(file, ".endif ; SPRITESHEETC_{}", guard_string_fixed)?;
+    for sheet in &pattern_table.sheets {
+        for (block, metatile) in sheet.metatiles.iter().enumerate() {
+            writeln!(file, "{prefix}{page}_{name}_{block}_PALETTE = {palette}",
+                     prefix = prefix,
+                     page = sheet.page,
+                     name = sheet.name,
+                     block = block,
+                     palette = metatile.palette)?;
+        }
+    }
 
-    file.sync_all()
+    file.finish()
 }
 
-/// Entry point for actual running.  Propagates all errors upward.
-pub fn run(config: Config) -> Result<(), Error> {
-    let input: Box<Read> = match config.input {
-        Some(filename) => match File::open(filename) {
-            Ok(file) => Box::new(file),
-            Err(err) => return Err(Error::new("Error opening input YAML file", err)),
-        },
-        None => Box::new(stdin()),
+/// The stage metatiles of every metatile sheet, as a YAML fragment to paste into a stage file.
+///
+/// ```
+/// use nestools::binaries::spritesheetc::metatiles_yaml;
+/// use nestools::sprites::{MetatileBlock, Page, PatternTable, SheetPlacement};
+/// use nestools::stage::serialize::{Metatile, TileRef};
+///
+/// let mut pattern_table = PatternTable::from_bytes(&[]);
+/// pattern_table.sheets.push(SheetPlacement {
+///     name: String::from("ground"),
+///     file: None,
+///     page: Page::Right,
+///     start: 8,
+///     count: 8,
+///     metatiles: vec![
+///         MetatileBlock { symbol: '0', palette: 0 },
+///         MetatileBlock { symbol: 'a', palette: 3 },
+///     ],
+/// });
+///
+/// let yaml = metatiles_yaml(&pattern_table).unwrap();
+/// let fragment: serde_yaml::Value = serde_yaml::from_str(&yaml).unwrap();
+/// let metatiles: Vec<Metatile> = serde_yaml::from_value(fragment["metatiles"].clone()).unwrap();
+/// assert_eq!(metatiles.len(), 2);
+/// assert_eq!((metatiles[1].name.as_str(), metatiles[1].symbol, metatiles[1].palette), ("ground_1", 'a', 3));
+/// match metatiles[1].tiles {
+///     [TileRef::Index(12), TileRef::Index(13), TileRef::Index(14), TileRef::Index(15)] => (),
+///     ref tiles => panic!("wrong tiles: {:?}", tiles),
+/// }
+/// assert!(metatiles[1].flags.is_empty());
+/// ```
+pub fn metatiles_yaml(pattern_table: &PatternTable) -> Result<String, Error> {
+    #[derive(Serialize)]
+    struct Fragment {
+        metatiles: Vec<Metatile>,
+    }
+
+    let mut metatiles = Vec::new();
+    for sheet in &pattern_table.sheets {
+        for (block, metatile) in sheet.metatiles.iter().enumerate() {
+            let first = sheet.start + block * 4;
+            metatiles.push(Metatile {
+                name: format!("{}_{}", sheet.name, block),
+                symbol: metatile.symbol,
+                tiles: [
+                    TileRef::Index(first as u8),
+                    TileRef::Index((first + 1) as u8),
+                    TileRef::Index((first + 2) as u8),
+                    TileRef::Index((first + 3) as u8),
+                ],
+                palette: metatile.palette,
+                flags: Vec::new(),
+            });
+        }
+    }
+
+    let yaml = match serde_yaml::to_string(&Fragment { metatiles }) {
+        Ok(yaml) => yaml,
+        Err(err) => return Err(Error::new("Error serializing metatiles", err)),
     };
+    Ok(format!("{}\n", yaml.trim_start_matches("---\n").trim_end()))
+}
 
-    let mut chr: Box<Write> = match config.chr {
-        Some(filename) => match File::create(filename) {
-            Ok(file) => Box::new(file),
-            Err(err) => return Err(Error::new("Error opening output CHR file", err)),
-        },
-        None => Box::new(stdout()),
+/// A single upload routine: its name, the PPU address (which is also the offset into the tile
+/// data), and the length in bytes.
+fn upload_routines(prefix: &str, pattern_table: &PatternTable) -> Vec<(String, usize, usize)> {
+    let base = |page| match page {
+        Page::Left => 0x0000,
+        Page::Right => 0x1000,
     };
+    let mut routines = vec![
+        (format!("{}upload_{}", prefix, Page::Left), base(Page::Left), pattern_table.left.len() * 16),
+        (format!("{}upload_{}", prefix, Page::Right), base(Page::Right), pattern_table.right.len() * 16),
+    ];
+    for sheet in pattern_table.sheets.iter().filter(|sheet| sheet.count > 0) {
+        routines.push((
+            format!("{}upload_{}_{}", prefix, sheet.page, sheet.name),
+            base(sheet.page) + sheet.start * 16,
+            sheet.count * 16));
+    }
+    routines
+}
 
-    let prefix = config.prefix;
+/// Every tile in the pattern table, in output order, along with its name.
+fn named_tiles(pattern_table: &PatternTable) -> impl Iterator<Item = (String, &[u8; 16])> {
+    let left = pattern_table.left.iter().map(|tile| (Page::Left, tile));
+    let right = pattern_table.right.iter().map(|tile| (Page::Right, tile));
+    left.chain(right).map(|(page, tile)| {
+        let name = match tile.name {
+            Some(ref name) => format!("{}_{}", page, name),
+            None => String::new(),
+        };
+        (name, &tile.data)
+    })
+}
+
+/// The ca65 CHR-RAM upload source file.  With `chr_ram`, the tile data is included in the named
+/// segment; otherwise `{prefix}chr_data` is imported.
+///
+/// ```
+/// use nestools::binaries::spritesheetc::upload_asm;
+/// use nestools::sprites::PatternTable;
+///
+/// let pattern_table = PatternTable::from_bytes(&[0xFF; 16]);
+/// let source = upload_asm("", Some("CHR"), &pattern_table);
+/// let lines: Vec<&str> = source.lines().map(str::trim).collect();
+///
+/// // The right page starts at $1000, and holds 256 tiles, stored as a count of 0
+/// let start = lines.iter().position(|&line| line == ".proc upload_RIGHT").unwrap();
+/// assert_eq!(&lines[start + 1..start + 11], &[
+///     "lda #<(chr_data + $1000)", "sta chr_pointer", "lda #>(chr_data + $1000)",
+///     "sta chr_pointer + 1", "lda #0", "sta chr_count", "lda #$10", "ldx #$00",
+///     "jmp chr_upload", ".endproc",
+/// ]);
+///
+/// // The address is written high byte first to $2006, then the data is written to $2007
+/// let upload = lines.iter().position(|&line| line == ".proc chr_upload").unwrap();
+/// assert_eq!(&lines[upload + 1..upload + 4], &["bit $2002", "sta $2006", "stx $2006"]);
+/// assert!(lines[upload..].contains(&"sta $2007"));
+///
+/// // The tile data goes in the given segment, and is exported
+/// assert!(lines.contains(&".export chr_data"));
+/// let data = lines.iter().position(|&line| line == ".segment \"CHR\"").unwrap();
+/// assert_eq!(lines[data + 1], "chr_data:");
+/// assert_eq!(lines[data + 2], ".byte $FF,$FF,$FF,$FF,$FF,$FF,$FF,$FF,$FF,$FF,$FF,$FF,$FF,$FF,$FF,$FF");
+/// assert_eq!(lines.iter().filter(|line| line.starts_with(".byte")).count(), 512);
+///
+/// // Without it, the data is imported instead
+/// let source = upload_asm("", None, &pattern_table);
+/// assert!(source.lines().any(|line| line == ".import chr_data"));
+/// assert!(!source.contains(".byte"));
+/// ```
+pub fn upload_asm(prefix: &str, chr_ram: Option<&str>, pattern_table: &PatternTable) -> String {
+    let mut lines = Vec::new();
+
+    let routines = upload_routines(prefix, pattern_table);
+    lines.push(String::from("; CHR-RAM upload routines, generated by spritesheetc.  Only call these while rendering is"));
+    lines.push(String::from("; disabled."));
+    lines.push(String::new());
+    for (name, _, _) in &routines {
+        lines.push(format!(".export {}", name));
+    }
+    match chr_ram {
+        Some(_) => lines.push(format!(".export {}chr_data", prefix)),
+        None => lines.push(format!(".import {}chr_data", prefix)),
+    }
+    lines.push(String::new());
+    lines.push(String::from(".segment \"ZEROPAGE\""));
+    lines.push(format!("{}chr_pointer: .res 2", prefix));
+    lines.push(format!("{}chr_count: .res 1", prefix));
+    lines.push(String::new());
+    lines.push(String::from(".segment \"CODE\""));
+    for (name, address, length) in &routines {
+        lines.push(String::new());
+        lines.push(format!(".proc {}", name));
+        lines.push(format!("    lda #<({}chr_data + ${:04X})", prefix, address));
+        lines.push(format!("    sta {}chr_pointer", prefix));
+        lines.push(format!("    lda #>({}chr_data + ${:04X})", prefix, address));
+        lines.push(format!("    sta {}chr_pointer + 1", prefix));
+        lines.push(format!("    lda #{}", (length / 16) % 256));
+        lines.push(format!("    sta {}chr_count", prefix));
+        lines.push(format!("    lda #${:02X}", address >> 8));
+        lines.push(format!("    ldx #${:02X}", address & 0xFF));
+        lines.push(format!("    jmp {}chr_upload", prefix));
+        lines.push(String::from(".endproc"));
+    }
+    lines.push(String::new());
+    lines.push(String::from("; Upload chr_count tiles (0 meaning 256) from chr_pointer to the PPU address in A (high)"));
+    lines.push(String::from("; and X (low)"));
+    lines.push(format!(".proc {}chr_upload", prefix));
+    lines.push(String::from("    bit $2002"));
+    lines.push(String::from("    sta $2006"));
+    lines.push(String::from("    stx $2006"));
+    lines.push(String::from("tile:"));
+    lines.push(String::from("    ldy #0"));
+    lines.push(String::from("byte:"));
+    lines.push(format!("    lda ({}chr_pointer), y", prefix));
+    lines.push(String::from("    sta $2007"));
+    lines.push(String::from("    iny"));
+    lines.push(String::from("    cpy #16"));
+    lines.push(String::from("    bne byte"));
+    lines.push(format!("    lda {}chr_pointer", prefix));
+    lines.push(String::from("    clc"));
+    lines.push(String::from("    adc #16"));
+    lines.push(format!("    sta {}chr_pointer", prefix));
+    lines.push(String::from("    bcc next"));
+    lines.push(format!("    inc {}chr_pointer + 1", prefix));
+    lines.push(String::from("next:"));
+    lines.push(format!("    dec {}chr_count", prefix));
+    lines.push(String::from("    bne tile"));
+    lines.push(String::from("    rts"));
+    lines.push(String::from(".endproc"));
+
+    if let Some(segment) = chr_ram {
+        lines.push(String::new());
+        lines.push(format!(".segment \"{}\"", segment));
+        lines.push(format!("{}chr_data:", prefix));
+        for (name, data) in named_tiles(pattern_table) {
+            let bytes: Vec<String> = data.iter().map(|byte| format!("${:02X}", byte)).collect();
+            if name.is_empty() {
+                lines.push(format!("    .byte {}", bytes.join(",")));
+            } else {
+                lines.push(format!("    .byte {} ; {}", bytes.join(","), name));
+            }
+        }
+    }
+
+    lines.push(String::new());
+    lines.join("\n")
+}
+
+/// The C CHR-RAM upload source file.  With `chr_ram`, the tile data is included in the named
+/// segment; otherwise `{prefix}chr_data` is declared extern.
+///
+/// ```
+/// use nestools::binaries::spritesheetc::upload_c;
+/// use nestools::sprites::PatternTable;
+///
+/// let pattern_table = PatternTable::from_bytes(&[0xFF; 16]);
+/// let source = upload_c("", Some("CHR"), &pattern_table);
+/// let lines: Vec<&str> = source.lines().map(str::trim).collect();
+///
+/// // The address is written high byte first to $2006, then the data is written to $2007
+/// assert!(lines.contains(&"#define PPU_ADDRESS (*(volatile unsigned char *)0x2006)"));
+/// assert!(lines.contains(&"#define PPU_DATA (*(volatile unsigned char *)0x2007)"));
+/// let upload = lines.iter().position(|&line| line.starts_with("static void chr_upload(")).unwrap();
+/// assert_eq!(&lines[upload + 2..upload + 7], &[
+///     "(void)PPU_STATUS;", "PPU_ADDRESS = address >> 8;", "PPU_ADDRESS = address & 0xFF;",
+///     "while (length--) {", "PPU_DATA = *data++;",
+/// ]);
+/// assert!(lines.contains(&"chr_upload(0x1000, chr_data + 0x1000, 4096);"));
+///
+/// // The tile data goes in the given segment
+/// let data = lines.iter().position(|&line| line == "#pragma rodata-name (push, \"CHR\")").unwrap();
+/// assert_eq!(lines[data + 1], "const unsigned char chr_data[8192] = {");
+///
+/// // Without it, the data is declared extern instead
+/// let source = upload_c("", None, &pattern_table);
+/// assert!(source.lines().any(|line| line == "extern const unsigned char chr_data[];"));
+/// assert!(!source.contains("#pragma"));
+/// ```
+pub fn upload_c(prefix: &str, chr_ram: Option<&str>, pattern_table: &PatternTable) -> String {
+    let mut lines = Vec::new();
+
+    lines.push(String::from("/* CHR-RAM upload routines, generated by spritesheetc.  Only call these while rendering is"));
+    lines.push(String::from(" * disabled. */"));
+    lines.push(String::new());
+    lines.push(format!("#define {}PPU_STATUS (*(volatile unsigned char *)0x2002)", prefix));
+    lines.push(format!("#define {}PPU_ADDRESS (*(volatile unsigned char *)0x2006)", prefix));
+    lines.push(format!("#define {}PPU_DATA (*(volatile unsigned char *)0x2007)", prefix));
+    lines.push(String::new());
+
+    match chr_ram {
+        Some(segment) => {
+            let tiles: Vec<_> = named_tiles(pattern_table).collect();
+            lines.push(format!("#pragma rodata-name (push, \"{}\")", segment));
+            lines.push(format!("const unsigned char {}chr_data[{}] = {{", prefix, tiles.len() * 16));
+            for (name, data) in tiles {
+                let bytes: Vec<String> = data.iter().map(|byte| format!("0x{:02X}", byte)).collect();
+                if name.is_empty() {
+                    lines.push(format!("    {},", bytes.join(", ")));
+                } else {
+                    lines.push(format!("    {}, /* {} */", bytes.join(", "), name));
+                }
+            }
+            lines.push(String::from("};"));
+            lines.push(String::from("#pragma rodata-name (pop)"));
+        },
+        None => lines.push(format!("extern const unsigned char {}chr_data[];", prefix)),
+    }
+
+    lines.push(String::new());
+    lines.push(format!("static void {}chr_upload(unsigned int address, const unsigned char *data, unsigned int length)", prefix));
+    lines.push(String::from("{"));
+    lines.push(format!("    (void){}PPU_STATUS;", prefix));
+    lines.push(format!("    {}PPU_ADDRESS = address >> 8;", prefix));
+    lines.push(format!("    {}PPU_ADDRESS = address & 0xFF;", prefix));
+    lines.push(String::from("    while (length--) {"));
+    lines.push(format!("        {}PPU_DATA = *data++;", prefix));
+    lines.push(String::from("    }"));
+    lines.push(String::from("}"));
+
+    for (name, address, length) in upload_routines(prefix, pattern_table) {
+        lines.push(String::new());
+        lines.push(format!("void {}(void)", name));
+        lines.push(String::from("{"));
+        lines.push(format!("    {}chr_upload(0x{:04X}, {}chr_data + 0x{:04X}, {});", prefix, address, prefix, address, length));
+        lines.push(String::from("}"));
+    }
+
+    lines.push(String::new());
+    lines.join("\n")
+}
+
+/// Entry point for actual running.  Propagates all errors upward.
+pub fn run(config: Config) -> Result<(), Error> {
+    let input = config.shared.open_input("YAML")?;
 
     let sheet_pattern_table: serialize::SheetPatternTable = match serde_yaml::from_reader(input) {
         Ok(table) => table,
         Err(err) => return Err(Error::new("Error loading YAML", err)),
     };
 
+    if config.shared.check {
+        return config.shared.report_problems(&PatternTable::check(&sheet_pattern_table));
+    }
+
+    let mut files: Vec<String> = Vec::new();
+    for file in sheet_pattern_table.sheets().filter_map(|sheet| sheet.file()) {
+        if !files.iter().any(|existing| existing == file) {
+            files.push(String::from(file));
+        }
+    }
+
     let pattern_table = match PatternTable::from_sheet_pattern_table(sheet_pattern_table) {
         Ok(table) => table,
         Err(err) => return Err(Error::new("Error building pattern table", err)),
     };
 
+    if config.stats {
+        let stats = Stats::from_pattern_table(&pattern_table);
+        let mut output = config.shared.open_output("statistics")?;
+        if config.json {
+            if let Err(err) = serde_json::to_writer_pretty(&mut output, &stats) {
+                return Err(Error::new("Error writing statistics", err));
+            }
+            writeln!(output)?;
+        } else {
+            write!(output, "{}", stats)?;
+        }
+
+        // The report itself is only seen on the terminal if it went to stdout
+        if config.shared.output.is_some() {
+            let used = |page| stats.pages.iter()
+                .filter(|stats| stats.page == page)
+                .map(|stats| stats.used)
+                .sum::<usize>();
+            config.shared.note(&format!("{}: left page {}/256 tiles, right page {}/256 tiles",
+                config.shared.program,
+                used(Page::Left),
+                used(Page::Right)));
+        }
+        return Ok(());
+    }
+
+    let mut chr = config.shared.open_output("CHR")?;
+
+    let prefix = config.prefix;
+
     if let Err(err) = pattern_table.write(&mut chr) {
         return Err(Error::new("Error writing pattern table", err));
     }
@@ -295,5 +781,29 @@ pub fn run(config: Config) -> Result<(), Error> {
         }
     }
 
+    if let Some(filename) = config.metatiles {
+        let yaml = metatiles_yaml(&pattern_table)?;
+        if let Err(err) = File::create(&filename).and_then(|mut file| file.write_all(yaml.as_bytes())) {
+            return Err(Error::new("Error writing metatiles YAML", err));
+        }
+    }
+
+    if let Some(filename) = config.upload_asm {
+        let source = upload_asm(&prefix, config.chr_ram.as_deref(), &pattern_table);
+        if let Err(err) = File::create(&filename).and_then(|mut file| file.write_all(source.as_bytes())) {
+            return Err(Error::new("Error writing ASM upload source", err));
+        }
+    }
+
+    if let Some(filename) = config.upload_c {
+        let source = upload_c(&prefix, config.chr_ram.as_deref(), &pattern_table);
+        if let Err(err) = File::create(&filename).and_then(|mut file| file.write_all(source.as_bytes())) {
+            return Err(Error::new("Error writing C upload source", err));
+        }
+    }
+
+    let files: Vec<&str> = files.iter().map(String::as_str).collect();
+    config.shared.write_depfile(&files)?;
+
     Ok(())
 }
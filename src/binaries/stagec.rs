@@ -4,52 +4,1033 @@
 //! The usage can be retrieved exactly how you'd expect:
 //! 
 //! ```sh
-//! $ spritesheetc -h
-//! Usage: spritesheetc [options]
+//! $ stagec -h
+//! Usage: stagec [options]
 //! 
 //! Options:
-//!     -i, --input FILE    input yaml description file. Defaults to stdin.
-//!     -o, --output FILE   output NES stage file name. Defaults to stdout.
+//!     -i, --input FILE    input file. Defaults to stdin.
+//!     -o, --output FILE   output file name. Defaults to stdout.
+//!     -d, --depfile FILE  write a Makefile-style dependency file for the output
+//!     -q, --quiet         do not print anything but errors
+//!         --check         validate the input and report all problems, without writing
+//!                         any output
 //!     -h, --help          print this help menu
+//!     -s, --sprites FILE  spritesheetc input yaml description file to look up tile
+//!                         names in. Overrides the stage's sprites attribute.
+//!         --decompile     read a binary stage and write it back out as YAML
+//!         --bank          read a bank description and pack every stage it lists into
+//!                         one bank, or a run of fixed-size banks
+//!     -z, --size SIZE     with --decompile, the height of a horizontal stage or the
+//!                         width of a vertical stage, in metatiles. Defaults to 15
+//!                         for horizontal stages and 16 for vertical ones.
+//!     -c, --header FILE   output C header file name.  Not generated if not specified.
+//!     -a, --asm FILE      output asm header file name.  Not generated if not specified.
+//!     -p, --prefix PREFIX the prefix for the header defines.  Defaults to blank.
+//!     -t, --tiled FILE    export the stage as a Tiled map, with its tileset image
+//!                         written next to it as a png
+//!     -y, --yaml FILE     write the stage as YAML after importing its Tiled map, with
+//!                         the data and objects filled in
+//!         --preview FILE  render the whole stage to a png
+//!         --chr FILE      compiled CHR file to render the preview and Tiled tileset
+//!                         from, instead of the sprites file
 //! ```
+//!
+//! Either palette may be given as the name of a palette set in the file named by the stage's
+//! `palettes` attribute, which is a `palettec` input file, instead of as 16 inline colors:
+//!
+//! ```yaml
+//! palettes: palettes.yaml
+//! background_palette: OVERWORLD
+//! sprite_palette: OVERWORLD
+//! ```
+//!
+//! Similarly, any of a metatile's `tiles` may be given by name instead of by index, using the
+//! names spritesheetc defines (without the prefix), like `LEFT_ground_0`.  Names are looked up in
+//! the pattern table built from the spritesheetc input file given by `--sprites` or by the stage's
+//! `sprites` attribute.  Every name must exist, and every named tile must come from the same
+//! page: the stage's `page` (`left` or `right`) if it is given.
+//!
+//! ```yaml
+//! sprites: sprites.yaml
+//! page: left
+//! metatiles:
+//!   - name: ground
+//!     symbol: '#'
+//!     palette: 1
+//!     tiles: [LEFT_ground_0, LEFT_ground_1, LEFT_ground_2, LEFT_ground_3]
+//! ```
+//!
+//! # Headers
+//!
+//! `--header` and `--asm` write C and asm headers of constants for the game code, each named with
+//! the `--prefix`.  Every stage's headers define `{PREFIX}STAGE_WIDTH` and `{PREFIX}STAGE_HEIGHT`
+//! in metatiles, `{PREFIX}BODY_LENGTH` for the compressed body in bytes, and
+//! `{PREFIX}ORIENTATION` as either `{PREFIX}ORIENTATION_HORIZONTAL` or
+//! `{PREFIX}ORIENTATION_VERTICAL`, which is the orientation bit of the attribute byte.  They also
+//! define `{PREFIX}METATILE_COUNT`, and the index of each metatile named by its `name`, so that
+//! game code can refer to metatiles by name, like when changing a block at runtime:
+//!
+//! ```c
+//! #define METATILE_sky 0
+//! #define METATILE_ground 1
+//! ```
+//!
+//! The sections below describe the constants for flags, objects, screens, the stage header, and
+//! packed palettes.  Every name that goes into a constant may only have letters, digits, and
+//! underscores, and no two constants may have the same name, so a metatile can not be named
+//! `COUNT`.  Nothing is written if they do.
+//!
+//! # Tiled
+//!
+//! Instead of writing the `data` by hand, big stages can be drawn in
+//! [Tiled](https://www.mapeditor.org/) and imported from a `.tmx` or `.json` map by giving the
+//! stage a `tiled` attribute.  Each tile's GID is mapped to the metatile with the given name, and
+//! the objects of the map's object layer are added to the stage's `objects`:
+//!
+//! ```yaml
+//! tiled:
+//!   map: level1.tmx
+//!   layer: terrain
+//!   object_layer: objects
+//!   tiles:
+//!     0: sky
+//!     1: ground
+//! ```
+//!
+//! A Tiled object's type (or class) is its kind, its name is its type, and an integer
+//! `parameter` custom property is its parameter.  The details are in
+//! [`nestools::stage::tiled`](../../stage/tiled/index.html).  With `--yaml`, the imported stage is
+//! also written out as a plain stage file, so a stage can be moved off of Tiled or inspected.
+//!
+//! Going the other way, `--tiled` exports the stage as a `.tmx` map, so that existing stages can
+//! be moved into Tiled without redrawing them.  The map's tileset is embedded, with its image
+//! written next to the map as a png rendered from the pattern table built from the sprites file,
+//! or from the CHR file given by `--chr`, so exporting needs one of them.  Each tileset tile keeps
+//! its metatile's `symbol` as a custom property, and the import uses it for any GID not in the
+//! `tiles` table, so an exported map imports back into exactly the same stage:
+//!
+//! ```yaml
+//! tiled:
+//!   map: level1.tmx
+//! ```
+//!
+//! # Previews
+//!
+//! `--preview` renders the whole stage to a png, laid out just as its data is, so that changes to
+//! a stage can be looked at (in a pull request, say) without building the ROM.  Each metatile is
+//! drawn 16 pixels square from its four tiles, in the colors of its palette in the stage's
+//! `background_palette`.  The tiles come from the compiled CHR file given by `--chr`, if there is
+//! one, and otherwise from the pattern table built from the sprites file.  Tile names are still
+//! looked up in the sprites file, so a stage that names its tiles needs one either way:
+//!
+//! ```sh
+//! $ stagec -i level1.yaml -o level1.bin --chr tiles.chr --preview level1.png
+//! ```
+//!
+//! # Flags
+//!
+//! Each metatile may have a list of `flags`, which are packed along with its palette into its
+//! attribute byte: the palette is in the low 2 bits, and each flag is one of the 6 bits above it,
+//! in the order of the stage's `flags` list.  The list defaults to `solid`, `hazard`, `ladder`,
+//! `water`, and `one_way`, and may be given at the top of the stage to pick different flags or
+//! add up to 6 of its own:
+//!
+//! ```yaml
+//! flags: [solid, hazard, ladder, water, one_way, breakable]
+//! metatiles:
+//!   - name: spikes
+//!     symbol: '^'
+//!     palette: 2
+//!     tiles: [5, 6, 7, 8]
+//!     flags: [solid, hazard]
+//! ```
+//!
+//! The headers define `{PREFIX}METATILE_PALETTE_MASK` to the mask of the palette bits, and
+//! `{PREFIX}FLAG_{flag}` to the mask of each flag's bit, like `FLAG_solid = 4`.
+//!
+//! # Objects
+//!
+//! The stage's `objects` place enemies, items, the player start, and doors, by the column (`x`)
+//! and line (`y`) of the stage data they are at.  Every object but the player start has a `type`,
+//! given by index or by name from the object types file named by the stage's `object_types`
+//! attribute, and a `parameter` byte for the game to use, like the stage a door leads to:
+//!
+//! ```yaml
+//! object_types: objects.yaml
+//! objects:
+//!   - {kind: player_start, x: 1, y: 12}
+//!   - {kind: enemy, type: walker, x: 20, y: 12, parameter: 1}
+//!   - {kind: item, type: coin, x: 8, y: 9}
+//!   - {kind: door, type: pipe, x: 60, y: 11, parameter: 3}
+//! ```
+//!
+//! The object types file is shared by every stage, and lists up to 64 type names for each of the
+//! `enemy`, `item`, and `door` kinds, as described in
+//! [`ObjectTypes`](../../stage/objects/struct.ObjectTypes.html).  A type's id is its kind in the
+//! high 2 bits and its index in the low 6.  When the stage has an object types file, the headers
+//! define `{PREFIX}OBJECT_KIND_MASK`, `{PREFIX}OBJECT_{KIND}` for each kind's bits, and
+//! `{PREFIX}{KIND}_{type}` for each type's id, like `ENEMY_walker = 64`.
+//!
+//! The objects are compiled into a table after the stage body: the number of objects, then 4
+//! bytes for each, sorted in the order they scroll into view.  Each is the object's position along
+//! the scroll direction (`x` for a horizontal stage, `y` for a vertical one), its position across
+//! it, its type id, and its parameter.
+//!
+//! # Screens
+//!
+//! A stage with a `screens` attribute is split into screens, so that a scrolling engine can load
+//! it a screen at a time, starting from any screen.  The screen size defaults to a full NES screen
+//! of 16 by 15 metatiles, and either side may be given:
+//!
+//! ```yaml
+//! orientation: horizontal
+//! screens: {width: 16, height: 15}
+//! ```
+//!
+//! A horizontal stage must be exactly one screen high and a whole number of screens wide, and a
+//! vertical stage exactly one screen wide and a whole number of screens high.  Each screen is
+//! compressed on its own, to at most 255 bytes.  Bit 5 of the attribute byte is set, and in place
+//! of the body length there is the number of screens, followed by a 16-bit little-endian offset
+//! to each screen from the start of the first, and then the offset of the end.  The headers
+//! define `{PREFIX}SCREEN_WIDTH`, `{PREFIX}SCREEN_HEIGHT`, and `{PREFIX}SCREEN_COUNT`.
+//!
+//! # Music and the stage header
+//!
+//! A stage may set its `music` track, by index or by name in the track list file given by its
+//! `tracks` attribute, whether it has `scroll_lock` so it can not be scrolled back toward its
+//! start, and a `time_limit` in seconds:
+//!
+//! ```yaml
+//! tracks: music.yaml
+//! music: castle
+//! scroll_lock: true
+//! time_limit: 300
+//! ```
+//!
+//! The track list is a YAML list of up to 256 track names, in order of index, like
+//! `[overworld, underground, castle, boss]`.
+//!
+//! If any of these are set, bit 7 of the attribute byte is set and a versioned header follows it,
+//! before the palettes.  The layout is described in
+//! [`nestools::stage::header`](../../stage/header/index.html).  The headers define
+//! `{PREFIX}HEADER_VERSION`, `{PREFIX}HEADER_LENGTH`, `{PREFIX}HEADER_ATTRIBUTE_BIT`,
+//! `{PREFIX}HEADER_SCROLL_LOCK`, and the offset of each field, like
+//! `{PREFIX}HEADER_OFFSET_MUSIC = 1`, along with `{PREFIX}MUSIC_{track}` for each track of the
+//! track list.  The C header also declares a `{PREFIX}stage_header` struct in the same layout
+//! (with cc65's 16-bit `unsigned int`), for the runtime to read the header through:
+//!
+//! ```c
+//! typedef struct {
+//!     unsigned char version;
+//!     unsigned char music;
+//!     unsigned char flags;
+//!     unsigned int time_limit;
+//! } stage_header;
+//! ```
+//!
+//! Stages without any of them have no header, and compile just as they did before it existed.
+//!
+//! # Packed palettes
+//!
+//! With `palette_encoding: packed`, both palettes are packed into 19 bytes instead of 32: the
+//! universal background color once, and then every other color in 6 bits.  This needs the first
+//! color of every background and sprite palette to be the universal background color.  Bit 4 of
+//! the attribute byte is set for packed palettes.  The layout is described in
+//! [`nestools::stage::palettes`](../../stage/palettes/index.html).
+//!
+//! The headers define `{PREFIX}PACKED_PALETTES_ATTRIBUTE_BIT` and
+//! `{PREFIX}PACKED_PALETTES_LENGTH`, and the asm header also defines a reference
+//! `{PREFIX}unpack_palettes` macro, which unpacks the palettes from a zero page pointer into a
+//! 32-byte buffer, ready to be copied to the PPU:
+//!
+//! ```text
+//! unpack_palettes stage_pointer, palette_buffer, temp
+//! ```
+//!
+//! # Compression
+//!
+//! The stage body is compressed with the codec given by the stage's `compression` attribute.  The
+//! codec's id is stored in the low 2 bits of the attribute byte, so the stage loader can pick the
+//! matching decoder.  The codecs are described in
+//! [`nestools::stage::codec`](../../stage/codec/index.html):
+//!
+//! | compression  | id | body                                                              |
+//! |--------------|----|-------------------------------------------------------------------|
+//! | `nibble_rle` | 0  | one byte per run of up to 16, the default and original format     |
+//! | `byte_rle`   | 1  | two bytes per run of up to 256                                    |
+//! | `lz77`       | 2  | literal indices and back-references into the last 255 positions   |
+//! | `dictionary` | 3  | every unique column (or row) once, then the entry for each        |
+//!
+//! `auto` compresses with every codec and keeps whichever gives the smallest body, or the
+//! smallest total of every screen's body.
+//!
+//! # Banks
+//!
+//! With `--bank`, the input is a bank description listing many stage files, each with a name,
+//! and the output is every stage compiled and packed into one bank, so there is no need to run
+//! `stagec` once per stage and write the `.incbin`s and pointer lists by hand:
+//!
+//! ```yaml
+//! bank_size: 8192
+//! stages:
+//!   - {name: level1, file: level1.yaml}
+//!   - {name: level2, file: level2.yaml}
+//! ```
+//!
+//! The bank starts with a 16-bit little-endian offset to each stage from the start of the bank,
+//! followed by the stages in order.  With a `bank_size`, the stages are split across as many
+//! banks of that size as they need, each starting with its own offset table and padded to the
+//! full size, and a stage that does not fit in what is left of a bank starts the next, so no
+//! stage is ever split across two banks.  The details are in
+//! [`nestools::stage::bank`](../../stage/bank/index.html).
+//!
+//! The headers define `{PREFIX}STAGE_COUNT` and `{PREFIX}BANK_COUNT`, and for each stage, like
+//! `level1`, `{PREFIX}STAGE_level1` for its number, along with `{PREFIX}STAGE_level1_BANK`,
+//! `{PREFIX}STAGE_level1_INDEX` in its bank's offset table, `{PREFIX}STAGE_level1_OFFSET` from the
+//! start of its bank, and `{PREFIX}STAGE_level1_SIZE`.  `--sprites` applies to every stage, and
+//! with `--check`, every stage is checked, and the problems are reported along with their stage's
+//! name.  The dependency file lists every stage file along with everything the stages use.
+//! `--yaml`, `--tiled`, `--preview`, and `--chr` only apply to a single stage, and are errors.
+//!
+//! # Decompiling
+//!
+//! With `--decompile`, the input is a binary stage, and the output is a stage YAML file that
+//! compiles back to exactly the same binary.  Names and symbols are lost in compilation, so the
+//! metatiles are named `metatile_0` through `metatile_15` and use the symbols `0` through `f`,
+//! and object types and the music track are given by index.
+//! The binary does not store the stage's dimensions either, so `--size` gives the number of data
+//! lines of a horizontal stage, or the length of the lines of a vertical one.
+//!
+//! The stage is validated before anything is written.  Symbols that are not metatiles, data lines
+//! of different lengths, more than 16 metatiles, and a compressed body longer than 255 bytes are
+//! all errors, reported with the line and column of the stage data where it applies.
+//!
+//! This is also available as `nestools stage`.  With `--check`, the stage is fully validated
+//! (palette values, metatile palettes, flags, and count, duplicate metatile names, duplicate and
+//! unknown symbols, tile names, objects, music, screens, and the compressed body size, along with
+//! the header constant names when headers are asked for), every problem is printed, and nothing
+//! is written.  The dependency file lists the Tiled map, the palettes file, the CHR file, the
+//! sprites file along with its png files, and the object types and track list files, if they are
+//! used.
+
+use std::fs::{self, File};
+use std::path::Path;
+use std::io::{self, Read, Write};
 
-use std::io::{stdin, Read, Write, stdout};
-use std::fs::File;
+use getopts::{Matches, Options};
 
-use super::Error;
+use super::{load_pattern_table, Error, HeaderFile, Language, Shared};
 
-use crate::stage::serialize;
+use crate::stage::Error as StageError;
+use crate::sprites::Tile;
+use crate::stage::objects::{Object, ObjectKind, ObjectTypeRef, ObjectTypes};
+use crate::stage::bank::{self, BankDescription};
+use crate::stage::header::{self, MusicRef};
+use crate::stage::palettes;
+use crate::stage::preview;
+use crate::stage::tiled;
+use crate::sprites::{Page, PatternTable};
+use crate::stage::{self, serialize};
+use crate::stage::serialize::Body;
 
 /// Config type, built from command line or however you'd like.
 pub struct Config {
-    pub input: Option<String>,
-    pub stage: Option<String>,
+    /// Options shared by all programs.  The output is the NES stage file.
+    pub shared: Shared,
+    pub sprites: Option<String>,
+    pub decompile: bool,
+    pub bank: bool,
+    pub size: Option<String>,
+    pub header: Option<String>,
+    pub asm: Option<String>,
+    pub prefix: String,
+    pub yaml: Option<String>,
+    pub tiled: Option<String>,
+    pub preview: Option<String>,
+    pub chr: Option<String>,
 }
 
-/// Entry point for actual running.  Propagates all errors upward.
-pub fn run(config: Config) -> Result<(), Error> {
-    let input: Box<dyn Read> = match config.input {
-        Some(filename) => match File::open(filename) {
-            Ok(file) => Box::new(file),
-            Err(err) => return Err(Error::new("Error opening input YAML file", err)),
-        },
-        None => Box::new(stdin()),
+impl Config {
+    /// Add the options specific to this program
+    pub fn options(opts: &mut Options) {
+        opts.optopt("s", "sprites", "spritesheetc input yaml description file to look up tile names in. Overrides the stage's sprites attribute.", "FILE");
+        opts.optflag("", "decompile", "read a binary stage and write it back out as YAML");
+        opts.optflag("", "bank", "read a bank description and pack every stage it lists into one bank, or a run of fixed-size banks");
+        opts.optopt("z", "size", "with --decompile, the height of a horizontal stage or the width of a vertical stage, in metatiles. Defaults to 15 for horizontal stages and 16 for vertical ones.", "SIZE");
+        opts.optopt("c", "header", "output C header file name", "FILE");
+        opts.optopt("a", "asm", "output asm header file name", "FILE");
+        opts.optopt("p", "prefix", "the prefix for the header defines", "PREFIX");
+        opts.optopt("t", "tiled", "export the stage as a Tiled map, with its tileset image written next to it as a png", "FILE");
+        opts.optopt("y", "yaml", "write the stage as YAML after importing its Tiled map, with the data and objects filled in", "FILE");
+        opts.optopt("", "preview", "render the whole stage to a png", "FILE");
+        opts.optopt("", "chr", "compiled CHR file to render the preview and Tiled tileset from, instead of the sprites file", "FILE");
+    }
+
+    /// Build the config from parsed matches
+    pub fn from_matches(matches: &Matches, shared: Shared) -> Config {
+        Config {
+            shared,
+            sprites: matches.opt_str("s"),
+            decompile: matches.opt_present("decompile"),
+            bank: matches.opt_present("bank"),
+            size: matches.opt_str("z"),
+            header: matches.opt_str("c"),
+            asm: matches.opt_str("a"),
+            prefix: matches.opt_str("p").unwrap_or_default(),
+            yaml: matches.opt_str("y"),
+            tiled: matches.opt_str("t"),
+            preview: matches.opt_str("preview"),
+            chr: matches.opt_str("chr"),
+        }
+    }
+}
+
+/// Parse the command line and run.  `args` does not include the program name.  Returns the exit
+/// code.
+pub fn main(program: &str, args: &[String]) -> i32 {
+    super::main(program, args, Config::options, Config::from_matches, run)
+}
+
+/// The constants defined by the headers, without the prefix.  `body_length` is the length of the
+/// compressed stage body.
+fn constants(stage: &serialize::Stage, body_length: usize, types: Option<&ObjectTypes>, tracks: Option<&[String]>) -> Vec<(String, usize)> {
+    let mut constants = vec![
+        (String::from("HEADER_VERSION"), header::VERSION as usize),
+        (String::from("HEADER_LENGTH"), header::LENGTH),
+        (String::from("HEADER_ATTRIBUTE_BIT"), header::ATTRIBUTE_BIT as usize),
+        (String::from("HEADER_SCROLL_LOCK"), header::SCROLL_LOCK as usize),
+    ];
+    for &(field, offset, _) in &header::FIELDS {
+        constants.push((format!("HEADER_OFFSET_{}", field), offset));
+    }
+    for (index, track) in tracks.unwrap_or(&[]).iter().enumerate() {
+        constants.push((format!("MUSIC_{}", track), index));
+    }
+    constants.push((String::from("PACKED_PALETTES_ATTRIBUTE_BIT"), palettes::ATTRIBUTE_BIT as usize));
+    constants.push((String::from("PACKED_PALETTES_LENGTH"), palettes::PACKED_LENGTH));
+    let (width, height) = stage.dimensions();
+    constants.push((String::from("STAGE_WIDTH"), width));
+    constants.push((String::from("STAGE_HEIGHT"), height));
+    constants.push((String::from("BODY_LENGTH"), body_length));
+    constants.push((String::from("ORIENTATION_HORIZONTAL"), 0));
+    constants.push((String::from("ORIENTATION_VERTICAL"), 0x40));
+    constants.push((String::from("ORIENTATION"), match stage.orientation {
+        serialize::Orientation::Horizontal => 0,
+        serialize::Orientation::Vertical => 0x40,
+    }));
+    constants.push((String::from("METATILE_COUNT"), stage.metatiles.len()));
+    for (index, metatile) in stage.metatiles.iter().enumerate() {
+        constants.push((format!("METATILE_{}", metatile.name), index));
+    }
+    constants.push((String::from("METATILE_PALETTE_MASK"), 3));
+    for flag in &stage.flags {
+        if let Some(bit) = stage.flag_bit(flag) {
+            constants.push((format!("FLAG_{}", flag), bit as usize));
+        }
+    }
+    if let Some(screen) = stage.screens {
+        let count = match stage.orientation {
+            serialize::Orientation::Horizontal => width / screen.width.max(1),
+            serialize::Orientation::Vertical => height / screen.height.max(1),
+        };
+        constants.push((String::from("SCREEN_WIDTH"), screen.width));
+        constants.push((String::from("SCREEN_HEIGHT"), screen.height));
+        constants.push((String::from("SCREEN_COUNT"), count));
+    }
+    if let Some(types) = types {
+        constants.push((String::from("OBJECT_KIND_MASK"), 0xC0));
+        for kind in &ObjectKind::ALL {
+            constants.push((format!("OBJECT_{}", kind.name().to_uppercase()), kind.id() as usize));
+        }
+        for (kind, name, id) in types.ids() {
+            constants.push((format!("{}_{}", kind.name().to_uppercase(), name), id as usize));
+        }
+    }
+    constants
+}
+
+/// Every name that can not be used in a header constant: metatile, flag, music track, and object
+/// type names must only have letters, digits, and underscores, and no two constants may end up
+/// with the same name, like a metatile named `COUNT` and `METATILE_COUNT`.
+fn identifier_problems(stage: &serialize::Stage, types: Option<&ObjectTypes>, tracks: Option<&[String]>) -> Vec<String> {
+    let mut names: Vec<(String, &str)> = Vec::new();
+    names.extend(stage.metatiles.iter().map(|metatile| (String::from("metatile"), metatile.name.as_str())));
+    names.extend(stage.flags.iter().map(|flag| (String::from("flag"), flag.as_str())));
+    names.extend(tracks.unwrap_or(&[]).iter().map(|track| (String::from("music track"), track.as_str())));
+    if let Some(types) = types {
+        names.extend(types.ids().into_iter().map(|(kind, name, _)| (format!("{} type", kind.name()), name)));
+    }
+    let mut problems: Vec<String> = names.into_iter()
+        .filter(|(_, name)| !stage::is_identifier(name))
+        .map(|(what, name)| format!("{} name {:?} can only have letters, digits, and underscores", what, name))
+        .collect();
+
+    // Only the names are checked, so the body length does not matter
+    let constants: Vec<String> = constants(stage, 0, types, tracks).into_iter().map(|(name, _)| name).collect();
+    for (index, name) in constants.iter().enumerate() {
+        if constants[..index].contains(name) {
+            problems.push(format!("header constant {} is defined more than once", name));
+        }
+    }
+    problems
+}
+
+/// Write out the C header file.
+fn write_c_header(filename: &str, prefix: &str, constants: &[(String, usize)]) -> Result<(), io::Error> {
+    let mut file = HeaderFile::create(filename, "STAGEC", Language::C)?;
+    for (name, value) in constants {
+        writeln!(file, "#define {}{} {}", prefix, name, value)?;
+    }
+
+    // Guarded on its own, so that the headers of several stages can be included together
+    let struct_guard = format!("STAGEC_{}STAGE_HEADER", prefix.to_uppercase());
+    writeln!(file, "#ifndef {}", struct_guard)?;
+    writeln!(file, "#define {}", struct_guard)?;
+    writeln!(file, "typedef struct {{")?;
+    for &(field, _, size) in &header::FIELDS {
+        let field_type = if size == 2 { "unsigned int" } else { "unsigned char" };
+        writeln!(file, "    {} {};", field_type, field.to_lowercase())?;
+    }
+    writeln!(file, "}} {}stage_header;", prefix)?;
+    writeln!(file, "#endif /* {} */", struct_guard)?;
+
+    file.finish()
+}
+
+/// Write out the ASM header file.
+fn write_asm_header(filename: &str, prefix: &str, constants: &[(String, usize)]) -> Result<(), io::Error> {
+    let mut file = HeaderFile::create(filename, "STAGEC", Language::Asm)?;
+    for (name, value) in constants {
+        writeln!(file, "{}{} = {}", prefix, name, value)?;
+    }
+
+    // Guarded on its own, so that the headers of several stages can be included together
+    let macro_guard = format!("STAGEC_{}UNPACK_PALETTES", prefix.to_uppercase());
+    writeln!(file, ".ifndef {}", macro_guard)?;
+    writeln!(file, "{} = 1", macro_guard)?;
+    write!(file, "{}", palettes::unpack_asm(&format!("{}unpack_palettes", prefix)))?;
+    writeln!(file, ".endif ; {}", macro_guard)?;
+
+    file.finish()
+}
+
+/// Write a stage as YAML, either decompiled or imported from Tiled.  This is written by hand
+/// rather than with serde_yaml, so that the palettes can be in hex and the data can be a block
+/// literal.  The Tiled import is left out, as the data has already been imported.
+///
+/// The data block gives its indentation explicitly, so that it reads back the same even when its
+/// first line starts with a space symbol:
+///
+/// ```
+/// use nestools::binaries::stagec::write_yaml;
+/// use nestools::stage::serialize::Stage;
+///
+/// let stage: Stage = serde_yaml::from_str("
+/// background_palette: [0x0F, 0x30, 0x10, 0x00, 0x0F, 0, 0, 0, 0x0F, 0, 0, 0, 0x0F, 0, 0, 0]
+/// sprite_palette: [0x0F, 0x16, 0x27, 0x30, 0x0F, 0, 0, 0, 0x0F, 0, 0, 0, 0x0F, 0, 0, 0]
+/// metatiles:
+///   - {name: sky, symbol: ' ', palette: 0, tiles: [0, 0, 0, 0]}
+///   - {name: ground, symbol: '=', palette: 1, tiles: [1, 2, 3, 4]}
+/// data: |2
+///     =
+///    ==
+///   ===
+/// ").unwrap();
+///
+/// let mut yaml = Vec::new();
+/// write_yaml(&mut yaml, &stage).unwrap();
+/// let written: Stage = serde_yaml::from_slice(&yaml).unwrap();
+/// assert_eq!(written.data, "  =\n ==\n===\n");
+/// assert_eq!(written.data, stage.data);
+/// ```
+pub fn write_yaml(output: &mut dyn Write, stage: &serialize::Stage) -> Result<(), Error> {
+    // Plain names are written as they are, and anything else quoted
+    let scalar = |value: &str| -> String {
+        if !value.is_empty() && value.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '/' || c == '-') {
+            String::from(value)
+        } else {
+            format!("'{}'", value.replace("'", "''"))
+        }
+    };
+    let hex = |bytes: &[u8]| -> String {
+        let bytes: Vec<String> = bytes.iter().map(|byte| format!("0x{:02X}", byte)).collect();
+        format!("[{}]", bytes.join(", "))
     };
+    let palette = |palette: &serialize::PaletteRef| match palette {
+        serialize::PaletteRef::Colors(colors) => hex(colors),
+        serialize::PaletteRef::Named(name) => scalar(name),
+    };
+
+    let orientation = match stage.orientation {
+        serialize::Orientation::Horizontal => "horizontal",
+        serialize::Orientation::Vertical => "vertical",
+    };
+    writeln!(output, "orientation: {}", orientation)?;
+    if let Some(codec) = stage.compression.codec() {
+        writeln!(output, "compression: {}", codec.name())?;
+    }
+    if stage.palette_encoding == serialize::PaletteEncoding::Packed {
+        writeln!(output, "palette_encoding: packed")?;
+    }
+    if let Some(screen) = stage.screens {
+        writeln!(output, "screens: {{width: {}, height: {}}}", screen.width, screen.height)?;
+    }
+    match stage.music {
+        Some(MusicRef::Index(index)) => writeln!(output, "music: {}", index)?,
+        Some(MusicRef::Name(ref name)) => writeln!(output, "music: {}", scalar(name))?,
+        None => (),
+    }
+    if stage.scroll_lock {
+        writeln!(output, "scroll_lock: true")?;
+    }
+    if let Some(time_limit) = stage.time_limit {
+        writeln!(output, "time_limit: {}", time_limit)?;
+    }
+    for (key, value) in &[("palettes", &stage.palettes), ("sprites", &stage.sprites), ("object_types", &stage.object_types), ("tracks", &stage.tracks)] {
+        if let Some(value) = value {
+            writeln!(output, "{}: {}", key, scalar(value))?;
+        }
+    }
+    match stage.page {
+        Some(Page::Left) => writeln!(output, "page: left")?,
+        Some(Page::Right) => writeln!(output, "page: right")?,
+        None => (),
+    }
+    if stage.flags != serialize::DEFAULT_FLAGS {
+        let flags: Vec<String> = stage.flags.iter().map(|flag| scalar(flag)).collect();
+        writeln!(output, "flags: [{}]", flags.join(", "))?;
+    }
+    writeln!(output, "background_palette: {}", palette(&stage.background_palette))?;
+    writeln!(output, "sprite_palette: {}", palette(&stage.sprite_palette))?;
+    writeln!(output, "metatiles:")?;
+    for metatile in &stage.metatiles {
+        let tiles: Vec<String> = metatile.tiles.iter()
+            .map(|tile| match tile {
+                serialize::TileRef::Index(index) => index.to_string(),
+                serialize::TileRef::Name(name) => scalar(name),
+            })
+            .collect();
+        writeln!(output, "  - name: {}", scalar(&metatile.name))?;
+        writeln!(output, "    symbol: '{}'", metatile.symbol.to_string().replace("'", "''"))?;
+        writeln!(output, "    palette: {}", metatile.palette)?;
+        writeln!(output, "    tiles: [{}]", tiles.join(", "))?;
+        if !metatile.flags.is_empty() {
+            let flags: Vec<String> = metatile.flags.iter().map(|flag| scalar(flag)).collect();
+            writeln!(output, "    flags: [{}]", flags.join(", "))?;
+        }
+    }
+    writeln!(output, "data: |2")?;
+    for line in stage.data.lines() {
+        writeln!(output, "  {}", line)?;
+    }
+    if !stage.objects.is_empty() {
+        writeln!(output, "objects:")?;
+    }
+    for object in &stage.objects {
+        let object_type = match object.object_type {
+            Some(ObjectTypeRef::Index(index)) => format!(", type: {}", index),
+            Some(ObjectTypeRef::Name(ref name)) => format!(", type: {}", scalar(name)),
+            None => String::new(),
+        };
+        writeln!(output, "  - {{kind: {}{}, x: {}, y: {}, parameter: {}}}",
+                 object.kind.name(), object_type, object.x, object.y, object.parameter)?;
+    }
+    Ok(())
+}
+
+/// Export the stage as a Tiled map, with its tileset image next to it.
+fn write_tiled(filename: &str, stage: &serialize::Stage, objects: &[Object], tiles: &[Tile]) -> Result<(), Error> {
+    let image = Path::new(filename).with_extension("png");
+    let image_name = image.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+
+    if let Err(err) = fs::write(filename, tiled::export_tmx(stage, objects, &image_name)) {
+        return Err(Error::new("Error writing Tiled map", err));
+    }
 
-    let mut output: Box<dyn Write> = match config.stage {
-        Some(filename) => match File::create(filename) {
-            Ok(file) => Box::new(file),
-            Err(err) => return Err(Error::new("Error opening input YAML file", err)),
+    let (pixels, width) = preview::render_metatiles(stage, tiles);
+    if let Err(err) = write_png(&image, &pixels, width) {
+        return Err(Error::new("Error writing Tiled tileset", err));
+    }
+    Ok(())
+}
+
+/// Write RGB pixels, row by row, to a png.
+fn write_png<P: AsRef<Path>>(filename: P, pixels: &[[u8; 3]], width: usize) -> Result<(), ::lodepng::Error> {
+    let buffer: Vec<u8> = pixels.iter().flat_map(|pixel| pixel.iter().cloned()).collect();
+    ::lodepng::encode24_file(filename, &buffer, width, pixels.len() / width.max(1))
+}
+
+/// Decompile a binary stage into YAML.
+fn decompile(config: Config) -> Result<(), Error> {
+    let mut bytes = Vec::new();
+    config.shared.open_input("stage")?.read_to_end(&mut bytes)?;
+
+    // The orientation is the only thing needed before parsing, to pick the default size
+    let vertical = bytes.first().map(|&attribute| attribute & 0x40 != 0).unwrap_or(false);
+    let size = match config.size {
+        Some(ref size) => match size.parse() {
+            Ok(size) => size,
+            Err(err) => return Err(Error::new("Error parsing size", err)),
         },
-        None => Box::new(stdout()),
+        None if vertical => 16,
+        None => 15,
+    };
+
+    let stage = match serialize::Stage::parse(&bytes, size) {
+        Ok(stage) => stage,
+        Err(err) => return Err(Error::new("Error parsing stage", err)),
     };
 
-    let stage: serialize::Stage = match serde_yaml::from_reader(input) {
+    if config.shared.check {
+        return config.shared.report_problems(&check_stage(&stage, stage.compress().ok().as_ref(), Vec::new()));
+    }
+
+    let mut output = config.shared.open_output("YAML")?;
+    write_yaml(&mut output, &stage)?;
+
+    config.shared.write_depfile(&[])?;
+
+    Ok(())
+}
+
+/// Load a stage from YAML, and import its Tiled map if it has one.  Returns the stage along with
+/// the Tiled map, if it was used.
+fn load_stage(input: Box<dyn Read>) -> Result<(serialize::Stage, Vec<String>), Error> {
+    let mut stage: serialize::Stage = match serde_yaml::from_reader(input) {
         Ok(stage) => stage,
         Err(err) => return Err(Error::new("Error loading YAML", err)),
     };
 
-    stage.write_binary(&mut output)?;
+    let mut dependencies = Vec::new();
+    if let Some(ref import) = stage.tiled {
+        dependencies.push(import.map.clone());
+    }
+    if let Err(err) = stage.import_tiled() {
+        return Err(Error::new("Error importing Tiled map", err));
+    }
+    Ok((stage, dependencies))
+}
+
+/// Everything a stage's names were resolved against.
+struct Resolved {
+    /// The files that were read
+    dependencies: Vec<String>,
+
+    /// Names that could not be resolved, and problems with the files they were resolved against
+    problems: Vec<String>,
+
+    /// The pattern table built from the sprites file, if it was loaded
+    pattern_table: Option<PatternTable>,
+
+    types: Option<ObjectTypes>,
+    tracks: Option<Vec<String>>,
+}
+
+/// Load the palettes of a stage, and resolve every tile, object type, and music track it gives by
+/// name from the files it names.  `sprites` overrides the stage's sprites file, which is loaded
+/// when tiles are given by name, or if `need_pattern_table` is set.
+fn resolve(stage: &mut serialize::Stage, sprites: Option<&String>, need_pattern_table: bool) -> Result<Resolved, Error> {
+    if let Err(err) = stage.load_palettes() {
+        return Err(Error::new("Error loading palettes", err));
+    }
+
+    let mut dependencies: Vec<String> = stage.palettes.iter().cloned().collect();
+    let mut problems = Vec::new();
+    let mut pattern_table = None;
+    if stage.has_named_tiles() || need_pattern_table {
+        match sprites.or(stage.sprites.as_ref()) {
+            Some(filename) => {
+                let (loaded, sprite_dependencies) = load_pattern_table(filename)?;
+                dependencies.extend(sprite_dependencies);
+                problems.extend(stage.resolve_tiles(&loaded));
+                pattern_table = Some(loaded);
+            },
+            None if stage.has_named_tiles() => problems.push(String::from(
+                "metatile tiles are given by name, but there is no sprites file to look them up in")),
+            None => problems.push(String::from(
+                "rendering the stage needs a sprites file or a CHR file to take its tiles from")),
+        }
+    }
+
+    let mut types = None;
+    if let Some(filename) = stage.object_types.clone() {
+        match ObjectTypes::load(&filename) {
+            Ok(loaded) => {
+                problems.extend(loaded.check());
+                problems.extend(stage.resolve_object_types(&loaded));
+                types = Some(loaded);
+            },
+            Err(err) => return Err(Error::new("Error loading object types", err)),
+        }
+        dependencies.push(filename);
+    } else if stage.has_named_object_types() {
+        problems.push(String::from(
+            "object types are given by name, but the stage has no object types file to look them up in"));
+    }
+
+    let mut tracks = None;
+    if let Some(filename) = stage.tracks.clone() {
+        match header::load_tracks(&filename) {
+            Ok(loaded) => {
+                problems.extend(stage.resolve_music(&loaded));
+                tracks = Some(loaded);
+            },
+            Err(err) => return Err(Error::new("Error loading music tracks", err)),
+        }
+        dependencies.push(filename);
+    } else if let Some(MusicRef::Name(_)) = stage.music {
+        problems.push(String::from(
+            "music is given by name, but the stage has no tracks file to look it up in"));
+    }
+
+    Ok(Resolved {
+        dependencies,
+        problems,
+        pattern_table,
+        types,
+        tracks,
+    })
+}
+
+/// Every problem with a resolved stage: the problems found while resolving it, and then the
+/// problems found validating it.  `body` is the stage's compressed body, if it could be
+/// compressed.
+fn check_stage(stage: &serialize::Stage, body: Option<&Body>, mut problems: Vec<String>) -> Vec<String> {
+    // A body that could not be compressed is reported by validating the stage on its own
+    let errors = match body {
+        Some(body) => stage.validate_compressed(body),
+        None => stage.validate(),
+    };
+    // Unresolved tiles, object types, and music were already reported while resolving them
+    problems.extend(errors.iter()
+        .filter(|error| !matches!(error, StageError::TileError(_) | StageError::ObjectTypeError(_) | StageError::MusicError(_)))
+        .map(StageError::to_string));
+    problems
+}
+
+/// Compile every stage of a bank description into a single bank, or a run of banks.
+fn bank(config: Config) -> Result<(), Error> {
+    let single = [
+        ("--yaml", config.yaml.is_some()),
+        ("--tiled", config.tiled.is_some()),
+        ("--preview", config.preview.is_some()),
+        ("--chr", config.chr.is_some()),
+    ];
+    if let Some((option, _)) = single.iter().find(|(_, given)| *given) {
+        return Err(Error::new("Invalid options", StageError::FormatError(format!(
+            "{} only applies to a single stage, and can not be used with --bank", option))));
+    }
+
+    let input = config.shared.open_input("bank YAML")?;
+    let description: BankDescription = match serde_yaml::from_reader(input) {
+        Ok(description) => description,
+        Err(err) => return Err(Error::new("Error loading YAML", err)),
+    };
+
+    let mut problems = description.check();
+    let mut dependencies = Vec::new();
+    let mut binaries = Vec::new();
+    for entry in &description.stages {
+        let file = match File::open(&entry.file) {
+            Ok(file) => file,
+            Err(err) => return Err(Error::new(&format!("Error opening stage {}", entry.name), err)),
+        };
+        let (mut stage, stage_dependencies) = load_stage(Box::new(file))?;
+        let resolved = resolve(&mut stage, config.sprites.as_ref(), false)?;
+        for dependency in Some(&entry.file).into_iter().chain(&stage_dependencies).chain(&resolved.dependencies) {
+            if !dependencies.contains(dependency) {
+                dependencies.push(dependency.clone());
+            }
+        }
+
+        let body = stage.compress().ok();
+        let stage_problems = check_stage(&stage, body.as_ref(), resolved.problems);
+        if config.shared.check {
+            problems.extend(stage_problems.iter().map(|problem| format!("stage {}: {}", entry.name, problem)));
+        } else if let Some(problem) = stage_problems.first() {
+            return Err(Error::new(&format!("Error compiling stage {}", entry.name), StageError::FormatError(problem.clone())));
+        }
+
+        if let (true, Some(body)) = (stage_problems.is_empty(), body) {
+            let mut binary = Vec::new();
+            if let Err(err) = stage.write_binary_unchecked(&mut binary, &body) {
+                return Err(Error::new(&format!("Error writing stage {}", entry.name), err));
+            }
+            binaries.push((entry.name.clone(), binary));
+        }
+    }
+
+    // The banks can only be packed once every stage has compiled
+    let packed = if binaries.len() == description.stages.len() {
+        match bank::pack(&binaries, description.bank_size) {
+            Ok(packed) => Some(packed),
+            Err(err) if config.shared.check => {
+                problems.push(err.to_string());
+                None
+            },
+            Err(err) => return Err(Error::new("Error packing stages", err)),
+        }
+    } else {
+        None
+    };
+
+    if config.shared.check {
+        return config.shared.report_problems(&problems);
+    }
+    if let Some(problem) = problems.first() {
+        return Err(Error::new("Invalid bank", StageError::BankError(problem.clone())));
+    }
+    let (binary, placements) = packed.expect("every stage compiled without problems");
+
+    let mut output = config.shared.open_output("bank")?;
+    output.write_all(&binary)?;
+
+    let mut constants = vec![
+        (String::from("STAGE_COUNT"), placements.len()),
+        (String::from("BANK_COUNT"), placements.last().map(|placement| placement.bank + 1).unwrap_or(0)),
+    ];
+    for (stage, (entry, placement)) in description.stages.iter().zip(&placements).enumerate() {
+        constants.push((format!("STAGE_{}", entry.name), stage));
+        constants.push((format!("STAGE_{}_BANK", entry.name), placement.bank));
+        constants.push((format!("STAGE_{}_INDEX", entry.name), placement.index));
+        constants.push((format!("STAGE_{}_OFFSET", entry.name), placement.offset));
+        constants.push((format!("STAGE_{}_SIZE", entry.name), placement.size));
+    }
+
+    if let Some(ref filename) = config.asm {
+        if let Err(err) = write_asm_header(filename, &config.prefix, &constants) {
+            return Err(Error::new("Error writing ASM header", err));
+        }
+    }
+
+    if let Some(ref filename) = config.header {
+        if let Err(err) = write_c_header(filename, &config.prefix, &constants) {
+            return Err(Error::new("Error writing C header", err));
+        }
+    }
+
+    let dependencies: Vec<&str> = dependencies.iter().map(String::as_str).collect();
+    config.shared.write_depfile(&dependencies)?;
+
+    Ok(())
+}
+
+/// Entry point for actual running.  Propagates all errors upward.
+pub fn run(config: Config) -> Result<(), Error> {
+    if config.decompile {
+        return decompile(config);
+    }
+    if config.bank {
+        return bank(config);
+    }
+
+    let (mut stage, mut dependencies) = load_stage(config.shared.open_input("YAML")?)?;
+
+    // Rendered before anything is resolved, so that names stay names, but only written once the
+    // stage is known to be valid
+    let mut yaml = None;
+    if config.yaml.is_some() && !config.shared.check {
+        let mut rendered = Vec::new();
+        write_yaml(&mut rendered, &stage)?;
+        yaml = Some(rendered);
+    }
+
+    // The object type names are needed for the Tiled export, and are lost in resolving
+    let objects = stage.objects.clone();
+
+    // The pattern table the stage is rendered from, for the preview and the Tiled tileset
+    let mut pattern_table = None;
+    if let Some(ref filename) = config.chr {
+        match fs::read(filename) {
+            Ok(bytes) => pattern_table = Some(PatternTable::from_bytes(&bytes)),
+            Err(err) => return Err(Error::new("Error reading CHR file", err)),
+        }
+        dependencies.push(filename.clone());
+    }
+    let render = config.tiled.is_some() || config.preview.is_some();
+    let resolved = resolve(&mut stage, config.sprites.as_ref(), render && pattern_table.is_none())?;
+    dependencies.extend(resolved.dependencies);
+    let pattern_table = pattern_table.or(resolved.pattern_table);
+    // Resolving the tiles sets the page, if they are given by name
+    let page = stage.page.unwrap_or(Page::Left);
+
+    // The names only need to be identifiers if they become header constants
+    let names = if config.header.is_some() || config.asm.is_some() {
+        identifier_problems(&stage, resolved.types.as_ref(), resolved.tracks.as_deref())
+    } else {
+        Vec::new()
+    };
+
+    // Compressed once, for validating, writing, and the header constants
+    let body = stage.compress();
+
+    if config.shared.check {
+        let problems = resolved.problems.into_iter().chain(names).collect();
+        return config.shared.report_problems(&check_stage(&stage, body.as_ref().ok(), problems));
+    }
+
+    if let Some(problem) = resolved.problems.first() {
+        return Err(Error::new("Error resolving names", StageError::FormatError(problem.clone())));
+    }
+    if let Some(problem) = names.first() {
+        return Err(Error::new("Invalid names for the headers", StageError::FormatError(problem.clone())));
+    }
+
+    // Validate before opening the output, so that nothing is written for an invalid stage
+    let body = match body {
+        Ok(body) => body,
+        Err(err) => return Err(Error::new("Invalid stage", err)),
+    };
+    if let Some(err) = stage.validate_compressed(&body).into_iter().next() {
+        return Err(Error::new("Invalid stage", err));
+    }
+
+    if let (Some(filename), Some(yaml)) = (config.yaml.as_ref(), yaml) {
+        if let Err(err) = File::create(filename).and_then(|mut file| file.write_all(&yaml)) {
+            return Err(Error::new("Error writing YAML file", err));
+        }
+    }
+
+    let mut output = config.shared.open_output("stage")?;
+
+    if let Err(err) = stage.write_binary_unchecked(&mut output, &body) {
+        return Err(Error::new("Error writing stage", err));
+    }
+
+    let constants = constants(&stage, body.length(), resolved.types.as_ref(), resolved.tracks.as_deref());
+
+    if let Some(ref filename) = config.asm {
+        if let Err(err) = write_asm_header(filename, &config.prefix, &constants) {
+            return Err(Error::new("Error writing ASM header", err));
+        }
+    }
+
+    if let Some(ref filename) = config.header {
+        if let Err(err) = write_c_header(filename, &config.prefix, &constants) {
+            return Err(Error::new("Error writing C header", err));
+        }
+    }
+
+    if let Some(ref pattern_table) = pattern_table {
+        let tiles = match page {
+            Page::Left => &pattern_table.left,
+            Page::Right => &pattern_table.right,
+        };
+
+        if let Some(ref filename) = config.tiled {
+            write_tiled(filename, &stage, &objects, tiles)?;
+        }
+
+        if let Some(ref filename) = config.preview {
+            let (pixels, width) = preview::render_stage(&stage, tiles);
+            if let Err(err) = write_png(filename, &pixels, width) {
+                return Err(Error::new("Error writing preview", err));
+            }
+        }
+    }
+
+    let dependencies: Vec<&str> = dependencies.iter().map(String::as_str).collect();
+    config.shared.write_depfile(&dependencies)?;
 
     Ok(())
 }
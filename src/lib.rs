@@ -1,5 +1,6 @@
 //! This is a set of relatively simple tools used to assist with the building of NES games.
-//! Currently, its only functionality is in managing sprite sheets.
+//! Currently, its functionality is in managing sprite sheets, compiling stages, and working with
+//! iNES ROM files.
 //!
 //! All binaries are individually described in their own binary modules.  [The list of binary
 //! modules is namespaced for convenience](binaries/index.html).
@@ -13,6 +14,9 @@
 extern crate serde_derive;
 extern crate lodepng;
 
+pub mod nametable;
+pub mod palette;
+pub mod rom;
 pub mod sprites;
 pub mod stage;
 
@@ -0,0 +1,426 @@
+//! Tools for working with full-screen backgrounds.  This is used to compile an indexed PNG of one
+//! or more screens into deduplicated background tiles, nametables, and attribute tables.
+//!
+//! The image must be a whole number of 256x240 screens.  Each pixel's palette index is split into
+//! a background palette (`index / 4`) and a color within that palette (`index % 4`), so a plain
+//! 4-color image simply uses palette 0 everywhere.  Color 0 of every palette is the shared
+//! universal background color, so those pixels may appear in any 16x16 area.
+//!
+//! Alternatively, a truecolor image can be used along with the 16-byte background palette (such as
+//! a stage's `background_palette`).  In that case, the palette of each 16x16 area is detected from
+//! the colors it uses.
+//!
+
+use crate::palette;
+use crate::sprites::{Error, Page, PatternTable, SheetPlacement, Tile};
+
+use std::collections::HashMap;
+
+/// Width of a single screen in tiles
+pub const SCREEN_WIDTH: usize = 32;
+
+/// Height of a single screen in tiles
+pub const SCREEN_HEIGHT: usize = 30;
+
+/// Size of a single nametable, without its attribute table
+pub const NAMETABLE_SIZE: usize = SCREEN_WIDTH * SCREEN_HEIGHT;
+
+/// Size of a single attribute table
+pub const ATTRIBUTE_TABLE_SIZE: usize = 64;
+
+/// A loaded background image, split into tiles and 16x16 palette areas.
+pub struct Background {
+    /// Width of the whole image, in tiles
+    pub width: usize,
+
+    /// Height of the whole image, in tiles
+    pub height: usize,
+
+    /// The tiles, unnamed, in row-major order
+    pub tiles: Vec<Tile>,
+
+    /// The background palette of each 16x16 area, in row-major order
+    pub palettes: Vec<u8>,
+}
+
+impl Background {
+    /// Build a background from a buffer of palette indices, one byte per pixel, in row-major
+    /// order.  Both dimensions are in pixels.
+    pub fn from_indices(buffer: &[u8], width: usize, height: usize) -> Result<Background, Error> {
+        if width == 0 || height == 0 || !width.is_multiple_of(SCREEN_WIDTH * 8) || !height.is_multiple_of(SCREEN_HEIGHT * 8) {
+            return Err(Error::DimensionsError(format!(
+                "Background must be a whole number of 256x240 screens, got {}x{}",
+                width, height)));
+        }
+        if buffer.len() != width * height {
+            return Err(Error::DimensionsError(format!(
+                "Need {} pixels for a {}x{} image, got {}",
+                width * height, width, height, buffer.len())));
+        }
+        if let Some(item) = buffer.iter().find(|&&item| item > 15) {
+            return Err(Error::PaletteError(format!(
+                "Image has a byte out of bounds; needs to be under 16, got {}.", item)));
+        }
+
+        let tile_width = width / 8;
+        let tile_height = height / 8;
+
+        let mut tiles = Vec::new();
+        for row in 0..tile_height {
+            for column in 0..tile_width {
+                let bytes: Vec<u8> = (0..8).flat_map(|line| {
+                    let offset = (row * 8 + line) * width + column * 8;
+                    buffer[offset..(offset + 8)].iter().map(|index| index & 0x03)
+                }).collect();
+                tiles.push(Tile::from_bytes(&bytes, None)?);
+            }
+        }
+
+        let area_width = width / 16;
+        let area_height = height / 16;
+        let mut palettes = Vec::new();
+        let mut conflicts = Vec::new();
+        for area_y in 0..area_height {
+            for area_x in 0..area_width {
+                let mut palette = None;
+                for y in (area_y * 16)..(area_y * 16 + 16) {
+                    for &index in &buffer[(y * width + area_x * 16)..(y * width + area_x * 16 + 16)] {
+                        // The universal background color fits every palette
+                        if index & 0x03 == 0 {
+                            continue;
+                        }
+                        match palette {
+                            None => palette = Some(index >> 2),
+                            Some(existing) if existing != index >> 2 && !conflicts.contains(&(area_x, area_y)) => {
+                                conflicts.push((area_x, area_y));
+                            },
+                            _ => (),
+                        }
+                    }
+                }
+                palettes.push(palette.unwrap_or(0));
+            }
+        }
+
+        if !conflicts.is_empty() {
+            let areas: Vec<String> = conflicts.iter()
+                .map(|(x, y)| format!("({}, {})", x * 16, y * 16))
+                .collect();
+            return Err(Error::PaletteError(format!(
+                "16x16 areas at pixel {} use more than one palette",
+                areas.join(", "))));
+        }
+
+        Ok(Background {
+            width: tile_width,
+            height: tile_height,
+            tiles,
+            palettes,
+        })
+    }
+
+    /// Build a background from a buffer of RGB pixels in row-major order, using the given 16-byte
+    /// background palette.  Both dimensions are in pixels.
+    ///
+    /// Every pixel is matched to the closest NES color.  Each 16x16 area is then given the first
+    /// of the four palettes that contains all of its colors, where the first color of the first
+    /// palette is the universal background color shared by all four.  Every area that uses a
+    /// color in no palette, or that needs more than one palette, is reported by its pixel
+    /// coordinates.
+    pub fn from_rgb(buffer: &[[u8; 3]], width: usize, height: usize, background_palette: &[u8; 16]) -> Result<Background, Error> {
+        if width == 0 || height == 0 || !width.is_multiple_of(SCREEN_WIDTH * 8) || !height.is_multiple_of(SCREEN_HEIGHT * 8) {
+            return Err(Error::DimensionsError(format!(
+                "Background must be a whole number of 256x240 screens, got {}x{}",
+                width, height)));
+        }
+        if buffer.len() != width * height {
+            return Err(Error::DimensionsError(format!(
+                "Need {} pixels for a {}x{} image, got {}",
+                width * height, width, height, buffer.len())));
+        }
+
+        // Compare RGB values rather than color numbers, so that duplicate colors (like the
+        // various blacks) match each other
+        let slot_rgb = |palette: usize, slot: usize| -> [u8; 3] {
+            if slot == 0 {
+                palette::rgb(background_palette[0])
+            } else {
+                palette::rgb(background_palette[palette * 4 + slot])
+            }
+        };
+        let pixels: Vec<[u8; 3]> = buffer.iter()
+            .map(|&pixel| palette::rgb(palette::nearest(pixel)))
+            .collect();
+
+        let mut indices = vec![0u8; width * height];
+        let mut unknown = Vec::new();
+        let mut conflicts = Vec::new();
+        for area_y in 0..(height / 16) {
+            for area_x in 0..(width / 16) {
+                let area: Vec<usize> = (0..16)
+                    .flat_map(|y| (0..16).map(move |x| (area_y * 16 + y) * width + area_x * 16 + x))
+                    .collect();
+
+                let fits = |palette: usize, pixel: usize| (0..4).any(|slot| slot_rgb(palette, slot) == pixels[pixel]);
+                if area.iter().any(|&pixel| !(0..4).any(|palette| fits(palette, pixel))) {
+                    unknown.push(format!("({}, {})", area_x * 16, area_y * 16));
+                    continue;
+                }
+                let palette = match (0..4).find(|&palette| area.iter().all(|&pixel| fits(palette, pixel))) {
+                    Some(palette) => palette,
+                    None => {
+                        conflicts.push(format!("({}, {})", area_x * 16, area_y * 16));
+                        continue;
+                    },
+                };
+                for &pixel in &area {
+                    let slot = (0..4).find(|&slot| slot_rgb(palette, slot) == pixels[pixel]).unwrap_or(0);
+                    indices[pixel] = if slot == 0 { 0 } else { (palette * 4 + slot) as u8 };
+                }
+            }
+        }
+
+        let mut problems = Vec::new();
+        if !unknown.is_empty() {
+            problems.push(format!(
+                "16x16 areas at pixel {} use colors that are in no background palette",
+                unknown.join(", ")));
+        }
+        if !conflicts.is_empty() {
+            problems.push(format!(
+                "16x16 areas at pixel {} use more than one palette",
+                conflicts.join(", ")));
+        }
+        if !problems.is_empty() {
+            return Err(Error::PaletteError(problems.join("; ")));
+        }
+
+        Background::from_indices(&indices, width, height)
+    }
+
+    /// Load a truecolor png file, detecting palettes with the given 16-byte background palette.
+    pub fn load_rgb(path: &str, background_palette: &[u8; 16]) -> Result<Background, Error> {
+        match ::lodepng::decode24_file(path) {
+            Ok(bitmap) => {
+                let buffer: Vec<[u8; 3]> = bitmap.buffer.iter().map(|pixel| [pixel.r, pixel.g, pixel.b]).collect();
+                Background::from_rgb(&buffer, bitmap.width, bitmap.height, background_palette)
+            },
+            Err(err) => Err(Error::PNGError(err)),
+        }
+    }
+
+    /// Decode a truecolor png file that is already in memory, detecting palettes with the given
+    /// 16-byte background palette.
+    pub fn decode_rgb(bytes: &[u8], background_palette: &[u8; 16]) -> Result<Background, Error> {
+        match ::lodepng::decode24(bytes) {
+            Ok(bitmap) => {
+                let buffer: Vec<[u8; 3]> = bitmap.buffer.iter().map(|pixel| [pixel.r, pixel.g, pixel.b]).collect();
+                Background::from_rgb(&buffer, bitmap.width, bitmap.height, background_palette)
+            },
+            Err(err) => Err(Error::PNGError(err)),
+        }
+    }
+
+    /// Load an indexed png file.
+    pub fn load(path: &str) -> Result<Background, Error> {
+        Background::from_image(::lodepng::decode_file(path, ::lodepng::ffi::ColorType::PALETTE, 8))
+    }
+
+    /// Decode an indexed png file that is already in memory.
+    pub fn decode(bytes: &[u8]) -> Result<Background, Error> {
+        Background::from_image(::lodepng::decode_memory(bytes, ::lodepng::ffi::ColorType::PALETTE, 8))
+    }
+
+    fn from_image(image: Result<::lodepng::Image, ::lodepng::ffi::Error>) -> Result<Background, Error> {
+        match image {
+            Ok(::lodepng::Image::RawData(bitmap)) => Background::from_indices(&bitmap.buffer, bitmap.width, bitmap.height),
+            Ok(_) => Err(Error::FormatError(String::from("Image format was incorrect"))),
+            Err(err) => Err(Error::PNGError(err)),
+        }
+    }
+
+    /// The number of screens across
+    pub fn screens_wide(&self) -> usize {
+        self.width / SCREEN_WIDTH
+    }
+
+    /// The number of screens down
+    pub fn screens_high(&self) -> usize {
+        self.height / SCREEN_HEIGHT
+    }
+
+    /// Merge the tiles into a page of a pattern table, reusing any identical named tile already
+    /// in that page.  New tiles are named `{name}_{number}` and placed after the page's sheets.
+    /// Returns the page index of every tile of the background, in row-major order.
+    ///
+    /// ```
+    /// use nestools::nametable::Background;
+    /// use nestools::sprites::{Page, PatternTable, SheetPlacement};
+    ///
+    /// // A single screen, blank but for two solid tiles of color 1 in the top left corner
+    /// let mut pixels = vec![0u8; 256 * 240];
+    /// for y in 0..8 {
+    ///     for x in 0..16 {
+    ///         pixels[y * 256 + x] = 1;
+    ///     }
+    /// }
+    /// let background = Background::from_indices(&pixels, 256, 240).unwrap();
+    /// assert_eq!((background.width, background.height, background.tiles.len()), (32, 30, 960));
+    ///
+    /// // The left page already holds a named blank tile
+    /// let mut pattern_table = PatternTable::from_bytes(&[]);
+    /// pattern_table.left[0].name = Some(String::from("blank_0"));
+    /// pattern_table.sheets.push(SheetPlacement {
+    ///     name: String::from("blank"),
+    ///     file: None,
+    ///     page: Page::Left,
+    ///     start: 0,
+    ///     count: 1,
+    ///     metatiles: Vec::new(),
+    /// });
+    ///
+    /// // Every blank tile reuses it, and both solid tiles share a single new tile after it
+    /// let indices = background.merge_into(&mut pattern_table, Page::Left, "title", None).unwrap();
+    /// assert_eq!(&indices[..3], &[1, 1, 0]);
+    /// assert_eq!(pattern_table.left[1].name.as_deref(), Some("title_0"));
+    /// assert_eq!(pattern_table.left[1].data, [0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0, 0, 0, 0, 0, 0, 0, 0]);
+    /// assert_eq!(pattern_table.sheets.last().unwrap().count, 1);
+    ///
+    /// let nametables = background.nametables(&indices);
+    /// assert_eq!(nametables.len(), 960);
+    /// assert_eq!(nametables.iter().filter(|&&index| index == 1).count(), 2);
+    /// ```
+    pub fn merge_into(&self, pattern_table: &mut PatternTable, page: Page, name: &str, file: Option<&str>) -> Result<Vec<u8>, Error> {
+        let used: usize = pattern_table.sheets.iter()
+            .filter(|sheet| sheet.page == page)
+            .map(|sheet| sheet.count)
+            .sum();
+        let tiles = match page {
+            Page::Left => &mut pattern_table.left,
+            Page::Right => &mut pattern_table.right,
+        };
+
+        let mut existing: HashMap<[u8; 16], usize> = HashMap::new();
+        for (index, tile) in tiles.iter().enumerate().take(used) {
+            if tile.name.is_some() {
+                existing.entry(tile.data).or_insert(index);
+            }
+        }
+
+        let mut next = used;
+        let mut indices = Vec::new();
+        for tile in &self.tiles {
+            let index = match existing.get(&tile.data) {
+                Some(&index) => index,
+                None => {
+                    if next >= 256 {
+                        return Err(Error::DimensionsError(format!(
+                            "{} page can not hold the background.  Can not exceed 256 tiles, but {} were already used and more unique tiles remain",
+                            page, used)));
+                    }
+                    tiles[next] = Tile {
+                        name: Some(format!("{}_{}", name, next - used)),
+                        data: tile.data,
+                    };
+                    existing.insert(tile.data, next);
+                    next += 1;
+                    next - 1
+                },
+            };
+            indices.push(index as u8);
+        }
+
+        pattern_table.sheets.push(SheetPlacement {
+            name: String::from(name),
+            file: file.map(String::from),
+            page,
+            start: used,
+            count: next - used,
+            metatiles: Vec::new(),
+        });
+
+        Ok(indices)
+    }
+
+    /// Build the nametables, given the page index of every tile as returned by `merge_into`.
+    /// Screens are ordered left-to-right, then top-to-bottom, with 960 bytes each.
+    pub fn nametables(&self, indices: &[u8]) -> Vec<u8> {
+        let mut output = Vec::new();
+        for screen_y in 0..self.screens_high() {
+            for screen_x in 0..self.screens_wide() {
+                for y in 0..SCREEN_HEIGHT {
+                    let offset = (screen_y * SCREEN_HEIGHT + y) * self.width + screen_x * SCREEN_WIDTH;
+                    output.extend_from_slice(&indices[offset..(offset + SCREEN_WIDTH)]);
+                }
+            }
+        }
+        output
+    }
+
+    /// Build the attribute tables, in the same screen order as the nametables, with 64 bytes
+    /// each.
+    ///
+    /// Each byte covers a 32x32 pixel area, as four 2-bit palette numbers: top left in the low
+    /// bits, then top right, bottom left, and bottom right.  The bottom half of the last row
+    /// falls off the screen, and is left as 0.
+    ///
+    /// ```
+    /// use nestools::nametable::Background;
+    /// use nestools::palette;
+    ///
+    /// let background_palette = [
+    ///     0x0F, 0x16, 0x27, 0x30, 0x0F, 0x01, 0x11, 0x21, 0x0F, 0x1A, 0x2A, 0x3A, 0x0F, 0x04, 0x14, 0x24,
+    /// ];
+    /// // Only the first 32x32 area has any color: palette 0 in its top left 16x16 area, and
+    /// // palettes 1, 2, and 3 in the others
+    /// let mut pixels = vec![palette::rgb(0x0F); 256 * 240];
+    /// pixels[0] = palette::rgb(0x16);
+    /// pixels[16] = palette::rgb(0x01);
+    /// pixels[16 * 256] = palette::rgb(0x1A);
+    /// pixels[16 * 256 + 16] = palette::rgb(0x04);
+    ///
+    /// let background = Background::from_rgb(&pixels, 256, 240, &background_palette).unwrap();
+    /// let attributes = background.attribute_tables();
+    /// assert_eq!(attributes.len(), 64);
+    /// assert_eq!(attributes[0], 0b11_10_01_00);
+    /// assert!(attributes[1..].iter().all(|&byte| byte == 0));
+    ///
+    /// // A 16x16 area using colors of two palettes is reported by its pixel coordinates
+    /// pixels[17] = palette::rgb(0x1A);
+    /// match Background::from_rgb(&pixels, 256, 240, &background_palette) {
+    ///     Err(err) => assert_eq!(err.to_string(), "16x16 areas at pixel (16, 0) use more than one palette"),
+    ///     Ok(_) => panic!("the area at (16, 0) needs palettes 1 and 2"),
+    /// }
+    /// ```
+    pub fn attribute_tables(&self) -> Vec<u8> {
+        let areas_wide = self.width / 2;
+        let screen_areas_wide = SCREEN_WIDTH / 2;
+        let screen_areas_high = SCREEN_HEIGHT / 2;
+
+        let palette = |x: usize, y: usize| -> u8 {
+            self.palettes[y * areas_wide + x] & 0x03
+        };
+
+        let mut output = Vec::new();
+        for screen_y in 0..self.screens_high() {
+            for screen_x in 0..self.screens_wide() {
+                for y in 0..(ATTRIBUTE_TABLE_SIZE / 8) {
+                    for x in 0..8 {
+                        let area_x = screen_x * screen_areas_wide + x * 2;
+                        let local_y = y * 2;
+                        let area = |dx: usize, dy: usize| -> u8 {
+                            let local = local_y + dy;
+                            if local >= screen_areas_high {
+                                0
+                            } else {
+                                palette(area_x + dx, screen_y * screen_areas_high + local)
+                            }
+                        };
+                        output.push(area(0, 0) | area(1, 0) << 2 | area(0, 1) << 4 | area(1, 1) << 6);
+                    }
+                }
+            }
+        }
+        output
+    }
+}
@@ -0,0 +1,201 @@
+//! Tools for working with NES colors and palettes.  Colors may be given by number or by name, and
+//! palette sets (a full 32-byte background and sprite palette) can be compiled from YAML
+//! descriptions, as described in [`serialize`](serialize/index.html).
+//!
+//! Color names are built from the hue columns of the NES palette, with a brightness prefix for
+//! each row: `dark_` for `$0x`, none for `$1x`, `light_` for `$2x`, and `pale_` for `$3x`.  The hues
+//! are `blue`, `indigo`, `violet`, `purple`, `magenta`, `red`, `orange`, `yellow`, `lime`,
+//! `green`, `sea_green`, and `cyan`, for columns 1 through C.  The grays are `dark_gray` (`$00`),
+//! `gray` (`$10`), `light_gray` (`$3D`), and `white` (`$30`), and `black` is `$0F`.  A few extra
+//! common names, like `sky_blue` (`$21`), are also available.
+//!
+
+pub mod serialize;
+
+use std::error;
+use std::fmt;
+use std::io;
+
+/// The size of a full compiled palette: 16 bytes of background palette, then 16 of sprite palette.
+pub const PALETTE_SIZE: usize = 32;
+
+/// Global palette error type.
+#[derive(Debug)]
+pub enum Error {
+    /// If a color is not a known name or a valid NES color number
+    ColorError(String),
+
+    /// If a palette description is not valid
+    FormatError(String),
+
+    /// If a palette file could not be read.  The string is the file name.
+    IoError(String, io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::ColorError(err) => write!(f, "{}", err),
+            Error::FormatError(err) => write!(f, "{}", err),
+            Error::IoError(file, err) => write!(f, "{}: {}", file, err),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match self {
+            Error::ColorError(err) => err,
+            Error::FormatError(err) => err,
+            Error::IoError(_, _) => "IO error reading palette file",
+        }
+    }
+}
+
+/// Hue names, for columns 1 through C of the NES palette
+const HUES: [&str; 12] = [
+    "blue", "indigo", "violet", "purple", "magenta", "red",
+    "orange", "yellow", "lime", "green", "sea_green", "cyan",
+];
+
+/// Brightness prefixes, for each row of the NES palette
+const BRIGHTNESSES: [&str; 4] = ["dark_", "", "light_", "pale_"];
+
+/// Named colors that are not simply a brightness and a hue
+const EXTRA_NAMES: [(&str, u8); 10] = [
+    ("black", 0x0F),
+    ("dark_gray", 0x00),
+    ("gray", 0x10),
+    ("light_gray", 0x3D),
+    ("white", 0x30),
+    ("sky_blue", 0x21),
+    ("navy", 0x02),
+    ("brown", 0x07),
+    ("pink", 0x35),
+    ("peach", 0x37),
+];
+
+/// The RGB value of every NES color, indexed by color number.  The NES has no true RGB output, so
+/// this is one common approximation of what the 2C02 PPU produces.
+pub const NES_COLORS: [[u8; 3]; 64] = [
+    [0x7C, 0x7C, 0x7C], [0x00, 0x00, 0xFC], [0x00, 0x00, 0xBC], [0x44, 0x28, 0xBC],
+    [0x94, 0x00, 0x84], [0xA8, 0x00, 0x20], [0xA8, 0x10, 0x00], [0x88, 0x14, 0x00],
+    [0x50, 0x30, 0x00], [0x00, 0x78, 0x00], [0x00, 0x68, 0x00], [0x00, 0x58, 0x00],
+    [0x00, 0x40, 0x58], [0x00, 0x00, 0x00], [0x00, 0x00, 0x00], [0x00, 0x00, 0x00],
+    [0xBC, 0xBC, 0xBC], [0x00, 0x78, 0xF8], [0x00, 0x58, 0xF8], [0x68, 0x44, 0xFC],
+    [0xD8, 0x00, 0xCC], [0xE4, 0x00, 0x58], [0xF8, 0x38, 0x00], [0xE4, 0x5C, 0x10],
+    [0xAC, 0x7C, 0x00], [0x00, 0xB8, 0x00], [0x00, 0xA8, 0x00], [0x00, 0xA8, 0x44],
+    [0x00, 0x88, 0x88], [0x00, 0x00, 0x00], [0x00, 0x00, 0x00], [0x00, 0x00, 0x00],
+    [0xF8, 0xF8, 0xF8], [0x3C, 0xBC, 0xFC], [0x68, 0x88, 0xFC], [0x98, 0x78, 0xF8],
+    [0xF8, 0x78, 0xF8], [0xF8, 0x58, 0x98], [0xF8, 0x78, 0x58], [0xFC, 0xA0, 0x44],
+    [0xF8, 0xB8, 0x00], [0xB8, 0xF8, 0x18], [0x58, 0xD8, 0x54], [0x58, 0xF8, 0x98],
+    [0x00, 0xE8, 0xD8], [0x78, 0x78, 0x78], [0x00, 0x00, 0x00], [0x00, 0x00, 0x00],
+    [0xFC, 0xFC, 0xFC], [0xA4, 0xE4, 0xFC], [0xB8, 0xB8, 0xF8], [0xD8, 0xB8, 0xF8],
+    [0xF8, 0xB8, 0xF8], [0xF8, 0xA4, 0xC0], [0xF0, 0xD0, 0xB0], [0xFC, 0xE0, 0xA8],
+    [0xF8, 0xD8, 0x78], [0xD8, 0xF8, 0x78], [0xB8, 0xF8, 0xB8], [0xB8, 0xF8, 0xD8],
+    [0x00, 0xFC, 0xFC], [0xF8, 0xD8, 0xF8], [0x00, 0x00, 0x00], [0x00, 0x00, 0x00],
+];
+
+/// Look up a color name, as described in the module documentation.  Names are case-insensitive.
+pub fn named(name: &str) -> Option<u8> {
+    let name = name.to_lowercase();
+    if let Some(&(_, color)) = EXTRA_NAMES.iter().find(|(extra, _)| *extra == name) {
+        return Some(color);
+    }
+    for (row, brightness) in BRIGHTNESSES.iter().enumerate() {
+        for (column, hue) in HUES.iter().enumerate() {
+            if name.len() == brightness.len() + hue.len() && name.starts_with(brightness) && name.ends_with(hue) {
+                return Some((row << 4 | (column + 1)) as u8);
+            }
+        }
+    }
+    None
+}
+
+/// Parse a color, which may be a name, or a number in `$0F`, `0x0F`, or plain decimal form.
+///
+/// ```
+/// use nestools::palette::parse_color;
+///
+/// assert_eq!(parse_color("black").unwrap(), 0x0F);
+/// assert_eq!(parse_color("Sky_Blue").unwrap(), 0x21);
+/// assert_eq!(parse_color("light_red").unwrap(), 0x26);
+/// assert_eq!(parse_color("$16").unwrap(), 0x16);
+/// assert_eq!(parse_color("0x2A").unwrap(), 0x2A);
+/// assert_eq!(parse_color("48").unwrap(), 0x30);
+/// assert!(parse_color("$40").is_err());
+/// assert!(parse_color("chartreuse").is_err());
+/// ```
+pub fn parse_color(color: &str) -> Result<u8, Error> {
+    let trimmed = color.trim();
+    let number = if let Some(hex) = trimmed.strip_prefix('$') {
+        u8::from_str_radix(hex, 16).ok()
+    } else if let Some(hex) = trimmed.strip_prefix("0x").or_else(|| trimmed.strip_prefix("0X")) {
+        u8::from_str_radix(hex, 16).ok()
+    } else if trimmed.starts_with(|c: char| c.is_ascii_digit()) {
+        trimmed.parse().ok()
+    } else {
+        return named(trimmed).ok_or_else(|| Error::ColorError(format!("unknown color name {}", trimmed)));
+    };
+    match number {
+        Some(number) if number <= 0x3F => Ok(number),
+        Some(number) => Err(Error::ColorError(format!(
+            "color {} is {:#04X}, but NES colors only go up to 0x3F", trimmed, number))),
+        None => Err(Error::ColorError(format!("could not parse color {}", trimmed))),
+    }
+}
+
+/// A compiled palette set, ready to be written to $3F00.
+#[derive(Debug)]
+pub struct Palette {
+    pub name: String,
+
+    /// The 4 background palettes, then the 4 sprite palettes, 4 colors each
+    pub colors: [u8; PALETTE_SIZE],
+}
+
+impl Palette {
+    /// The 16-byte background half of the palette
+    pub fn background(&self) -> [u8; 16] {
+        let mut background = [0; 16];
+        background.copy_from_slice(&self.colors[..16]);
+        background
+    }
+
+    /// The 16-byte sprite half of the palette
+    pub fn sprite(&self) -> [u8; 16] {
+        let mut sprite = [0; 16];
+        sprite.copy_from_slice(&self.colors[16..]);
+        sprite
+    }
+}
+
+/// The RGB value of a NES color.  Only the low 6 bits of the color are used.
+pub fn rgb(color: u8) -> [u8; 3] {
+    NES_COLORS[(color & 0x3F) as usize]
+}
+
+/// Find the NES color closest to an RGB value.  Several NES colors share the same RGB value (most
+/// notably black), in which case the lowest is returned.
+///
+/// ```
+/// use nestools::palette::nearest;
+///
+/// assert_eq!(nearest([0x00, 0x00, 0xFC]), 0x01);
+/// assert_eq!(nearest([0x02, 0x01, 0x00]), 0x0D);
+/// assert_eq!(nearest([0xFF, 0xFF, 0xFF]), 0x30);
+/// ```
+pub fn nearest(rgb: [u8; 3]) -> u8 {
+    let distance = |color: &[u8; 3]| -> u32 {
+        color.iter().zip(rgb.iter())
+            .map(|(&a, &b)| (a as i32 - b as i32).pow(2) as u32)
+            .sum()
+    };
+    let mut best = 0;
+    for (index, color) in NES_COLORS.iter().enumerate() {
+        if distance(color) < distance(&NES_COLORS[best]) {
+            best = index;
+        }
+    }
+    best as u8
+}
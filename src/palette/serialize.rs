@@ -0,0 +1,177 @@
+//! This module works with the serialization of palette sets, which describe a full background and
+//! sprite palette with named or numbered colors.
+
+use super::{parse_color, Error, Palette, PALETTE_SIZE};
+
+use std::fs::File;
+
+/// A single color, either as a number or as a string to be parsed with
+/// [`parse_color`](../fn.parse_color.html)
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Color {
+    Number(u8),
+    Name(String),
+}
+
+impl Color {
+    /// Resolve the color to its NES color number.
+    pub fn resolve(&self) -> Result<u8, Error> {
+        match self {
+            Color::Number(number) if *number <= 0x3F => Ok(*number),
+            Color::Number(number) => Err(Error::ColorError(format!(
+                "color {:#04X} is out of range; NES colors only go up to 0x3F", number))),
+            Color::Name(name) => parse_color(name),
+        }
+    }
+}
+
+/// A named palette set.  Each of the background and sprite lists holds up to 4 palettes, which
+/// may either be 3 colors (with color 0 filled in) or all 4.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct PaletteDescription {
+    pub name: String,
+
+    /// The universal background color.  Defaults to color 0 of the first background palette if
+    /// it has 4 colors, or black otherwise.
+    #[serde(default)]
+    pub universal: Option<Color>,
+
+    #[serde(default)]
+    pub background: Vec<Vec<Color>>,
+
+    #[serde(default)]
+    pub sprite: Vec<Vec<Color>>,
+}
+
+/// Load a list of palette descriptions from a YAML file.
+pub fn load(filename: &str) -> Result<Vec<PaletteDescription>, Error> {
+    let file = match File::open(filename) {
+        Ok(file) => file,
+        Err(err) => return Err(Error::IoError(String::from(filename), err)),
+    };
+    match serde_yaml::from_reader(file) {
+        Ok(descriptions) => Ok(descriptions),
+        Err(err) => Err(Error::FormatError(format!("{}: {}", filename, err))),
+    }
+}
+
+impl PaletteDescription {
+    /// Build the palette, collecting every problem rather than stopping at the first.
+    fn compile(&self) -> (Palette, Vec<String>) {
+        let mut problems = Vec::new();
+        let mut colors = [0u8; PALETTE_SIZE];
+
+        let resolve = |color: &Color, problems: &mut Vec<String>| match color.resolve() {
+            Ok(color) => color,
+            Err(err) => {
+                problems.push(format!("palette {}: {}", self.name, err));
+                0x0F
+            },
+        };
+
+        let universal = match (&self.universal, self.background.first()) {
+            (Some(color), _) => resolve(color, &mut problems),
+            (None, Some(first)) if first.len() == 4 => resolve(&first[0], &mut problems),
+            _ => 0x0F,
+        };
+
+        for (kind, palettes, base) in &[("background", &self.background, 0), ("sprite", &self.sprite, 16)] {
+            if palettes.len() > 4 {
+                problems.push(format!(
+                    "palette {} has {} {} palettes, but there can only be 4",
+                    self.name, palettes.len(), kind));
+            }
+            for index in 0..4 {
+                let offset = base + index * 4;
+                // Sprite color 0 mirrors background color 0, so it follows whatever that is
+                colors[offset] = if *base == 0 { universal } else { colors[index * 4] };
+                for slot in 1..4 {
+                    colors[offset + slot] = universal;
+                }
+
+                let palette = match palettes.get(index) {
+                    Some(palette) => palette,
+                    None => continue,
+                };
+                match palette.len() {
+                    3 => for (slot, color) in palette.iter().enumerate() {
+                        colors[offset + slot + 1] = resolve(color, &mut problems);
+                    },
+                    4 => {
+                        let first = resolve(&palette[0], &mut problems);
+                        if *base == 0 && index == 0 && first != universal {
+                            problems.push(format!(
+                                "palette {}: background palette 0 color 0 ($3F00) is ${:02X}, but the universal color is ${:02X}",
+                                self.name, first, universal));
+                        } else if *base == 16 && first != colors[index * 4] {
+                            problems.push(format!(
+                                "palette {}: sprite palette {} color 0 (${:04X}) is ${:02X}, but it mirrors ${:04X}, which is ${:02X}",
+                                self.name, index, 0x3F00 + offset, first, 0x3F00 + index * 4, colors[index * 4]));
+                        }
+                        colors[offset] = first;
+                        for (slot, color) in palette.iter().enumerate().skip(1) {
+                            colors[offset + slot] = resolve(color, &mut problems);
+                        }
+                    },
+                    count => problems.push(format!(
+                        "palette {}: {} palette {} has {} colors, but must have 3 or 4",
+                        self.name, kind, index, count)),
+                }
+            }
+        }
+
+        (Palette { name: self.name.clone(), colors }, problems)
+    }
+
+    /// Validate the palette, returning every problem found.  This includes unknown colors, bad
+    /// palette sizes, and a color 0 that does not match the one it is mirrored with: $3F10,
+    /// $3F14, $3F18, and $3F1C are mirrors of $3F00, $3F04, $3F08, and $3F0C.
+    pub fn check(&self) -> Vec<String> {
+        self.compile().1
+    }
+
+    /// Build the palette, failing on the first problem [`check`](#method.check) would report.
+    /// Palettes that are not given are filled with the universal color.
+    ///
+    /// ```
+    /// use nestools::palette::serialize::PaletteDescription;
+    ///
+    /// let description: PaletteDescription = serde_yaml::from_str(r#"
+    /// name: OVERWORLD
+    /// universal: sky_blue
+    /// background:
+    ///   - [white, light_gray, $16]
+    ///   - [sky_blue, dark_green, green, 0x2A]
+    /// sprite:
+    ///   - [black, red, pale_orange]
+    /// "#).unwrap();
+    ///
+    /// let palette = description.build().unwrap();
+    /// assert_eq!(palette.background(), [
+    ///     0x21, 0x30, 0x3D, 0x16,
+    ///     0x21, 0x0A, 0x1A, 0x2A,
+    ///     0x21, 0x21, 0x21, 0x21,
+    ///     0x21, 0x21, 0x21, 0x21,
+    /// ]);
+    /// assert_eq!(&palette.sprite()[..4], &[0x21, 0x0F, 0x16, 0x37]);
+    ///
+    /// let mirrored: PaletteDescription = serde_yaml::from_str(r#"
+    /// name: BAD
+    /// background:
+    ///   - [black, white, gray, dark_gray]
+    /// sprite:
+    ///   - [white, red, orange, yellow]
+    /// "#).unwrap();
+    /// assert_eq!(mirrored.check().len(), 1);
+    /// assert!(mirrored.build().is_err());
+    /// ```
+    pub fn build(&self) -> Result<Palette, Error> {
+        let (palette, problems) = self.compile();
+        match problems.into_iter().next() {
+            Some(problem) => Err(Error::FormatError(problem)),
+            None => Ok(palette),
+        }
+    }
+}
@@ -0,0 +1,563 @@
+//! Tools for working with iNES and NES 2.0 ROM files.  This allows parsing and writing the
+//! header, and splitting a ROM into its sections, so that the CHR data can be pulled out as
+//! pattern tables or replaced.
+//!
+
+pub mod serialize;
+
+use crate::sprites::{self, PatternTable};
+use std::error;
+use std::fmt;
+use std::io;
+
+/// The size of a single PRG-ROM bank, which is also the unit of the PRG-ROM size in the header.
+pub const PRG_BANK_SIZE: usize = 16384;
+
+/// The size of a single CHR-ROM bank, which is also the unit of the CHR-ROM size in the header.
+/// This is exactly the size of one full [`PatternTable`](../sprites/struct.PatternTable.html).
+pub const CHR_BANK_SIZE: usize = 8192;
+
+/// The size of the trainer, if present.
+pub const TRAINER_SIZE: usize = 512;
+
+/// Global ROM error type.
+#[derive(Debug)]
+pub enum Error {
+    /// If the header or file layout is not valid iNES or NES 2.0
+    FormatError(String),
+
+    /// If some data does not have the size the header says it should, or a size can not be
+    /// represented in the header
+    SizeError(String),
+
+    /// If some file making up the ROM could not be read.  The string is the file name.
+    IoError(String, io::Error),
+
+    /// If a pattern table could not be built from a sprite sheet manifest
+    SpriteError(String, sprites::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::FormatError(err) => write!(f, "{}", err),
+            Error::SizeError(err) => write!(f, "{}", err),
+            Error::IoError(file, err) => write!(f, "{}: {}", file, err),
+            Error::SpriteError(file, err) => write!(f, "{}: {}", file, err),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match self {
+            Error::FormatError(err) => err,
+            Error::SizeError(err) => err,
+            Error::IoError(_, _) => "IO error reading ROM section",
+            Error::SpriteError(_, _) => "Error building pattern table",
+        }
+    }
+}
+
+/// Allowed ROM sizes for a known mapper.  All sizes are in bytes, and every size must be a power
+/// of two between the minimum and maximum.
+pub struct MapperLimits {
+    pub name: &'static str,
+    pub prg_min: usize,
+    pub prg_max: usize,
+
+    /// Minimum CHR-ROM size.  0 means the board may use CHR-RAM instead.
+    pub chr_min: usize,
+
+    /// Maximum CHR-ROM size.  0 means the board only uses CHR-RAM.
+    pub chr_max: usize,
+}
+
+/// The limits of the common mappers.  Mappers not listed here are only checked for whole banks.
+pub fn mapper_limits(mapper: u16) -> Option<MapperLimits> {
+    const K: usize = 1024;
+    let (name, prg_min, prg_max, chr_min, chr_max) = match mapper {
+        0 => ("NROM", 16 * K, 32 * K, 8 * K, 8 * K),
+        1 => ("MMC1", 32 * K, 512 * K, 0, 128 * K),
+        2 => ("UxROM", 32 * K, 256 * K, 0, 8 * K),
+        3 => ("CNROM", 16 * K, 32 * K, 8 * K, 32 * K),
+        4 => ("MMC3", 32 * K, 512 * K, 0, 256 * K),
+        7 => ("AxROM", 32 * K, 256 * K, 0, 0),
+        9 => ("MMC2", 128 * K, 128 * K, 8 * K, 128 * K),
+        66 => ("GxROM", 32 * K, 128 * K, 8 * K, 32 * K),
+        _ => return None,
+    };
+    Some(MapperLimits {
+        name,
+        prg_min,
+        prg_max,
+        chr_min,
+        chr_max,
+    })
+}
+
+/// Nametable mirroring, as set in the header.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Mirroring {
+    #[serde(rename = "horizontal")]
+    #[default]
+    Horizontal,
+    #[serde(rename = "vertical")]
+    Vertical,
+    #[serde(rename = "four_screen")]
+    FourScreen,
+}
+
+/// A parsed iNES or NES 2.0 header.
+///
+/// ROM sizes are always stored in bytes.  Fields that only exist in NES 2.0 are ignored when
+/// writing a plain iNES header, and [`check`](#method.check) reports any that are set.
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+pub struct Header {
+    /// Whether this is a NES 2.0 header rather than a plain iNES one
+    pub nes2: bool,
+
+    /// Size of the PRG-ROM in bytes
+    pub prg_rom_size: usize,
+
+    /// Size of the CHR-ROM in bytes.  0 means the cartridge uses CHR-RAM.
+    pub chr_rom_size: usize,
+
+    pub mapper: u16,
+
+    /// NES 2.0 only
+    pub submapper: u8,
+
+    pub mirroring: Mirroring,
+
+    /// Whether the cartridge has battery-backed or other non-volatile memory
+    pub battery: bool,
+
+    /// Whether a 512-byte trainer precedes the PRG-ROM
+    pub trainer: bool,
+
+    /// Console type, from the low bits of byte 7.  0 is a regular NES or Famicom.
+    pub console_type: u8,
+
+    /// PRG-RAM size as a shift count, so the size is `64 << shift`, or 0 for none.  NES 2.0 only.
+    pub prg_ram_shift: u8,
+
+    /// PRG-NVRAM size as a shift count.  NES 2.0 only.
+    pub prg_nvram_shift: u8,
+
+    /// CHR-RAM size as a shift count.  NES 2.0 only.
+    pub chr_ram_shift: u8,
+
+    /// CHR-NVRAM size as a shift count.  NES 2.0 only.
+    pub chr_nvram_shift: u8,
+
+    /// CPU/PPU timing.  0 is NTSC, 1 is PAL, 2 is multi-region, 3 is Dendy.  Only NTSC and PAL
+    /// can be represented in a plain iNES header.
+    pub timing: u8,
+
+    /// Vs. System or extended console type byte.  NES 2.0 only.
+    pub system_type: u8,
+
+    /// Number of miscellaneous ROMs after the CHR-ROM.  NES 2.0 only.
+    pub misc_roms: u8,
+
+    /// Default expansion device.  NES 2.0 only.
+    pub expansion_device: u8,
+}
+
+/// Decode a NES 2.0 ROM size from its LSB and MSB nibble.
+fn decode_size(lsb: u8, msb: u8, unit: usize) -> usize {
+    if msb == 0x0F {
+        // Exponent-multiplier notation: EEEEEEMM is 2^E * (MM * 2 + 1)
+        let exponent = (lsb >> 2) as u32;
+        let multiplier = (lsb & 0x03) as usize * 2 + 1;
+        2usize.saturating_pow(exponent).saturating_mul(multiplier)
+    } else {
+        ((msb as usize) << 8 | lsb as usize) * unit
+    }
+}
+
+/// Encode a NES 2.0 ROM size into its LSB and MSB nibble, using exponent-multiplier notation if
+/// it is not a whole number of units.
+fn encode_size(size: usize, unit: usize, name: &str) -> Result<(u8, u8), Error> {
+    if size.is_multiple_of(unit) && size / unit < 0xF00 {
+        let units = size / unit;
+        return Ok(((units & 0xFF) as u8, (units >> 8) as u8));
+    }
+    for multiplier in 0..4usize {
+        let odd = multiplier * 2 + 1;
+        if size.is_multiple_of(odd) && (size / odd).is_power_of_two() {
+            let exponent = (size / odd).trailing_zeros();
+            if exponent < 64 {
+                return Ok(((exponent as u8) << 2 | multiplier as u8, 0x0F));
+            }
+        }
+    }
+    Err(Error::SizeError(format!("{} size of {} bytes can not be represented in a NES 2.0 header", name, size)))
+}
+
+impl Header {
+    /// Parse a 16-byte header.
+    ///
+    /// ```
+    /// use nestools::rom::{Header, Mirroring};
+    ///
+    /// let bytes = [
+    ///     b'N', b'E', b'S', 0x1A,
+    ///     0x02, 0x01, 0x41, 0x08,
+    ///     0x10, 0x00, 0x07, 0x00,
+    ///     0x00, 0x00, 0x00, 0x01,
+    /// ];
+    /// let header = Header::parse(&bytes).unwrap();
+    /// assert!(header.nes2);
+    /// assert_eq!(header.mapper, 4);
+    /// assert_eq!(header.submapper, 1);
+    /// assert_eq!(header.prg_rom_size, 32768);
+    /// assert_eq!(header.chr_rom_size, 8192);
+    /// assert_eq!(header.mirroring, Mirroring::Vertical);
+    /// assert_eq!(header.prg_ram_shift, 7);
+    /// assert_eq!(header.to_bytes().unwrap(), bytes);
+    /// ```
+    pub fn parse(bytes: &[u8]) -> Result<Header, Error> {
+        if bytes.len() < 16 {
+            return Err(Error::SizeError(format!("Header needs 16 bytes, got {}", bytes.len())));
+        }
+        if bytes[0..4] != *b"NES\x1A" {
+            return Err(Error::FormatError(String::from("File does not start with the iNES magic number")));
+        }
+
+        let flags6 = bytes[6];
+        let flags7 = bytes[7];
+        let nes2 = flags7 & 0x0C == 0x08;
+
+        let mirroring = if flags6 & 0x08 != 0 {
+            Mirroring::FourScreen
+        } else if flags6 & 0x01 != 0 {
+            Mirroring::Vertical
+        } else {
+            Mirroring::Horizontal
+        };
+
+        let mut header = Header {
+            nes2,
+            mirroring,
+            battery: flags6 & 0x02 != 0,
+            trainer: flags6 & 0x04 != 0,
+            console_type: flags7 & 0x03,
+            ..Header::default()
+        };
+
+        if nes2 {
+            header.mapper = (flags6 >> 4) as u16 | (flags7 & 0xF0) as u16 | ((bytes[8] & 0x0F) as u16) << 8;
+            header.submapper = bytes[8] >> 4;
+            header.prg_rom_size = decode_size(bytes[4], bytes[9] & 0x0F, PRG_BANK_SIZE);
+            header.chr_rom_size = decode_size(bytes[5], bytes[9] >> 4, CHR_BANK_SIZE);
+            header.prg_ram_shift = bytes[10] & 0x0F;
+            header.prg_nvram_shift = bytes[10] >> 4;
+            header.chr_ram_shift = bytes[11] & 0x0F;
+            header.chr_nvram_shift = bytes[11] >> 4;
+            header.timing = bytes[12] & 0x03;
+            header.system_type = bytes[13];
+            header.misc_roms = bytes[14] & 0x03;
+            header.expansion_device = bytes[15] & 0x3F;
+        } else {
+            // Old dumping tools wrote garbage into the tail of the header, which makes the upper
+            // mapper nibble unreliable
+            let upper = if bytes[12..16].iter().all(|&byte| byte == 0) {
+                flags7 & 0xF0
+            } else {
+                0
+            };
+            header.mapper = (flags6 >> 4 | upper) as u16;
+            header.prg_rom_size = bytes[4] as usize * PRG_BANK_SIZE;
+            header.chr_rom_size = bytes[5] as usize * CHR_BANK_SIZE;
+            header.timing = bytes[9] & 0x01;
+        }
+
+        Ok(header)
+    }
+
+    /// Check the ROM sizes against the limits of the mapper, and that every field can be written in
+    /// the chosen header format, collecting every problem found.  An empty list means the header
+    /// is valid.
+    ///
+    /// ```
+    /// use nestools::rom::{mapper_limits, Header};
+    ///
+    /// let header = Header {prg_rom_size: 32768, chr_rom_size: 8192, ..Header::default()};
+    /// assert_eq!(mapper_limits(header.mapper).unwrap().name, "NROM");
+    /// assert!(header.check().is_empty());
+    ///
+    /// // Half a PRG-ROM bank
+    /// let header = Header {prg_rom_size: 8192, chr_rom_size: 8192, ..Header::default()};
+    /// assert_eq!(header.check().len(), 2);
+    ///
+    /// // Multi-region timing and PRG-RAM sizes only exist in NES 2.0 headers
+    /// let header = Header {prg_rom_size: 32768, chr_rom_size: 8192, timing: 2, prg_ram_shift: 7, ..Header::default()};
+    /// assert_eq!(header.check().len(), 2);
+    /// assert!(Header {nes2: true, ..header}.check().is_empty());
+    /// ```
+    pub fn check(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if !self.nes2 {
+            if self.timing > 1 {
+                let timing = if self.timing == 2 { "multi-region" } else { "Dendy" };
+                problems.push(format!(
+                    "{} timing can only be given in a NES 2.0 header, but the header is plain iNES",
+                    timing));
+            }
+            let ram = [
+                ("PRG-RAM", self.prg_ram_shift),
+                ("PRG-NVRAM", self.prg_nvram_shift),
+                ("CHR-RAM", self.chr_ram_shift),
+                ("CHR-NVRAM", self.chr_nvram_shift),
+            ];
+            for (name, shift) in ram.iter().filter(|(_, shift)| *shift != 0) {
+                problems.push(format!(
+                    "{} size of {} bytes can only be given in a NES 2.0 header, but the header is plain iNES",
+                    name, 64usize << shift));
+            }
+        }
+
+        if self.prg_rom_size == 0 || !self.prg_rom_size.is_multiple_of(PRG_BANK_SIZE) {
+            problems.push(format!(
+                "PRG-ROM is {} bytes, but must be a nonzero multiple of {}",
+                self.prg_rom_size, PRG_BANK_SIZE));
+        }
+        if !self.chr_rom_size.is_multiple_of(CHR_BANK_SIZE) {
+            problems.push(format!(
+                "CHR-ROM is {} bytes, but must be a multiple of {}",
+                self.chr_rom_size, CHR_BANK_SIZE));
+        }
+
+        if let Some(limits) = mapper_limits(self.mapper) {
+            let prg = self.prg_rom_size;
+            if !prg.is_power_of_two() || prg < limits.prg_min || prg > limits.prg_max {
+                problems.push(format!(
+                    "PRG-ROM is {} bytes, but {} (mapper {}) needs a power of two from {} to {} bytes",
+                    prg, limits.name, self.mapper, limits.prg_min, limits.prg_max));
+            }
+            let chr = self.chr_rom_size;
+            if chr == 0 {
+                if limits.chr_min > 0 {
+                    problems.push(format!(
+                        "{} (mapper {}) needs CHR-ROM, but none was given",
+                        limits.name, self.mapper));
+                }
+            } else if limits.chr_max == 0 {
+                problems.push(format!(
+                    "{} (mapper {}) only uses CHR-RAM, but {} bytes of CHR-ROM were given",
+                    limits.name, self.mapper, chr));
+            } else if !chr.is_power_of_two() || chr < limits.chr_min.max(CHR_BANK_SIZE) || chr > limits.chr_max {
+                problems.push(format!(
+                    "CHR-ROM is {} bytes, but {} (mapper {}) needs a power of two from {} to {} bytes",
+                    chr, limits.name, self.mapper, limits.chr_min.max(CHR_BANK_SIZE), limits.chr_max));
+            }
+        }
+
+        problems
+    }
+
+    /// Serialize this header into its 16 bytes.  Fails if a size or the mapper can not be
+    /// represented in the chosen header format.
+    pub fn to_bytes(&self) -> Result<[u8; 16], Error> {
+        let mut bytes = [0u8; 16];
+        bytes[0..4].copy_from_slice(b"NES\x1A");
+
+        let mut flags6 = (self.mapper as u8 & 0x0F) << 4;
+        match self.mirroring {
+            Mirroring::Horizontal => (),
+            Mirroring::Vertical => flags6 |= 0x01,
+            Mirroring::FourScreen => flags6 |= 0x08,
+        }
+        if self.battery {
+            flags6 |= 0x02;
+        }
+        if self.trainer {
+            flags6 |= 0x04;
+        }
+        bytes[6] = flags6;
+        bytes[7] = (self.mapper as u8 & 0xF0) | (self.console_type & 0x03);
+
+        if self.nes2 {
+            if self.mapper > 0x0FFF {
+                return Err(Error::FormatError(format!("Mapper {} does not fit in a NES 2.0 header", self.mapper)));
+            }
+            let (prg_lsb, prg_msb) = encode_size(self.prg_rom_size, PRG_BANK_SIZE, "PRG-ROM")?;
+            let (chr_lsb, chr_msb) = encode_size(self.chr_rom_size, CHR_BANK_SIZE, "CHR-ROM")?;
+            bytes[4] = prg_lsb;
+            bytes[5] = chr_lsb;
+            bytes[7] |= 0x08;
+            bytes[8] = (self.mapper >> 8) as u8 & 0x0F | self.submapper << 4;
+            bytes[9] = prg_msb | chr_msb << 4;
+            bytes[10] = self.prg_ram_shift & 0x0F | self.prg_nvram_shift << 4;
+            bytes[11] = self.chr_ram_shift & 0x0F | self.chr_nvram_shift << 4;
+            bytes[12] = self.timing & 0x03;
+            bytes[13] = self.system_type;
+            bytes[14] = self.misc_roms & 0x03;
+            bytes[15] = self.expansion_device & 0x3F;
+        } else {
+            if self.mapper > 0xFF {
+                return Err(Error::FormatError(format!("Mapper {} does not fit in an iNES header, use NES 2.0", self.mapper)));
+            }
+            if !self.prg_rom_size.is_multiple_of(PRG_BANK_SIZE) || self.prg_rom_size / PRG_BANK_SIZE > 0xFF {
+                return Err(Error::SizeError(format!("PRG-ROM size of {} bytes can not be represented in an iNES header", self.prg_rom_size)));
+            }
+            if !self.chr_rom_size.is_multiple_of(CHR_BANK_SIZE) || self.chr_rom_size / CHR_BANK_SIZE > 0xFF {
+                return Err(Error::SizeError(format!("CHR-ROM size of {} bytes can not be represented in an iNES header", self.chr_rom_size)));
+            }
+            bytes[4] = (self.prg_rom_size / PRG_BANK_SIZE) as u8;
+            bytes[5] = (self.chr_rom_size / CHR_BANK_SIZE) as u8;
+            bytes[9] = self.timing & 0x01;
+        }
+
+        Ok(bytes)
+    }
+}
+
+/// A full ROM file, split into its sections.
+pub struct Rom {
+    pub header: Header,
+
+    /// The trainer, present if the header says so
+    pub trainer: Option<Vec<u8>>,
+
+    pub prg: Vec<u8>,
+    pub chr: Vec<u8>,
+
+    /// Anything after the CHR-ROM, such as NES 2.0 miscellaneous ROMs
+    pub extra: Vec<u8>,
+}
+
+impl Rom {
+    /// Split a full ROM file into its sections, checking the sizes against the header.
+    ///
+    /// ```
+    /// use nestools::rom::Rom;
+    ///
+    /// // NROM with one PRG-ROM bank and one CHR-ROM bank
+    /// let mut bytes = vec![b'N', b'E', b'S', 0x1A, 0x01, 0x01, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    /// bytes.resize(16 + 16384, 0xEA);
+    /// bytes.resize(16 + 16384 + 8192, 0x00);
+    /// let rom = Rom::parse(&bytes).unwrap();
+    /// assert_eq!((rom.prg.len(), rom.chr.len(), rom.chr_banks()), (16384, 8192, 1));
+    ///
+    /// // A file shorter than its header says is an error, as is a short header
+    /// assert!(Rom::parse(&bytes[..bytes.len() - 1]).is_err());
+    /// assert!(Rom::parse(&bytes[..10]).is_err());
+    ///
+    /// // So is a NES 2.0 exponent size far bigger than any file
+    /// let mut huge = bytes.clone();
+    /// huge[7] = 0x08;
+    /// huge[4] = 0xFF;
+    /// huge[9] = 0x0F;
+    /// assert!(Rom::parse(&huge).is_err());
+    /// ```
+    pub fn parse(bytes: &[u8]) -> Result<Rom, Error> {
+        let header = Header::parse(bytes)?;
+        let mut offset: usize = 16;
+
+        let mut take = |size: usize, name: &str| -> Result<Vec<u8>, Error> {
+            // A crafted NES 2.0 size can be anything up to usize::MAX
+            let end = match offset.checked_add(size) {
+                Some(end) if end <= bytes.len() => end,
+                _ => return Err(Error::SizeError(format!(
+                    "ROM is too short.  Header specifies {} bytes of {} at offset {}, but the file is only {} bytes",
+                    size, name, offset, bytes.len()))),
+            };
+            let section = bytes[offset..end].to_vec();
+            offset = end;
+            Ok(section)
+        };
+
+        let trainer = if header.trainer {
+            Some(take(TRAINER_SIZE, "trainer")?)
+        } else {
+            None
+        };
+        let prg = take(header.prg_rom_size, "PRG-ROM")?;
+        let chr = take(header.chr_rom_size, "CHR-ROM")?;
+        let extra = bytes[offset..].to_vec();
+
+        Ok(Rom {
+            header,
+            trainer,
+            prg,
+            chr,
+            extra,
+        })
+    }
+
+    /// Write the full ROM file.  The header's sizes must match the sections.
+    pub fn write<T: io::Write>(&self, writer: &mut T) -> Result<(), io::Error> {
+        let header = self.header.to_bytes()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+        writer.write_all(&header)?;
+        if let Some(ref trainer) = self.trainer {
+            writer.write_all(trainer)?;
+        }
+        writer.write_all(&self.prg)?;
+        writer.write_all(&self.chr)?;
+        writer.write_all(&self.extra)?;
+        Ok(())
+    }
+
+    /// The number of full 8KiB CHR-ROM banks.
+    pub fn chr_banks(&self) -> usize {
+        self.chr.len() / CHR_BANK_SIZE
+    }
+
+    /// Pull a single CHR-ROM bank out as a pattern table.
+    pub fn chr_bank(&self, bank: usize) -> Result<PatternTable, Error> {
+        if bank >= self.chr_banks() {
+            return Err(Error::SizeError(format!(
+                "CHR bank {} does not exist.  The ROM has {} CHR banks",
+                bank, self.chr_banks())));
+        }
+        let start = bank * CHR_BANK_SIZE;
+        Ok(PatternTable::from_bytes(&self.chr[start..(start + CHR_BANK_SIZE)]))
+    }
+
+    /// Pull all CHR-ROM banks out as pattern tables.
+    pub fn pattern_tables(&self) -> Vec<PatternTable> {
+        self.chr.chunks_exact(CHR_BANK_SIZE).map(PatternTable::from_bytes).collect()
+    }
+
+    /// Replace CHR-ROM data, starting at the given bank.  The data must be a whole number of
+    /// banks, and must fit in the CHR-ROM size given in the header.
+    ///
+    /// ```
+    /// use nestools::rom::Rom;
+    ///
+    /// let mut bytes = vec![b'N', b'E', b'S', 0x1A, 0x01, 0x01, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    /// bytes.resize(16 + 16384 + 8192, 0x00);
+    /// let mut rom = Rom::parse(&bytes).unwrap();
+    ///
+    /// let mut chr = vec![0u8; 8192];
+    /// chr[0] = 0xFF;
+    /// rom.replace_chr(0, &chr).unwrap();
+    /// assert_eq!(rom.chr_bank(0).unwrap().left[0].data[0], 0xFF);
+    /// assert!(rom.chr_bank(1).is_err());
+    ///
+    /// // Only whole banks that fit in the header's CHR-ROM size can be replaced
+    /// assert!(rom.replace_chr(0, &chr[..100]).is_err());
+    /// assert!(rom.replace_chr(1, &chr).is_err());
+    /// ```
+    pub fn replace_chr(&mut self, bank: usize, chr: &[u8]) -> Result<(), Error> {
+        if chr.is_empty() || !chr.len().is_multiple_of(CHR_BANK_SIZE) {
+            return Err(Error::SizeError(format!(
+                "CHR data must be a whole number of {} byte banks, but is {} bytes",
+                CHR_BANK_SIZE, chr.len())));
+        }
+        let end = bank.checked_mul(CHR_BANK_SIZE).and_then(|start| start.checked_add(chr.len()));
+        let end = match end {
+            Some(end) if end <= self.chr.len() => end,
+            _ => return Err(Error::SizeError(format!(
+                "CHR data of {} bytes at bank {} does not fit in the {} bytes of CHR-ROM given in the header",
+                chr.len(), bank, self.header.chr_rom_size))),
+        };
+        self.chr[(end - chr.len())..end].copy_from_slice(chr);
+        Ok(())
+    }
+}
@@ -0,0 +1,227 @@
+//! This module works with the serialization of the ROM description format, which describes how to
+//! assemble a full ROM file out of a header configuration, PRG files, and CHR data.
+
+use super::{Error, Header, Mirroring, Rom, TRAINER_SIZE};
+use crate::sprites::PatternTable;
+use crate::sprites::serialize::SheetPatternTable;
+
+use std::fs::File;
+use std::io::Read;
+
+/// CPU/PPU timing mode
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+pub enum Timing {
+    #[serde(rename = "ntsc")]
+    #[default]
+    Ntsc,
+    #[serde(rename = "pal")]
+    Pal,
+    #[serde(rename = "multi")]
+    Multi,
+    #[serde(rename = "dendy")]
+    Dendy,
+}
+
+/// A single piece of CHR data
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum Chr {
+    /// A spritesheetc manifest, which is compiled into a single 8KiB bank
+    Sprites {
+        sprites: String,
+    },
+
+    /// A raw CHR file, included as-is
+    File {
+        file: String,
+    },
+}
+
+/// Top level ROM description type.  Holds all the data necessary to assemble the ROM file.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RomDescription {
+    pub mapper: u16,
+
+    #[serde(default)]
+    pub submapper: u8,
+
+    /// Whether to write a NES 2.0 header rather than a plain iNES one
+    #[serde(default)]
+    pub nes2: bool,
+
+    #[serde(default)]
+    pub mirroring: Mirroring,
+
+    #[serde(default)]
+    pub battery: bool,
+
+    #[serde(default)]
+    pub timing: Timing,
+
+    /// PRG-RAM size in bytes.  NES 2.0 only.
+    #[serde(default)]
+    pub prg_ram: usize,
+
+    /// Battery-backed PRG-RAM size in bytes.  NES 2.0 only.
+    #[serde(default)]
+    pub prg_nvram: usize,
+
+    /// CHR-RAM size in bytes.  NES 2.0 only.
+    #[serde(default)]
+    pub chr_ram: usize,
+
+    /// Battery-backed CHR-RAM size in bytes.  NES 2.0 only.
+    #[serde(default)]
+    pub chr_nvram: usize,
+
+    /// Optional 512-byte trainer file
+    #[serde(default)]
+    pub trainer: Option<String>,
+
+    /// PRG files, concatenated in order
+    pub prg: Vec<String>,
+
+    /// CHR data, concatenated in order.  Leave empty for CHR-RAM.
+    #[serde(default)]
+    pub chr: Vec<Chr>,
+}
+
+fn read_file(filename: &str) -> Result<Vec<u8>, Error> {
+    let mut data = Vec::new();
+    match File::open(filename).and_then(|mut file| file.read_to_end(&mut data)) {
+        Ok(_) => Ok(data),
+        Err(err) => Err(Error::IoError(String::from(filename), err)),
+    }
+}
+
+fn load_manifest(filename: &str) -> Result<SheetPatternTable, Error> {
+    let file = match File::open(filename) {
+        Ok(file) => file,
+        Err(err) => return Err(Error::IoError(String::from(filename), err)),
+    };
+    match serde_yaml::from_reader(file) {
+        Ok(table) => Ok(table),
+        Err(err) => Err(Error::FormatError(format!("{}: {}", filename, err))),
+    }
+}
+
+/// Convert a RAM size in bytes to the shift count used by NES 2.0, where the size is `64 << shift`.
+fn ram_shift(size: usize, name: &str) -> Result<u8, Error> {
+    if size == 0 {
+        return Ok(0);
+    }
+    if size < 128 || !size.is_power_of_two() || size > 64 << 15 {
+        return Err(Error::SizeError(format!(
+            "{} size of {} bytes must be 0, or a power of two from 128 to {}",
+            name, size, 64 << 15)));
+    }
+    Ok((size / 64).trailing_zeros() as u8)
+}
+
+impl RomDescription {
+    /// Read every file and assemble the ROM.  The header is filled out from the description and
+    /// the actual sizes of the PRG and CHR data.
+    ///
+    /// ```
+    /// use nestools::rom::Header;
+    /// use nestools::rom::serialize::RomDescription;
+    ///
+    /// let prg = std::env::temp_dir().join("nestools_build_prg.bin");
+    /// std::fs::write(&prg, vec![0xEA; 32768]).unwrap();
+    /// let description: RomDescription = serde_yaml::from_str(&format!("
+    /// mapper: 2
+    /// mirroring: vertical
+    /// prg: ['{}']
+    /// ", prg.display())).unwrap();
+    ///
+    /// let rom = description.build().unwrap();
+    /// assert!(rom.header.check().is_empty());
+    /// assert_eq!(Header::parse(&rom.header.to_bytes().unwrap()).unwrap(), rom.header);
+    /// ```
+    pub fn build(&self) -> Result<Rom, Error> {
+        let trainer = match self.trainer {
+            Some(ref filename) => {
+                let trainer = read_file(filename)?;
+                if trainer.len() != TRAINER_SIZE {
+                    return Err(Error::SizeError(format!(
+                        "trainer {} is {} bytes, but must be exactly {}",
+                        filename, trainer.len(), TRAINER_SIZE)));
+                }
+                Some(trainer)
+            },
+            None => None,
+        };
+
+        let mut prg = Vec::new();
+        for filename in &self.prg {
+            prg.extend(read_file(filename)?);
+        }
+
+        let mut chr = Vec::new();
+        for source in &self.chr {
+            match source {
+                Chr::File { file } => chr.extend(read_file(file)?),
+                Chr::Sprites { sprites } => {
+                    let pattern_table = match PatternTable::from_sheet_pattern_table(load_manifest(sprites)?) {
+                        Ok(table) => table,
+                        Err(err) => return Err(Error::SpriteError(sprites.clone(), err)),
+                    };
+                    if let Err(err) = pattern_table.write(&mut chr) {
+                        return Err(Error::IoError(sprites.clone(), err));
+                    }
+                },
+            }
+        }
+
+        let header = Header {
+            nes2: self.nes2,
+            prg_rom_size: prg.len(),
+            chr_rom_size: chr.len(),
+            mapper: self.mapper,
+            submapper: self.submapper,
+            mirroring: self.mirroring,
+            battery: self.battery,
+            trainer: trainer.is_some(),
+            console_type: 0,
+            prg_ram_shift: ram_shift(self.prg_ram, "PRG-RAM")?,
+            prg_nvram_shift: ram_shift(self.prg_nvram, "PRG-NVRAM")?,
+            chr_ram_shift: ram_shift(self.chr_ram, "CHR-RAM")?,
+            chr_nvram_shift: ram_shift(self.chr_nvram, "CHR-NVRAM")?,
+            timing: self.timing as u8,
+            system_type: 0,
+            misc_roms: 0,
+            expansion_device: 0,
+        };
+
+        Ok(Rom {
+            header,
+            trainer,
+            prg,
+            chr,
+            extra: Vec::new(),
+        })
+    }
+
+    /// Every file the ROM is built from, including the png files used by sprite sheet manifests.
+    /// Manifests that can not be loaded are listed, but not looked into.
+    pub fn dependencies(&self) -> Vec<String> {
+        let mut dependencies: Vec<String> = self.trainer.iter().chain(self.prg.iter()).cloned().collect();
+        for source in &self.chr {
+            match source {
+                Chr::File { file } => dependencies.push(file.clone()),
+                Chr::Sprites { sprites } => {
+                    dependencies.push(sprites.clone());
+                    if let Ok(table) = load_manifest(sprites) {
+                        for file in table.sheets().filter_map(|sheet| sheet.file()) {
+                            if !dependencies.iter().any(|existing| existing == file) {
+                                dependencies.push(String::from(file));
+                            }
+                        }
+                    }
+                },
+            }
+        }
+        dependencies
+    }
+}
@@ -3,8 +3,8 @@
 //!
 
 pub mod serialize;
+pub mod stats;
 use self::serialize::SheetPatternTable;
-use self::serialize::Sheet::*;
 use std::error;
 use std::fmt;
 use std::io;
@@ -31,10 +31,10 @@ pub enum Error {
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let output: &str = match self {
-            &Error::PNGError(ref err) => err.as_str(),
-            &Error::DimensionsError(ref err) => &err,
-            &Error::PaletteError(ref err) => &err,
-            &Error::FormatError(ref err) => &err,
+            Error::PNGError(err) => err.as_str(),
+            Error::DimensionsError(err) => err,
+            Error::PaletteError(err) => err,
+            Error::FormatError(err) => err,
         };
         write!(f, "{}", output)
     }
@@ -43,10 +43,10 @@ impl fmt::Display for Error {
 impl error::Error for Error {
     fn description(&self) -> &str {
         match self {
-            &Error::PNGError(ref err) => err.as_str(),
-            &Error::DimensionsError(ref err) => &err,
-            &Error::PaletteError(ref err) => &err,
-            &Error::FormatError(ref err) => &err,
+            Error::PNGError(err) => err.as_str(),
+            Error::DimensionsError(err) => err,
+            Error::PaletteError(err) => err,
+            Error::FormatError(err) => err,
         }
     }
 }
@@ -103,7 +103,7 @@ impl Tile {
     ///     [3, 0, 0, 0, 0, 2, 2, 2],
     /// ]);
     /// ```
-    pub fn iter(&self) -> TileIterator {
+    pub fn iter(&self) -> TileIterator<'_> {
         TileIterator {
             row: 0,
             tile: self,
@@ -191,12 +191,8 @@ impl Tile {
         let mut data: [u8; 16] = [0; 16];
         data.clone_from_slice(&bytes);
 
-        let new_name = match name {
-            Some(name) => Some(String::from(name)),
-            None => None
-        };
         Ok(Tile {
-            name: new_name,
+            name: name.map(String::from),
             data,
         })
     }
@@ -236,7 +232,6 @@ impl<'a> Iterator for TileIterator<'a> {
 /// [`TileIterator`]: struct.TileIterator.html
 /// [`iter`]: struct.Tile.html#method.iter
 /// [`Tile`]: struct.Tile.html
-
 pub struct TileRowIterator {
     column: u8,
     bytes: (u8, u8),
@@ -260,37 +255,167 @@ impl Iterator for TileRowIterator {
     }
 }
 
+/// One of the two pages of a pattern table.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Page {
+    #[serde(rename = "left")]
+    Left,
+    #[serde(rename = "right")]
+    Right,
+}
+
+impl fmt::Display for Page {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Page::Left => f.pad("LEFT"),
+            Page::Right => f.pad("RIGHT"),
+        }
+    }
+}
+
+/// Where a single sheet ended up in the pattern table.
+#[derive(Clone, Debug)]
+pub struct SheetPlacement {
+    /// The name of the sheet
+    pub name: String,
+
+    /// The png file the sheet was pulled from, if any
+    pub file: Option<String>,
+
+    /// The page the sheet was placed in
+    pub page: Page,
+
+    /// The index of the first tile of the sheet in its page
+    pub start: usize,
+
+    /// The number of tiles in the sheet
+    pub count: usize,
+
+    /// The 16x16 blocks of a metatile sheet, each made of 4 consecutive tiles starting at
+    /// `start`.  Empty for every other sheet type.
+    pub metatiles: Vec<MetatileBlock>,
+}
+
+/// A single 16x16 block of a metatile sheet.
+#[derive(Clone, Debug)]
+pub struct MetatileBlock {
+    /// The symbol used for the block in stage data
+    pub symbol: char,
+
+    /// The guessed background palette of the block
+    pub palette: u8,
+}
+
 /// A pattern table of tiles, in two pages.
 pub struct PatternTable {
     pub left: Vec<Tile>,
     pub right: Vec<Tile>,
+
+    /// The placement of every sheet, in the order they were placed in each page.
+    pub sheets: Vec<SheetPlacement>,
+}
+
+/// Decide which of the shared sheets go into the left page, given the amount of tiles already
+/// used in each page and the size of each shared sheet.
+///
+/// This is a simple subset-sum over the left page's free space, so it will always find an
+/// assignment if one exists.  Of all valid assignments, the one leaving the pages most evenly
+/// filled is chosen.  Returns None if the sheets can not fit at all.
+fn pack_shared(left_used: usize, right_used: usize, sizes: &[usize]) -> Option<Vec<bool>> {
+    if left_used > 256 || right_used > 256 {
+        return None;
+    }
+    let left_free = 256 - left_used;
+    let total: usize = sizes.iter().sum();
+
+    // reachable[i][sum] is whether the first i sheets can put exactly sum tiles in the left page
+    let mut reachable = vec![vec![false; left_free + 1]; sizes.len() + 1];
+    reachable[0][0] = true;
+    for (i, &size) in sizes.iter().enumerate() {
+        for sum in 0..=left_free {
+            reachable[i + 1][sum] = reachable[i][sum]
+                || (sum >= size && reachable[i][sum - size]);
+        }
+    }
+
+    let best = (0..=left_free)
+        .filter(|&sum| reachable[sizes.len()][sum] && right_used + total - sum <= 256)
+        .min_by_key(|&sum| {
+            (left_used + sum).abs_diff(right_used + total - sum)
+        })?;
+
+    // Walk backwards to recover which sheets made up the sum
+    let mut assignment = vec![false; sizes.len()];
+    let mut sum = best;
+    for i in (0..sizes.len()).rev() {
+        if !reachable[i][sum] {
+            assignment[i] = true;
+            sum -= sizes[i];
+        }
+    }
+    Some(assignment)
 }
 
 impl PatternTable {
     /// Loads in a SheetPatternTable, and uses it to create a PatternTable.
+    ///
+    /// Sheets in the `shared` list are placed after the fixed sheets of whichever page they are
+    /// assigned to, keeping each sheet contiguous and both pages within 256 tiles:
+    ///
+    /// ```
+    /// use nestools::sprites::PatternTable;
+    /// use nestools::sprites::serialize::SheetPatternTable;
+    ///
+    /// let sheet_table: SheetPatternTable = serde_yaml::from_str("
+    /// left:
+    ///   - {type: Fill, name: BIG, value: 1, count: 200}
+    /// right:
+    ///   - {type: Fill, name: SMALL, value: 2, count: 150}
+    /// shared:
+    ///   - {type: Fill, name: FIRST, value: 3, count: 100}
+    ///   - {type: Fill, name: SECOND, value: 3, count: 50}
+    /// ").unwrap();
+    ///
+    /// let pattern_table = PatternTable::from_sheet_pattern_table(sheet_table).unwrap();
+    /// assert_eq!(pattern_table.left[200].name.as_ref().unwrap(), "SECOND_0");
+    /// assert_eq!(pattern_table.right[150].name.as_ref().unwrap(), "FIRST_0");
+    ///
+    /// let overfull: SheetPatternTable = serde_yaml::from_str("
+    /// left:
+    ///   - {type: Fill, name: BIG, value: 1, count: 300}
+    /// shared:
+    ///   - {type: Fill, name: FIRST, value: 3, count: 10}
+    /// ").unwrap();
+    /// match PatternTable::from_sheet_pattern_table(overfull) {
+    ///     Err(err) => assert!(err.to_string().starts_with("left table contained too many tiles")),
+    ///     Ok(_) => panic!("the left page is over 256 tiles"),
+    /// }
+    /// ```
     pub fn from_sheet_pattern_table(sheet_table: SheetPatternTable) -> Result<PatternTable, Error> {
         let mut left = Vec::new();
         let mut right = Vec::new();
+        let mut sheets = Vec::new();
+
+        fn place(page: Page, pagetiles: &mut Vec<Tile>, sheets: &mut Vec<SheetPlacement>, sheet: &serialize::Sheet, (tiles, metatiles): (Vec<Tile>, Vec<MetatileBlock>)) {
+            sheets.push(SheetPlacement {
+                name: sheet.name().to_string(),
+                file: sheet.file().map(String::from),
+                page,
+                start: pagetiles.len(),
+                count: tiles.len(),
+                metatiles,
+            });
+            pagetiles.extend(tiles);
+        }
 
-        for sheet in sheet_table.left {
-            let tiles = match sheet {
-                Animation(sprite) => sprite.pull_tiles(),
-                Slice(sprite) => sprite.pull_tiles(),
-                Simple(sprite) => sprite.pull_tiles(),
-                Fill(sprite) => sprite.pull_tiles(),
-            }?;
-            left.extend(tiles.into_iter());
+        for sheet in &sheet_table.left {
+            place(Page::Left, &mut left, &mut sheets, sheet, sheet.pull()?);
         }
-        for sheet in sheet_table.right {
-            let tiles = match sheet {
-                Animation(sprite) => sprite.pull_tiles(),
-                Slice(sprite) => sprite.pull_tiles(),
-                Simple(sprite) => sprite.pull_tiles(),
-                Fill(sprite) => sprite.pull_tiles(),
-            }?;
-            right.extend(tiles.into_iter());
+        for sheet in &sheet_table.right {
+            place(Page::Right, &mut right, &mut sheets, sheet, sheet.pull()?);
         }
 
+        // The fixed sheets have to fit before the shared sheets can be placed around them
         if left.len() > 256 {
             return Err(Error::DimensionsError(format!(
                 "left table contained too many tiles. Can not exceed 256, but has {}",
@@ -301,6 +426,29 @@ impl PatternTable {
                 "right table contained too many tiles. Can not exceed 256, but has {}",
                 right.len())));
         }
+
+        let shared = sheet_table.shared.iter()
+            .map(|sheet| sheet.pull())
+            .collect::<Result<Vec<_>, _>>()?;
+        if !shared.is_empty() {
+            let sizes: Vec<usize> = shared.iter().map(|(tiles, _)| tiles.len()).collect();
+            let assignment = match pack_shared(left.len(), right.len(), &sizes) {
+                Some(assignment) => assignment,
+                None => return Err(Error::DimensionsError(format!(
+                    "shared sheets could not be fit into the pattern table. Left has {} tiles, right has {}, and shared sheets need {} more, out of 512",
+                    left.len(),
+                    right.len(),
+                    sizes.iter().sum::<usize>()))),
+            };
+            for ((sheet, pulled), in_left) in sheet_table.shared.iter().zip(shared).zip(assignment) {
+                if in_left {
+                    place(Page::Left, &mut left, &mut sheets, sheet, pulled);
+                } else {
+                    place(Page::Right, &mut right, &mut sheets, sheet, pulled);
+                }
+            }
+        }
+
         let blank = Tile {name: None, data: [0u8; 16]};
 
         // Pattern table must be tightly packed
@@ -313,16 +461,89 @@ impl PatternTable {
 
         Ok(PatternTable {
             left,
-            right
+            right,
+            sheets,
         })
     }
 
+    /// Build a pattern table from raw CHR data, such as a bank pulled out of a ROM.  The tiles
+    /// are unnamed.  A full pattern table is 8192 bytes; any missing data is filled with blank
+    /// tiles, and anything beyond that is ignored.
+    pub fn from_bytes(bytes: &[u8]) -> PatternTable {
+        let mut tiles = bytes.chunks(16).take(512).map(|chunk| {
+            let mut data = [0u8; 16];
+            data[..chunk.len()].copy_from_slice(chunk);
+            Tile {name: None, data}
+        }).collect::<Vec<Tile>>();
+        tiles.resize(512, Tile {name: None, data: [0u8; 16]});
+        let right = tiles.split_off(256);
+
+        PatternTable {
+            left: tiles,
+            right,
+            sheets: Vec::new(),
+        }
+    }
+
+    /// Validate a SheetPatternTable without building anything, collecting every problem found
+    /// rather than stopping at the first.  Every sheet is fully loaded, so all png files are read
+    /// and checked.  An empty list means the table is valid.
+    pub fn check(sheet_table: &SheetPatternTable) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        let mut names: Vec<&str> = Vec::new();
+        for sheet in sheet_table.sheets() {
+            if names.contains(&sheet.name()) {
+                problems.push(format!("sheet name {} is used more than once", sheet.name()));
+            } else {
+                names.push(sheet.name());
+            }
+        }
+
+        // Sizes of the sheets that loaded successfully
+        let mut sizes = |sheets: &[serialize::Sheet]| -> Vec<Option<usize>> {
+            sheets.iter().map(|sheet| match sheet.pull() {
+                Ok((tiles, _)) => Some(tiles.len()),
+                Err(err) => {
+                    problems.push(format!("sheet {}: {}", sheet.name(), err));
+                    None
+                },
+            }).collect()
+        };
+        let left: usize = sizes(&sheet_table.left).into_iter().flatten().sum();
+        let right: usize = sizes(&sheet_table.right).into_iter().flatten().sum();
+        let shared = sizes(&sheet_table.shared);
+
+        if left > 256 {
+            problems.push(format!(
+                "left table contained too many tiles. Can not exceed 256, but has {}",
+                left));
+        }
+        if right > 256 {
+            problems.push(format!(
+                "right table contained too many tiles. Can not exceed 256, but has {}",
+                right));
+        }
+        if left <= 256 && right <= 256 {
+            let shared: Vec<usize> = shared.into_iter().flatten().collect();
+            if pack_shared(left, right, &shared).is_none() {
+                problems.push(format!(
+                    "shared sheets could not be fit into the pattern table. Left has {} tiles, right has {}, and shared sheets need {} more, out of 512",
+                    left,
+                    right,
+                    shared.iter().sum::<usize>()));
+            }
+        }
+
+        problems
+    }
+
     pub fn write<T: io::Write>(&self, writer: &mut T) -> Result<(), io::Error>{
-        for ref tile in &self.left {
-            writer.write(&tile.data)?;
+        for tile in &self.left {
+            writer.write_all(&tile.data)?;
         }
-        for ref tile in &self.right {
-            writer.write(&tile.data)?;
+        for tile in &self.right {
+            writer.write_all(&tile.data)?;
         }
         Ok(())
     }
@@ -1,7 +1,7 @@
 //! This module assists in serialization of the sprite description format, and serialization of
 //! sprites into various formats.
 
-use super::{Tile, Error};
+use super::{MetatileBlock, Tile, Error};
 
 use std::iter;
 
@@ -28,7 +28,7 @@ impl Fill {
                 format!("Value must be between 0 and 3, but was {}", self.value)
             ));
         }
-        let data: Vec<u8> = iter::repeat(self.value).take(64).collect();
+        let data: Vec<u8> = iter::repeat_n(self.value, 64).collect();
         let mut output = Vec::new();
         for num in 0..self.count {
             let tile = Tile::from_bytes(
@@ -179,6 +179,13 @@ impl Slice {
 
         for (slice_number, slice) in self.slices.iter().enumerate() {
             for (tile_number, slice_tile) in slice.iter().enumerate() {
+                if *slice_tile >= tiles.len() {
+                    return Err(Error::DimensionsError(format!(
+                        "Slice {} refers to tile {}, but the sheet only has {} tiles",
+                        slice_number,
+                        slice_tile,
+                        tiles.len())));
+                }
                 let mut tile = tiles[*slice_tile].clone();
                 tile.name = Some(format!("{name}_{slicenumber}_{tilenumber}",
                      name = self.name,
@@ -192,6 +199,115 @@ impl Slice {
     }
 }
 
+/// The default metatile symbols, in order
+const METATILE_SYMBOLS: &str = "0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
+
+/// A sheet of 16x16 blocks, for building stage metatiles.  Each block is pulled in as its four
+/// tiles, in the order top-left, top-right, bottom-left, bottom-right, matching the `tiles` of a
+/// stage metatile.
+///
+/// The image may use palette indices up to 15, like a background.  Each pixel's tile color is
+/// `index % 4`, and the palette of each block is guessed as the most common `index / 4` of its
+/// pixels that are not color 0.
+///
+/// Generates defines named $name_$blocknumber_$corner, where corner is TL, TR, BL, or BR.  Blocks
+/// are numbered in row-major order.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Metatile {
+    /// The png filename to pull in
+    pub file: String,
+
+    /// The name, used for generation of the C and ASM definition headers
+    pub name: String,
+
+    /// The width of the whole image, in 16x16 blocks
+    pub width: usize,
+
+    /// The height of the whole image, in 16x16 blocks
+    pub height: usize,
+
+    /// The stage symbol of each block, in order.  Defaults to the digits, then the lowercase and
+    /// uppercase letters.
+    #[serde(default)]
+    pub symbols: Option<String>,
+}
+
+impl Metatile {
+    /// Load the tiles of every block, in order, and the guessed palette of each block.
+    fn load(&self) -> Result<(Vec<Tile>, Vec<u8>), Error> {
+        let bitmap = match ::lodepng::decode_file(&self.file, ::lodepng::ffi::ColorType::PALETTE, 8) {
+            Ok(::lodepng::Image::RawData(bitmap)) => bitmap,
+            Ok(_) => return Err(Error::FormatError(String::from("Image format was incorrect"))),
+            Err(err) => return Err(Error::PNGError(err)),
+        };
+        if bitmap.width < self.width * 16 {
+            return Err(Error::DimensionsError(
+                    format!("Image too thin, need {}, got {}.", self.width * 16, bitmap.width)
+                    ));
+        } else if bitmap.height < self.height * 16 {
+            return Err(Error::DimensionsError(
+                    format!("Image too short, need {}, got {}.", self.height * 16, bitmap.height)
+                    ));
+        }
+        if let Some(item) = bitmap.buffer.iter().find(|&&item| item > 15) {
+            return Err(Error::PaletteError(
+                    format!("Image has a byte out of bounds; needs to be under 16, got {}.", item)
+                    ));
+        }
+
+        let mut tiles = Vec::new();
+        let mut palettes = Vec::new();
+        for block_y in 0..self.height {
+            for block_x in 0..self.width {
+                let mut counts = [0usize; 4];
+                // Top-left, top-right, bottom-left, bottom-right
+                for &(corner_x, corner_y) in &[(0, 0), (1, 0), (0, 1), (1, 1)] {
+                    let x = block_x * 16 + corner_x * 8;
+                    let y = block_y * 16 + corner_y * 8;
+                    let bytes: Vec<u8> = (0..8).flat_map(|line| {
+                        let offset = (y + line) * bitmap.width + x;
+                        &bitmap.buffer[offset..(offset + 8)]
+                    }).cloned().collect();
+                    for &index in bytes.iter().filter(|&&index| index & 0x03 != 0) {
+                        counts[(index >> 2) as usize] += 1;
+                    }
+                    let bytes: Vec<u8> = bytes.iter().map(|index| index & 0x03).collect();
+                    tiles.push(Tile::from_bytes(&bytes, None)?);
+                }
+                // Ties go to the lowest palette, which also makes a blank block palette 0
+                let palette = (0..4).rev().max_by_key(|&palette| counts[palette]).unwrap_or(0);
+                palettes.push(palette as u8);
+            }
+        }
+        Ok((tiles, palettes))
+    }
+
+    /// Pulls the named tiles out of this metatile sheet, along with the symbol and guessed
+    /// palette of every block
+    pub fn pull(&self) -> Result<(Vec<Tile>, Vec<MetatileBlock>), Error> {
+        let (mut tiles, palettes) = self.load()?;
+        for (number, tile) in tiles.iter_mut().enumerate() {
+            tile.name = Some(format!("{name}_{block}_{corner}",
+                name = self.name,
+                block = number / 4,
+                corner = ["TL", "TR", "BL", "BR"][number % 4],
+                ));
+        }
+
+        let symbols: Vec<char> = self.symbols.as_deref().unwrap_or(METATILE_SYMBOLS).chars().collect();
+        if symbols.len() < palettes.len() {
+            return Err(Error::FormatError(format!(
+                "Metatile sheet {} has {} blocks, but only {} symbols",
+                self.name, palettes.len(), symbols.len())));
+        }
+        let blocks = palettes.into_iter().zip(symbols).map(|(palette, symbol)| MetatileBlock {
+            symbol,
+            palette,
+        }).collect();
+        Ok((tiles, blocks))
+    }
+}
+
 /// An enum used for differentiating sheets by type
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "type")]
@@ -200,14 +316,66 @@ pub enum Sheet {
     Slice(Slice),
     Simple(Simple),
     Fill(Fill),
+    Metatile(Metatile),
+}
+
+impl Sheet {
+    /// Pulls the named tiles out of whichever sheet type this is, along with its metatile
+    /// blocks, which are empty for every type but `Metatile`
+    pub fn pull(&self) -> Result<(Vec<Tile>, Vec<MetatileBlock>), Error> {
+        match self {
+            Sheet::Animation(sprite) => Ok((sprite.pull_tiles()?, Vec::new())),
+            Sheet::Slice(sprite) => Ok((sprite.pull_tiles()?, Vec::new())),
+            Sheet::Simple(sprite) => Ok((sprite.pull_tiles()?, Vec::new())),
+            Sheet::Fill(sprite) => Ok((sprite.pull_tiles()?, Vec::new())),
+            Sheet::Metatile(sprite) => sprite.pull(),
+        }
+    }
+
+    /// The name of the sheet, as used in the generated headers
+    pub fn name(&self) -> &str {
+        match self {
+            Sheet::Animation(sprite) => &sprite.name,
+            Sheet::Slice(sprite) => &sprite.name,
+            Sheet::Simple(sprite) => &sprite.name,
+            Sheet::Fill(sprite) => &sprite.name,
+            Sheet::Metatile(sprite) => &sprite.name,
+        }
+    }
+
+    /// The png file the sheet pulls its tiles from, if it has one
+    pub fn file(&self) -> Option<&str> {
+        match self {
+            Sheet::Animation(sprite) => Some(&sprite.file),
+            Sheet::Slice(sprite) => Some(&sprite.file),
+            Sheet::Simple(sprite) => Some(&sprite.file),
+            Sheet::Fill(_) => None,
+            Sheet::Metatile(sprite) => Some(&sprite.file),
+        }
+    }
 }
 
 /// A sheet pattern table, for organizing sprite sheets by order into their appropriate table
 /// section.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct SheetPatternTable {
+    #[serde(default)]
     pub left: Vec<Sheet>,
+
+    #[serde(default)]
     pub right: Vec<Sheet>,
+
+    /// Sheets that may live in either page.  These are assigned to a page automatically, after
+    /// all the fixed sheets of that page.
+    #[serde(default, alias = "auto")]
+    pub shared: Vec<Sheet>,
+}
+
+impl SheetPatternTable {
+    /// Iterate over all sheets, fixed and shared
+    pub fn sheets(&self) -> impl Iterator<Item = &Sheet> {
+        self.left.iter().chain(self.right.iter()).chain(self.shared.iter())
+    }
 }
 
 pub trait LoadTiles {
@@ -218,10 +386,10 @@ pub trait LoadTiles {
     fn sheet_height(&self) -> usize;
 
     /// path to the image
-    fn image_path<'a>(&'a self) -> &'a str;
+    fn image_path(&self) -> &str;
 
     /// name of the image
-    fn name<'a>(&'a self) -> &'a str;
+    fn name(&self) -> &str;
 
     /// Simply loads the image and pulls in the PNG data as tiles.
     ///
@@ -269,7 +437,7 @@ pub trait LoadTiles {
                     }
                     Ok(tiles)
                 } else {
-                    return Err(Error::FormatError(String::from("Image format was incorrect")));
+                    Err(Error::FormatError(String::from("Image format was incorrect")))
                 }
             },
             Err(err) => {
@@ -286,10 +454,10 @@ impl LoadTiles for Simple {
     fn sheet_height(&self) -> usize {
         self.height
     }
-    fn image_path<'a>(&'a self) -> &'a str {
+    fn image_path(&self) -> &str {
         &self.file
     }
-    fn name<'a>(&'a self) -> &'a str {
+    fn name(&self) -> &str {
         &self.name
     }
 }
@@ -301,10 +469,10 @@ impl LoadTiles for Animation {
     fn sheet_height(&self) -> usize {
         self.frame_height
     }
-    fn image_path<'a>(&'a self) -> &'a str {
+    fn image_path(&self) -> &str {
         &self.file
     }
-    fn name<'a>(&'a self) -> &'a str {
+    fn name(&self) -> &str {
         &self.name
     }
 }
@@ -316,10 +484,10 @@ impl LoadTiles for Slice {
     fn sheet_height(&self) -> usize {
         self.height
     }
-    fn image_path<'a>(&'a self) -> &'a str {
+    fn image_path(&self) -> &str {
         &self.file
     }
-    fn name<'a>(&'a self) -> &'a str {
+    fn name(&self) -> &str {
         &self.name
     }
 }
@@ -0,0 +1,217 @@
+//! Tile budget and usage statistics for a built pattern table.
+
+use super::{Page, PatternTable, Tile};
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fmt;
+
+/// The number of largest sheets listed in the report.
+const LARGEST_COUNT: usize = 5;
+
+/// Usage of a single pattern table page.
+#[derive(Serialize, Debug)]
+pub struct PageStats {
+    pub page: Page,
+
+    /// Tiles taken up by sheets
+    pub used: usize,
+
+    /// Tiles still available
+    pub free: usize,
+}
+
+/// Usage of a single sheet.
+#[derive(Serialize, Debug)]
+pub struct SheetStats {
+    pub name: String,
+    pub page: Page,
+
+    /// Index of the first tile of the sheet in its page
+    pub start: usize,
+
+    /// Number of tiles the sheet takes up
+    pub tiles: usize,
+}
+
+/// A set of tiles within a single page that have identical data, and could therefore be merged
+/// into one.
+#[derive(Serialize, Debug)]
+pub struct Duplicate {
+    pub page: Page,
+
+    /// Names of all the identical tiles, in page order
+    pub tiles: Vec<String>,
+}
+
+/// A tile pulled from a png which has no set pixels at all.
+#[derive(Serialize, Debug)]
+pub struct BlankTile {
+    pub page: Page,
+    pub name: String,
+
+    /// The png file the tile was pulled from
+    pub file: String,
+}
+
+/// Full usage report of a pattern table.
+#[derive(Serialize, Debug)]
+pub struct Stats {
+    pub pages: Vec<PageStats>,
+    pub sheets: Vec<SheetStats>,
+    pub duplicates: Vec<Duplicate>,
+    pub blank: Vec<BlankTile>,
+
+    /// Names of the largest sheets, largest first
+    pub largest: Vec<String>,
+}
+
+fn page_tiles(pattern_table: &PatternTable, page: Page) -> &[Tile] {
+    match page {
+        Page::Left => &pattern_table.left,
+        Page::Right => &pattern_table.right,
+    }
+}
+
+impl Stats {
+    /// Gather statistics from a pattern table.
+    ///
+    /// ```
+    /// use nestools::sprites::{Page, PatternTable, SheetPlacement, Tile};
+    /// use nestools::sprites::stats::Stats;
+    ///
+    /// let tile = |name: &str, byte: u8| Tile {name: Some(String::from(name)), data: [byte; 16]};
+    /// let sheet = |name: &str, start: usize, count: usize| SheetPlacement {
+    ///     name: String::from(name),
+    ///     file: Some(format!("{}.png", name)),
+    ///     page: Page::Left,
+    ///     start,
+    ///     count,
+    ///     metatiles: Vec::new(),
+    /// };
+    ///
+    /// // The hero's second tile is the same as its first, and the coin's second tile is blank
+    /// let pattern_table = PatternTable {
+    ///     left: vec![tile("hero_0", 1), tile("hero_1", 1), tile("hero_2", 2), tile("coin_0", 3), tile("coin_1", 0)],
+    ///     right: Vec::new(),
+    ///     sheets: vec![sheet("coin", 3, 2), sheet("hero", 0, 3)],
+    /// };
+    ///
+    /// let stats = Stats::from_pattern_table(&pattern_table);
+    /// assert_eq!((stats.pages[0].used, stats.pages[0].free), (5, 251));
+    /// assert_eq!((stats.pages[1].used, stats.pages[1].free), (0, 256));
+    /// assert_eq!(stats.duplicates[0].tiles, vec!["hero_0", "hero_1"]);
+    /// assert_eq!(stats.mergeable(), 1);
+    /// assert_eq!((stats.blank[0].name.as_str(), stats.blank[0].file.as_str()), ("coin_1", "coin.png"));
+    /// assert_eq!(stats.largest, vec!["hero", "coin"]);
+    /// ```
+    pub fn from_pattern_table(pattern_table: &PatternTable) -> Stats {
+        let mut pages = Vec::new();
+        let mut duplicates = Vec::new();
+        for &page in &[Page::Left, Page::Right] {
+            let used: usize = pattern_table.sheets.iter()
+                .filter(|sheet| sheet.page == page)
+                .map(|sheet| sheet.count)
+                .sum();
+            pages.push(PageStats {
+                page,
+                used,
+                free: 256 - used,
+            });
+
+            // Group named tiles by data, keeping the order of first appearance
+            let mut groups: Vec<Vec<String>> = Vec::new();
+            let mut group_indices: HashMap<[u8; 16], usize> = HashMap::new();
+            for tile in page_tiles(pattern_table, page) {
+                if let Some(ref name) = tile.name {
+                    let index = *group_indices.entry(tile.data).or_insert_with(|| {
+                        groups.push(Vec::new());
+                        groups.len() - 1
+                    });
+                    groups[index].push(name.clone());
+                }
+            }
+            duplicates.extend(groups.into_iter()
+                .filter(|group| group.len() > 1)
+                .map(|tiles| Duplicate { page, tiles }));
+        }
+
+        let mut blank = Vec::new();
+        for sheet in &pattern_table.sheets {
+            if let Some(ref file) = sheet.file {
+                let tiles = &page_tiles(pattern_table, sheet.page)[sheet.start..(sheet.start + sheet.count)];
+                for tile in tiles.iter().filter(|tile| tile.data == [0u8; 16]) {
+                    blank.push(BlankTile {
+                        page: sheet.page,
+                        name: tile.name.clone().unwrap_or_default(),
+                        file: file.clone(),
+                    });
+                }
+            }
+        }
+
+        let sheets: Vec<SheetStats> = pattern_table.sheets.iter()
+            .map(|sheet| SheetStats {
+                name: sheet.name.clone(),
+                page: sheet.page,
+                start: sheet.start,
+                tiles: sheet.count,
+            })
+            .collect();
+
+        let mut largest: Vec<&SheetStats> = sheets.iter().collect();
+        // Stable sort, so equal sheets stay in placement order
+        largest.sort_by_key(|sheet| Reverse(sheet.tiles));
+        let largest = largest.into_iter()
+            .take(LARGEST_COUNT)
+            .map(|sheet| sheet.name.clone())
+            .collect();
+
+        Stats {
+            pages,
+            sheets,
+            duplicates,
+            blank,
+            largest,
+        }
+    }
+
+    /// The number of tiles that could be saved by merging all duplicates.
+    pub fn mergeable(&self) -> usize {
+        self.duplicates.iter().map(|duplicate| duplicate.tiles.len() - 1).sum()
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{:<8} {:>6} {:>6}", "PAGE", "USED", "FREE")?;
+        for page in &self.pages {
+            writeln!(f, "{:<8} {:>6} {:>6}", page.page, page.used, page.free)?;
+        }
+
+        writeln!(f)?;
+        writeln!(f, "{:<32} {:<8} {:>6} {:>6}", "SHEET", "PAGE", "START", "TILES")?;
+        for sheet in &self.sheets {
+            writeln!(f, "{:<32} {:<8} {:>6} {:>6}", sheet.name, sheet.page, sheet.start, sheet.tiles)?;
+        }
+
+        writeln!(f)?;
+        writeln!(f, "Largest sheets:")?;
+        for name in &self.largest {
+            writeln!(f, "    {}", name)?;
+        }
+
+        writeln!(f)?;
+        writeln!(f, "Duplicate tiles ({} could be merged):", self.mergeable())?;
+        for duplicate in &self.duplicates {
+            writeln!(f, "    {}: {}", duplicate.page, duplicate.tiles.join(", "))?;
+        }
+
+        writeln!(f)?;
+        writeln!(f, "Blank tiles pulled from images ({}):", self.blank.len())?;
+        for tile in &self.blank {
+            writeln!(f, "    {}_{} ({})", tile.page, tile.name, tile.file)?;
+        }
+        Ok(())
+    }
+}
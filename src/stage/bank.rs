@@ -0,0 +1,159 @@
+//! Packing many compiled stages into a single bank, or across several fixed-size PRG banks, with
+//! a table of offsets at the start of each bank.
+//!
+//! Each bank starts with a 16-bit little-endian offset for each of its stages, from the start of
+//! the bank, followed by the stages themselves in order.  Stages are placed in order, and a stage
+//! that does not fit in what is left of a bank starts the next one, so no stage is ever split
+//! across two banks.  With a bank size, every bank is padded to it with `0xFF`, so that bank `n`
+//! starts at `n` times the bank size; without one, everything goes in a single bank, unpadded.
+
+use super::Error;
+
+/// The byte banks are padded with
+const PADDING: u8 = 0xFF;
+
+/// The size of each entry of a bank's offset table
+const OFFSET_SIZE: usize = 2;
+
+/// A stage of a bank description.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct BankStage {
+    /// The name used for the stage's header constants
+    pub name: String,
+
+    /// The stage YAML file
+    pub file: String,
+}
+
+/// A bank of stages, as given to `stagec --bank`.
+///
+/// ```yaml
+/// bank_size: 8192
+/// stages:
+///   - {name: level1, file: level1.yaml}
+///   - {name: level2, file: level2.yaml}
+/// ```
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct BankDescription {
+    /// The size of each PRG bank.  If not given, all stages go in a single bank.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bank_size: Option<usize>,
+
+    /// The stages, in order
+    pub stages: Vec<BankStage>,
+}
+
+impl BankDescription {
+    /// Validate the description, returning every problem found.
+    pub fn check(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.stages.is_empty() {
+            problems.push(String::from("bank has no stages"));
+        }
+        if let Some(bank_size) = self.bank_size {
+            if bank_size <= OFFSET_SIZE || bank_size > 0x10000 {
+                problems.push(format!(
+                    "bank size is {}, but must be more than {} and at most 65536", bank_size, OFFSET_SIZE));
+            }
+        }
+        for (index, stage) in self.stages.iter().enumerate() {
+            if !super::is_identifier(&stage.name) {
+                problems.push(format!(
+                    "stage name {:?} can only have letters, digits, and underscores", stage.name));
+            }
+            if self.stages[..index].iter().any(|other| other.name == stage.name) {
+                problems.push(format!("stage {} is listed more than once", stage.name));
+            }
+        }
+        problems
+    }
+}
+
+/// Where a stage was placed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Placement {
+    /// The bank the stage is in, counting from 0
+    pub bank: usize,
+
+    /// The index of the stage in its bank's offset table
+    pub index: usize,
+
+    /// The offset of the stage from the start of its bank
+    pub offset: usize,
+
+    /// The size of the stage, in bytes
+    pub size: usize,
+}
+
+/// Pack compiled stages, each given with its name, in order, returning every bank one after
+/// another along with where each stage was placed.  Fails if a stage does not fit in a bank, or
+/// without a bank size, if the stages do not fit in the 16-bit offsets.
+///
+/// ```
+/// use nestools::stage::bank::{pack, Placement};
+///
+/// let stages = vec![
+///     (String::from("first"), vec![1; 4]),
+///     (String::from("second"), vec![2; 3]),
+///     (String::from("third"), vec![3; 2]),
+/// ];
+/// let (binary, placements) = pack(&stages, None).unwrap();
+/// assert_eq!(&binary[..6], &[6, 0, 10, 0, 13, 0]);
+/// assert_eq!(binary.len(), 15);
+///
+/// // Only the first 2 stages fit in the first bank, with their offset table
+/// let (binary, placements) = pack(&stages, Some(12)).unwrap();
+/// assert_eq!(binary.len(), 24);
+/// assert_eq!(&binary[..4], &[4, 0, 8, 0]);
+/// assert_eq!(placements[2], Placement {bank: 1, index: 0, offset: 2, size: 2});
+/// assert_eq!(&binary[12..16], &[2, 0, 3, 3]);
+///
+/// assert!(pack(&stages, Some(5)).is_err());
+/// ```
+pub fn pack(stages: &[(String, Vec<u8>)], bank_size: Option<usize>) -> Result<(Vec<u8>, Vec<Placement>), Error> {
+    let limit = bank_size.unwrap_or(0x10000);
+
+    // Group the stages into banks first, as the offset table's size depends on the bank's stages
+    let mut banks: Vec<Vec<usize>> = vec![Vec::new()];
+    let mut used = 0;
+    for (stage, (name, binary)) in stages.iter().enumerate() {
+        let size = OFFSET_SIZE + binary.len();
+        if size > limit {
+            return Err(Error::BankError(format!(
+                "stage {} is {} bytes, which with its offset does not fit in a bank of {} bytes",
+                name, binary.len(), limit)));
+        }
+        if used + size > limit {
+            if bank_size.is_none() {
+                return Err(Error::BankError(format!(
+                    "stages are more than {} bytes, which does not fit in 16-bit offsets without a bank size",
+                    limit)));
+            }
+            banks.push(Vec::new());
+            used = 0;
+        }
+        banks.last_mut().expect("there is always a bank").push(stage);
+        used += size;
+    }
+
+    let mut output = Vec::new();
+    let mut placements = Vec::new();
+    for (bank, members) in banks.iter().enumerate() {
+        let start = output.len();
+        let mut offset = members.len() * OFFSET_SIZE;
+        for (index, &stage) in members.iter().enumerate() {
+            output.extend_from_slice(&(offset as u16).to_le_bytes());
+            placements.push(Placement { bank, index, offset, size: stages[stage].1.len() });
+            offset += stages[stage].1.len();
+        }
+        for &stage in members {
+            output.extend_from_slice(&stages[stage].1);
+        }
+        if let Some(bank_size) = bank_size {
+            output.resize(start + bank_size, PADDING);
+        }
+    }
+    Ok((output, placements))
+}
@@ -0,0 +1,300 @@
+//! Compression codecs for the stage body.
+//!
+//! The stage body is the metatile index of every position of the stage, in the order it is stored
+//! (column by column for horizontal stages, row by row for vertical ones).  Each storage line is
+//! a column of a horizontal stage, or a row of a vertical one.  Every codec has an id, which is
+//! stored in the low 2 bits of the stage attribute byte, and a decoder, which is the reference
+//! for the 6502 side.
+
+use std::convert::TryFrom;
+
+use super::Error;
+
+/// A stage body compression codec.
+pub trait Codec {
+    /// The id stored in the stage attribute byte
+    fn id(&self) -> u8;
+
+    /// The name used for the `compression` option
+    fn name(&self) -> &'static str;
+
+    /// Compress the metatile indices.  `line_length` is the length of a single storage line.
+    /// Fails if the indices can not be represented in this codec's format.
+    fn encode(&self, indices: &[u8], line_length: usize) -> Result<Vec<u8>, Error>;
+
+    /// Decompress a body back into metatile indices.
+    fn decode(&self, body: &[u8]) -> Result<Vec<u8>, Error>;
+}
+
+/// Split the indices into runs of the same index, each at most `limit` long.
+fn runs(indices: &[u8], limit: usize) -> Vec<(u8, usize)> {
+    let mut output: Vec<(u8, usize)> = Vec::new();
+    for &index in indices {
+        match output.last_mut() {
+            Some((current, count)) if *current == index && *count < limit => *count += 1,
+            _ => output.push((index, 1)),
+        }
+    }
+    output
+}
+
+/// The original format.  Each byte is a run, with the run length minus 1 in the high nibble and
+/// the metatile index in the low nibble, so runs are at most 16 long.
+pub struct NibbleRle;
+
+impl Codec for NibbleRle {
+    fn id(&self) -> u8 {
+        0
+    }
+
+    fn name(&self) -> &'static str {
+        "nibble_rle"
+    }
+
+    fn encode(&self, indices: &[u8], _line_length: usize) -> Result<Vec<u8>, Error> {
+        Ok(runs(indices, 16).into_iter()
+            .map(|(index, count)| ((count as u8) - 1) << 4 | 0x0F & index)
+            .collect())
+    }
+
+    fn decode(&self, body: &[u8]) -> Result<Vec<u8>, Error> {
+        Ok(body.iter()
+            .flat_map(|&byte| std::iter::repeat_n(byte & 0x0F, (byte >> 4) as usize + 1))
+            .collect())
+    }
+}
+
+/// Byte-wide runs.  Each run is two bytes: the run length minus 1, then the metatile index, so
+/// runs may be up to 256 long.
+pub struct ByteRle;
+
+impl Codec for ByteRle {
+    fn id(&self) -> u8 {
+        1
+    }
+
+    fn name(&self) -> &'static str {
+        "byte_rle"
+    }
+
+    fn encode(&self, indices: &[u8], _line_length: usize) -> Result<Vec<u8>, Error> {
+        Ok(runs(indices, 256).into_iter()
+            .flat_map(|(index, count)| vec![(count - 1) as u8, index])
+            .collect())
+    }
+
+    fn decode(&self, body: &[u8]) -> Result<Vec<u8>, Error> {
+        if !body.len().is_multiple_of(2) {
+            return Err(Error::FormatError(format!(
+                "byte RLE body is {} bytes, but must be made of 2-byte runs", body.len())));
+        }
+        Ok(body.chunks(2)
+            .flat_map(|run| std::iter::repeat_n(run[1], run[0] as usize + 1))
+            .collect())
+    }
+}
+
+/// LZ77-style back-references.  A byte under 0x80 is a single literal metatile index.  A byte
+/// with the high bit set is a back-reference, copying `(byte & 0x7F) + 3` indices starting the
+/// number of positions back given by the following byte.  Copies may overlap what they produce,
+/// so a back-reference of distance 1 is a run.
+pub struct Lz77;
+
+/// The shortest back-reference worth encoding, as it takes 2 bytes
+const LZ77_MIN_LENGTH: usize = 3;
+
+/// The longest back-reference that fits in the 7-bit length
+const LZ77_MAX_LENGTH: usize = 0x7F + LZ77_MIN_LENGTH;
+
+impl Codec for Lz77 {
+    fn id(&self) -> u8 {
+        2
+    }
+
+    fn name(&self) -> &'static str {
+        "lz77"
+    }
+
+    fn encode(&self, indices: &[u8], _line_length: usize) -> Result<Vec<u8>, Error> {
+        let mut output = Vec::new();
+        let mut position = 0;
+        while position < indices.len() {
+            // Find the longest match within the last 255 positions, preferring the closest
+            let mut best = (0, 0);
+            for distance in 1..=position.min(255) {
+                let length = (0..LZ77_MAX_LENGTH.min(indices.len() - position))
+                    .take_while(|&offset| indices[position + offset] == indices[position + offset - distance])
+                    .count();
+                if length > best.0 {
+                    best = (length, distance);
+                }
+            }
+
+            let (length, distance) = best;
+            if length >= LZ77_MIN_LENGTH {
+                output.push(0x80 | (length - LZ77_MIN_LENGTH) as u8);
+                output.push(distance as u8);
+                position += length;
+            } else {
+                output.push(indices[position]);
+                position += 1;
+            }
+        }
+        Ok(output)
+    }
+
+    fn decode(&self, body: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut output: Vec<u8> = Vec::new();
+        let mut bytes = body.iter();
+        while let Some(&byte) = bytes.next() {
+            if byte & 0x80 == 0 {
+                output.push(byte);
+                continue;
+            }
+            let length = (byte & 0x7F) as usize + LZ77_MIN_LENGTH;
+            let distance = match bytes.next() {
+                Some(&distance) => distance as usize,
+                None => return Err(Error::FormatError(String::from(
+                    "LZ77 body ends in the middle of a back-reference"))),
+            };
+            if distance == 0 || distance > output.len() {
+                return Err(Error::FormatError(format!(
+                    "LZ77 back-reference of distance {} at position {} points outside the stage",
+                    distance, output.len())));
+            }
+            for _ in 0..length {
+                output.push(output[output.len() - distance]);
+            }
+        }
+        Ok(output)
+    }
+}
+
+/// Column/row dictionary deduplication.  Every unique storage line is stored once, and the stage
+/// is a list of dictionary entries.  The body is the line length, the number of entries, every
+/// entry with two metatile indices packed in each byte (high nibble first, padded to a whole
+/// byte), and then the entry number of every line.  Both the line length and the number of
+/// entries must fit in a byte.
+///
+/// ```
+/// use nestools::stage::codec::{Codec, Dictionary};
+///
+/// // Every line of 2 is different, making 256 entries, one more than the count can hold
+/// let indices: Vec<u8> = (0..=255u8).flat_map(|pair| vec![pair >> 4, pair & 0x0F]).collect();
+/// assert!(Dictionary.encode(&indices, 2).is_err());
+/// assert_eq!(Dictionary.encode(&indices[2..], 2).unwrap()[..2], [2, 255]);
+///
+/// assert!(Dictionary.encode(&[0; 256], 256).is_err());
+/// ```
+pub struct Dictionary;
+
+impl Codec for Dictionary {
+    fn id(&self) -> u8 {
+        3
+    }
+
+    fn name(&self) -> &'static str {
+        "dictionary"
+    }
+
+    fn encode(&self, indices: &[u8], line_length: usize) -> Result<Vec<u8>, Error> {
+        if line_length == 0 {
+            return Ok(vec![0, 0]);
+        }
+        let stored_length = match u8::try_from(line_length) {
+            Ok(length) => length,
+            Err(_) => return Err(Error::FormatError(format!(
+                "dictionary lines are {} metatiles long, but can not be longer than 255", line_length))),
+        };
+        let mut entries: Vec<&[u8]> = Vec::new();
+        let mut lines = Vec::new();
+        for line in indices.chunks(line_length) {
+            let entry = match entries.iter().position(|&entry| entry == line) {
+                Some(entry) => entry,
+                None => {
+                    entries.push(line);
+                    entries.len() - 1
+                },
+            };
+            lines.push(entry);
+        }
+
+        // The entry count is a single byte, so every entry number fits in one as well
+        let count = match u8::try_from(entries.len()) {
+            Ok(count) => count,
+            Err(_) => return Err(Error::FormatError(format!(
+                "dictionary has {} unique lines, but can not have more than 255", entries.len()))),
+        };
+        let mut output = vec![stored_length, count];
+        for entry in entries {
+            output.extend(entry.chunks(2).map(|pair| pair[0] << 4 | pair.get(1).unwrap_or(&0) & 0x0F));
+        }
+        output.extend(lines.into_iter().map(|entry| entry as u8));
+        Ok(output)
+    }
+
+    fn decode(&self, body: &[u8]) -> Result<Vec<u8>, Error> {
+        if body.len() < 2 {
+            return Err(Error::FormatError(String::from("dictionary body is missing its header")));
+        }
+        let line_length = body[0] as usize;
+        let count = body[1] as usize;
+        let entry_size = line_length.div_ceil(2);
+        let lines_start = 2 + count * entry_size;
+        if body.len() < lines_start {
+            return Err(Error::FormatError(format!(
+                "dictionary body ends in the middle of its {} entries", count)));
+        }
+
+        let entries: Vec<Vec<u8>> = body[2..lines_start].chunks(entry_size.max(1))
+            .take(count)
+            .map(|packed| {
+                packed.iter()
+                    .flat_map(|&byte| vec![byte >> 4, byte & 0x0F])
+                    .take(line_length)
+                    .collect()
+            })
+            .collect();
+
+        let mut output = Vec::new();
+        for &entry in &body[lines_start..] {
+            match entries.get(entry as usize) {
+                Some(entry) => output.extend(entry),
+                None => return Err(Error::FormatError(format!(
+                    "dictionary body uses entry {}, but there are only {}", entry, count))),
+            }
+        }
+        Ok(output)
+    }
+}
+
+/// Every codec, in order of id.
+pub const CODECS: [&dyn Codec; 4] = [&NibbleRle, &ByteRle, &Lz77, &Dictionary];
+
+/// Look up a codec by the id stored in the stage attribute byte.
+pub fn codec(id: u8) -> Option<&'static dyn Codec> {
+    CODECS.iter().find(|codec| codec.id() == id).cloned()
+}
+
+/// Compress with every codec that can represent the indices, returning the one with the smallest
+/// body.  Ties go to the lowest id.
+///
+/// ```
+/// use nestools::stage::codec::{best, CODECS};
+///
+/// let indices = [0, 0, 0, 0, 1, 2, 1, 2, 1, 2, 1, 2, 0, 0, 0, 0];
+/// for codec in CODECS.iter() {
+///     let body = codec.encode(&indices, 4).unwrap();
+///     assert_eq!(codec.decode(&body).unwrap(), indices);
+/// }
+///
+/// let (codec, body) = best(&indices, 4);
+/// assert_eq!(codec.name(), "lz77");
+/// assert_eq!(body, [0, 0x80, 1, 1, 2, 0x83, 2, 0x81, 12]);
+/// ```
+pub fn best(indices: &[u8], line_length: usize) -> (&'static dyn Codec, Vec<u8>) {
+    CODECS.iter()
+        .filter_map(|&codec| codec.encode(indices, line_length).ok().map(|body| (codec, body)))
+        .min_by_key(|(codec, body)| (body.len(), codec.id()))
+        .expect("the run-length codecs can always encode")
+}
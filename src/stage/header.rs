@@ -0,0 +1,129 @@
+//! The stage header, holding the stage's music track and other settings for the runtime, along
+//! with the music track list file that gives every track its index.
+//!
+//! A stage only has a header if it sets any of `music`, `scroll_lock`, or `time_limit`, in which
+//! case the top bit of its attribute byte is set and the header follows the attribute byte.  The
+//! header starts with its version, so that the runtime can tell which layout it is reading.
+//! Version 1 is 5 bytes:
+//!
+//! | Offset | Size | Field                                                      |
+//! |--------|------|------------------------------------------------------------|
+//! | 0      | 1    | The header version, 1                                      |
+//! | 1      | 1    | The music track index                                      |
+//! | 2      | 1    | Header flags: bit 0 is scroll lock                         |
+//! | 3      | 2    | The time limit in seconds, little-endian.  0 is no limit.  |
+
+use std::fs::File;
+
+use super::Error;
+
+/// The version of the header layout written
+pub const VERSION: u8 = 1;
+
+/// The length of the header, in bytes
+pub const LENGTH: usize = 5;
+
+/// The bit of the stage attribute byte that is set when the stage has a header
+pub const ATTRIBUTE_BIT: u8 = 0x80;
+
+/// The bit of the header flags that is set when the stage can not scroll back toward its start
+pub const SCROLL_LOCK: u8 = 0x01;
+
+/// The offset of each field of the header, with its size, in the order of the layout
+pub const FIELDS: [(&str, usize, usize); 4] = [
+    ("VERSION", 0, 1),
+    ("MUSIC", 1, 1),
+    ("FLAGS", 2, 1),
+    ("TIME_LIMIT", 3, 2),
+];
+
+/// A music track, either by index or by name.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum MusicRef {
+    /// The index of the track
+    Index(u8),
+
+    /// The name of the track in the stage's `tracks` file.  Resolved by
+    /// [`Stage::resolve_music`](../serialize/struct.Stage.html#method.resolve_music).
+    Name(String),
+}
+
+impl MusicRef {
+    /// The track index, if the track is given by index or has been resolved.
+    pub fn index(&self) -> Option<u8> {
+        match self {
+            MusicRef::Index(index) => Some(*index),
+            MusicRef::Name(_) => None,
+        }
+    }
+}
+
+/// The header of a stage, as it is stored.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Header {
+    pub music: u8,
+    pub scroll_lock: bool,
+    pub time_limit: u16,
+}
+
+impl Header {
+    /// The header as it is written, version first.
+    ///
+    /// ```
+    /// use nestools::stage::header::Header;
+    ///
+    /// let header = Header {music: 3, scroll_lock: true, time_limit: 300};
+    /// assert_eq!(header.to_bytes(), [1, 3, 1, 0x2C, 0x01]);
+    /// assert_eq!(Header::from_bytes(&header.to_bytes()).unwrap(), header);
+    /// ```
+    pub fn to_bytes(&self) -> [u8; LENGTH] {
+        let flags = if self.scroll_lock { SCROLL_LOCK } else { 0 };
+        let time_limit = self.time_limit.to_le_bytes();
+        [VERSION, self.music, flags, time_limit[0], time_limit[1]]
+    }
+
+    /// Read a header written by [`to_bytes`](#method.to_bytes).  Headers of any other version, or
+    /// with unknown flags set, are errors.
+    pub fn from_bytes(bytes: &[u8; LENGTH]) -> Result<Header, Error> {
+        if bytes[0] != VERSION {
+            return Err(Error::FormatError(format!(
+                "stage header is version {}, but only version {} is supported", bytes[0], VERSION)));
+        }
+        if bytes[2] & !SCROLL_LOCK != 0 {
+            return Err(Error::FormatError(format!(
+                "stage header flags {:#04X} have unknown bits set", bytes[2])));
+        }
+        Ok(Header {
+            music: bytes[1],
+            scroll_lock: bytes[2] & SCROLL_LOCK != 0,
+            time_limit: u16::from_le_bytes([bytes[3], bytes[4]]),
+        })
+    }
+}
+
+/// Load the music track list, a YAML list of up to 256 track names, in order of index.
+///
+/// ```yaml
+/// [overworld, underground, castle, boss]
+/// ```
+pub fn load_tracks(filename: &str) -> Result<Vec<String>, Error> {
+    let file = match File::open(filename) {
+        Ok(file) => file,
+        Err(err) => return Err(Error::MusicError(format!("{}: {}", filename, err))),
+    };
+    let tracks: Vec<String> = match serde_yaml::from_reader(file) {
+        Ok(tracks) => tracks,
+        Err(err) => return Err(Error::MusicError(format!("{}: {}", filename, err))),
+    };
+    if tracks.len() > 256 {
+        return Err(Error::MusicError(format!(
+            "{}: there are {} tracks, but there can not be more than 256", filename, tracks.len())));
+    }
+    for (index, track) in tracks.iter().enumerate() {
+        if tracks[..index].contains(track) {
+            return Err(Error::MusicError(format!("{}: track {} is listed more than once", filename, track)));
+        }
+    }
+    Ok(tracks)
+}
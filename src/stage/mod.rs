@@ -1,4 +1,151 @@
 //! Tools for working with stages.
 //!
 
+pub mod bank;
+pub mod codec;
+pub mod header;
+pub mod objects;
+pub mod palettes;
+pub mod preview;
 pub mod serialize;
+pub mod tiled;
+
+use std::error;
+use std::fmt;
+use std::io;
+
+/// Global stage error type.  Problems in the stage data give the 1-based line and column of the
+/// offending character.
+#[derive(Debug)]
+pub enum Error {
+    /// If a binary stage is not in the format `Stage::write_binary` produces
+    FormatError(String),
+
+    /// If a palette has a color that is not an NES color, has not been loaded, or a metatile uses
+    /// a palette that does not exist
+    PaletteError(String),
+
+    /// If a metatile has a tile given by name that has not been resolved
+    TileError(String),
+
+    /// If the stage's flags are invalid, or a metatile has a flag the stage does not
+    FlagError(String),
+
+    /// If an object is outside the stage or has a type that does not exist, or the object types
+    /// file is invalid
+    ObjectError(String),
+
+    /// If an object has a type given by name that has not been resolved
+    ObjectTypeError(String),
+
+    /// If there are more metatiles than can be indexed in the stage body
+    MetatileCountError(usize),
+
+    /// If more than one metatile uses the same symbol
+    DuplicateSymbolError(char),
+
+    /// If more than one metatile has the same name
+    DuplicateNameError(String),
+
+    /// If the stage data uses a symbol that is not a metatile
+    SymbolError {
+        line: usize,
+        column: usize,
+        symbol: char,
+    },
+
+    /// If a stage data line is not as long as the first line.  The column is where the line
+    /// should have ended, or where it ended early.
+    LineLengthError {
+        line: usize,
+        column: usize,
+        length: usize,
+        expected: usize,
+    },
+
+    /// If the music track list could not be loaded, or the stage's music is given by a name that
+    /// has not been resolved
+    MusicError(String),
+
+    /// If a Tiled map could not be loaded or imported
+    TiledError(String),
+
+    /// If a stage split into screens is not a whole number of screens, or a screen is too long
+    ScreenError(String),
+
+    /// If stages do not fit in their banks
+    BankError(String),
+
+    /// If the compressed stage body is longer than its length byte can hold
+    BodyLengthError(usize),
+
+    /// If the stage could not be written
+    IoError(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::FormatError(err) => write!(f, "{}", err),
+            Error::PaletteError(err) => write!(f, "{}", err),
+            Error::TileError(err) => write!(f, "{}", err),
+            Error::FlagError(err) => write!(f, "{}", err),
+            Error::ObjectError(err) => write!(f, "{}", err),
+            Error::ObjectTypeError(err) => write!(f, "{}", err),
+            Error::MusicError(err) => write!(f, "{}", err),
+            Error::TiledError(err) => write!(f, "{}", err),
+            Error::ScreenError(err) => write!(f, "{}", err),
+            Error::BankError(err) => write!(f, "{}", err),
+            Error::MetatileCountError(count) => write!(
+                f, "stage has {} metatiles, but can not have more than 16", count),
+            Error::DuplicateSymbolError(symbol) => write!(
+                f, "metatile symbol {:?} is used more than once", symbol),
+            Error::DuplicateNameError(name) => write!(
+                f, "metatile name {:?} is used more than once", name),
+            Error::SymbolError { line, column, symbol } => write!(
+                f, "data line {}, column {}: symbol {:?} is not a metatile", line, column, symbol),
+            Error::LineLengthError { line, column, length, expected } => write!(
+                f, "data line {}, column {}: line is {} metatiles long, but the first line is {}",
+                line, column, length, expected),
+            Error::BodyLengthError(length) => write!(
+                f, "compressed stage body is {} bytes, but can not exceed 255", length),
+            Error::IoError(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match self {
+            Error::FormatError(err) => err,
+            Error::PaletteError(err) => err,
+            Error::TileError(err) => err,
+            Error::FlagError(err) => err,
+            Error::ObjectError(err) => err,
+            Error::ObjectTypeError(err) => err,
+            Error::MusicError(err) => err,
+            Error::TiledError(err) => err,
+            Error::ScreenError(err) => err,
+            Error::BankError(err) => err,
+            Error::MetatileCountError(_) => "Too many metatiles",
+            Error::DuplicateSymbolError(_) => "Duplicate metatile symbol",
+            Error::DuplicateNameError(_) => "Duplicate metatile name",
+            Error::SymbolError { .. } => "Unknown symbol in stage data",
+            Error::LineLengthError { .. } => "Stage data lines are different lengths",
+            Error::BodyLengthError(_) => "Compressed stage body is too long",
+            Error::IoError(_) => "IO error writing stage",
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::IoError(error)
+    }
+}
+
+/// Whether a name can be used in a generated C or ASM constant: it must not be empty, and must
+/// only have ASCII letters, digits, and underscores.
+pub fn is_identifier(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
@@ -0,0 +1,203 @@
+//! Objects placed in a stage, like enemy spawns, items, the player start, and doors, along with
+//! the shared object types file that gives every type its id.
+//!
+//! An object type id is a single byte, with the object's kind in the high 2 bits and the index of
+//! its type within that kind, in the object types file, in the low 6 bits.  The player start has
+//! no types, so its id is always 0.
+
+use std::fs::File;
+
+use super::Error;
+
+/// The most types a single kind can have, as the type index only has 6 bits
+pub const MAX_TYPES: usize = 64;
+
+/// The bit of the type id holding the kind
+const KIND_SHIFT: u8 = 6;
+
+/// What an object is, which decides what its type and parameter mean.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub enum ObjectKind {
+    /// Where the player enters the stage.  Has no type.
+    #[serde(rename = "player_start")]
+    PlayerStart,
+
+    /// An enemy spawn point
+    #[serde(rename = "enemy")]
+    Enemy,
+
+    /// An item to pick up
+    #[serde(rename = "item")]
+    Item,
+
+    /// A door to another stage.  Its parameter is the stage it leads to.
+    #[serde(rename = "door")]
+    Door,
+}
+
+impl ObjectKind {
+    /// Every kind, in order of the value stored in the type id
+    pub const ALL: [ObjectKind; 4] = [ObjectKind::PlayerStart, ObjectKind::Enemy, ObjectKind::Item, ObjectKind::Door];
+
+    /// The kind's bits of the type id
+    pub fn id(self) -> u8 {
+        (self as u8) << KIND_SHIFT
+    }
+
+    /// The kind of a type id
+    pub fn from_id(id: u8) -> ObjectKind {
+        ObjectKind::ALL[(id >> KIND_SHIFT) as usize]
+    }
+
+    /// The name used in the stage file and for header constants
+    pub fn name(self) -> &'static str {
+        match self {
+            ObjectKind::PlayerStart => "player_start",
+            ObjectKind::Enemy => "enemy",
+            ObjectKind::Item => "item",
+            ObjectKind::Door => "door",
+        }
+    }
+}
+
+/// The type of an object, either by index within its kind or by name.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum ObjectTypeRef {
+    /// The index of the type within its kind
+    Index(u8),
+
+    /// The name of the type in the stage's `object_types` file.  Resolved by
+    /// [`Stage::resolve_object_types`](../serialize/struct.Stage.html#method.resolve_object_types).
+    Name(String),
+}
+
+impl ObjectTypeRef {
+    /// The type index, if the type is given by index or has been resolved.
+    pub fn index(&self) -> Option<u8> {
+        match self {
+            ObjectTypeRef::Index(index) => Some(*index),
+            ObjectTypeRef::Name(_) => None,
+        }
+    }
+}
+
+/// A single object, placed by metatile coordinate.  `x` is the column and `y` the line of the
+/// stage data, both counting from 0, no matter the stage's orientation.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Object {
+    pub kind: ObjectKind,
+
+    /// The type of enemy, item, or door.  Defaults to the first type of the kind.
+    #[serde(rename = "type", default, skip_serializing_if = "Option::is_none")]
+    pub object_type: Option<ObjectTypeRef>,
+
+    pub x: u8,
+    pub y: u8,
+
+    /// A byte for the game to use however the type needs, like an enemy's direction or the stage
+    /// a door leads to.
+    #[serde(default)]
+    pub parameter: u8,
+}
+
+impl Object {
+    /// The type id, if the type is resolved.
+    pub fn id(&self) -> Option<u8> {
+        match self.object_type {
+            Some(ref object_type) => object_type.index().map(|index| self.kind.id() | index),
+            None => Some(self.kind.id()),
+        }
+    }
+}
+
+/// The shared object types file, naming the types of each kind.  Each list holds up to 64 names,
+/// and each type's id is its kind's bits along with its index in the list.
+///
+/// ```yaml
+/// enemy: [walker, flyer]
+/// item: [coin, key]
+/// door: [door, pipe]
+/// ```
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ObjectTypes {
+    #[serde(default)]
+    pub enemy: Vec<String>,
+
+    #[serde(default)]
+    pub item: Vec<String>,
+
+    #[serde(default)]
+    pub door: Vec<String>,
+}
+
+impl ObjectTypes {
+    /// Load the object types from a YAML file.
+    pub fn load(filename: &str) -> Result<ObjectTypes, Error> {
+        let file = match File::open(filename) {
+            Ok(file) => file,
+            Err(err) => return Err(Error::ObjectError(format!("{}: {}", filename, err))),
+        };
+        match serde_yaml::from_reader(file) {
+            Ok(types) => Ok(types),
+            Err(err) => Err(Error::ObjectError(format!("{}: {}", filename, err))),
+        }
+    }
+
+    /// The type names of a kind.
+    pub fn names(&self, kind: ObjectKind) -> &[String] {
+        match kind {
+            ObjectKind::PlayerStart => &[],
+            ObjectKind::Enemy => &self.enemy,
+            ObjectKind::Item => &self.item,
+            ObjectKind::Door => &self.door,
+        }
+    }
+
+    /// Every type with its id, in order of id.
+    ///
+    /// ```
+    /// use nestools::stage::objects::{ObjectKind, ObjectTypes};
+    ///
+    /// let types: ObjectTypes = serde_yaml::from_str("
+    /// enemy: [walker, flyer]
+    /// door: [pipe]
+    /// ").unwrap();
+    /// assert_eq!(types.ids(), vec![
+    ///     (ObjectKind::Enemy, "walker", 0x40),
+    ///     (ObjectKind::Enemy, "flyer", 0x41),
+    ///     (ObjectKind::Door, "pipe", 0xC0),
+    /// ]);
+    /// ```
+    pub fn ids(&self) -> Vec<(ObjectKind, &str, u8)> {
+        ObjectKind::ALL.iter()
+            .flat_map(|&kind| {
+                self.names(kind).iter()
+                    .enumerate()
+                    .map(move |(index, name)| (kind, name.as_str(), kind.id() | index as u8))
+            })
+            .collect()
+    }
+
+    /// Validate the types, returning every problem found.
+    pub fn check(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        for &kind in &ObjectKind::ALL {
+            let names = self.names(kind);
+            if names.len() > MAX_TYPES {
+                problems.push(format!(
+                    "there are {} {} types, but there can not be more than {}",
+                    names.len(), kind.name(), MAX_TYPES));
+            }
+            for (index, name) in names.iter().enumerate() {
+                if names[..index].contains(name) {
+                    problems.push(format!("{} type {} is listed more than once", kind.name(), name));
+                }
+            }
+        }
+        problems
+    }
+}
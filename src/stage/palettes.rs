@@ -0,0 +1,151 @@
+//! The packed encoding of a stage's palettes, which fits both 16-color palettes into 19 bytes
+//! rather than 32.
+//!
+//! The NES only has 64 colors, so each color fits in 6 bits, and the first color of every
+//! palette is the universal background color, which only needs to be stored once.  The packed
+//! palettes are the universal background color, and then the other 3 colors of each of the 4
+//! background palettes and the 4 sprite palettes, in order, packed 4 colors to 3 bytes.  The
+//! first 3 colors of each group are the low 6 bits of the 3 bytes, and the 4th color is spread
+//! across their top 2 bits, its highest bits in the first byte.
+//!
+//! Packing is only lossless if every palette's first color is the universal background color,
+//! which is what [`pack`](fn.pack.html) checks.
+
+use super::Error;
+
+/// The length of the packed palettes, in bytes
+pub const PACKED_LENGTH: usize = 19;
+
+/// The bit of the stage attribute byte that is set when the stage's palettes are packed
+pub const ATTRIBUTE_BIT: u8 = 0x10;
+
+/// The index of every color that is stored, in order, out of the 32 colors of both palettes
+fn stored_colors() -> impl Iterator<Item = usize> {
+    (0..32).filter(|index| index % 4 != 0)
+}
+
+/// Pack the background and sprite palettes.  Fails if any palette's first color is not the
+/// universal background color, or a color is not an NES color.
+///
+/// ```
+/// use nestools::stage::palettes::{pack, unpack};
+///
+/// let background = [0x0F, 0x30, 0x10, 0x00, 0x0F, 0x1A, 0x2A, 0x0A, 0x0F, 0x16, 0x27, 0x18, 0x0F, 0x01, 0x11, 0x21];
+/// let sprite = [0x0F, 0x16, 0x27, 0x30, 0x0F, 0x02, 0x12, 0x22, 0x0F, 0x0B, 0x1B, 0x2B, 0x0F, 0x3D, 0x2D, 0x20];
+/// let packed = pack(&background, &sprite).unwrap();
+/// assert_eq!(packed.len(), 19);
+/// // The universal color, then the first group: 0x30, 0x10, 0x00, and 0x1A in the top bits
+/// assert_eq!(&packed[..4], &[0x0F, 0x70, 0x90, 0x80]);
+/// assert_eq!(unpack(&packed), (background, sprite));
+///
+/// let mut mismatched = sprite;
+/// mismatched[4] = 0x30;
+/// assert!(pack(&background, &mismatched).is_err());
+/// ```
+pub fn pack(background: &[u8; 16], sprite: &[u8; 16]) -> Result<[u8; PACKED_LENGTH], Error> {
+    let universal = background[0];
+    let colors: Vec<u8> = background.iter().chain(sprite.iter()).cloned().collect();
+    for (index, &color) in colors.iter().enumerate() {
+        if color > 0x3F {
+            return Err(Error::PaletteError(format!(
+                "palette color {} is {:#04X}, but NES colors only go up to 0x3F", index, color)));
+        }
+        if index % 4 == 0 && color != universal {
+            let (name, index) = if index < 16 { ("background", index) } else { ("sprite", index - 16) };
+            return Err(Error::PaletteError(format!(
+                "{} palette entry {} is {:#04X}, but packed palettes need it to be the universal background color {:#04X}",
+                name, index, color, universal)));
+        }
+    }
+
+    let mut packed = [0u8; PACKED_LENGTH];
+    packed[0] = universal;
+    let stored: Vec<u8> = stored_colors().map(|index| colors[index]).collect();
+    for (group, colors) in stored.chunks(4).enumerate() {
+        let bytes = &mut packed[1 + group * 3..1 + group * 3 + 3];
+        for (position, byte) in bytes.iter_mut().enumerate() {
+            // The 4th color's bits go out highest first
+            *byte = colors[position] | ((colors[3] >> (4 - position * 2)) & 0x03) << 6;
+        }
+    }
+    Ok(packed)
+}
+
+/// Unpack palettes packed by [`pack`](fn.pack.html) into the background and sprite palettes.
+pub fn unpack(bytes: &[u8; PACKED_LENGTH]) -> ([u8; 16], [u8; 16]) {
+    let mut colors = [bytes[0]; 32];
+    let mut stored = Vec::new();
+    for group in bytes[1..].chunks(3) {
+        let mut fourth = 0;
+        for &byte in group {
+            stored.push(byte & 0x3F);
+            fourth = fourth << 2 | byte >> 6;
+        }
+        stored.push(fourth);
+    }
+    for (index, color) in stored_colors().zip(stored) {
+        colors[index] = color;
+    }
+
+    let mut background = [0; 16];
+    let mut sprite = [0; 16];
+    background.copy_from_slice(&colors[..16]);
+    sprite.copy_from_slice(&colors[16..]);
+    (background, sprite)
+}
+
+/// A reference 6502 routine unpacking the palettes, as a ca65 macro.  `source` is a zero page
+/// pointer to the packed palettes, `dest` is the 32-byte buffer to unpack both palettes into,
+/// ready to be copied to $3F00, and `temp` is a zero page byte to work in.  Clobbers A, X, and Y.
+pub fn unpack_asm(name: &str) -> String {
+    format!(r#".macro {name} source, dest, temp
+    .local universal, group
+    ; The universal background color goes in the first entry of every palette
+    ldy #0
+    lda (source),y
+    ldx #28
+universal:
+    sta dest,x
+    dex
+    dex
+    dex
+    dex
+    bpl universal
+
+    ; Then each group of 3 bytes holds 4 colors, skipping the universal entries
+    ldx #1
+    iny
+group:
+    lda #0
+    sta temp
+    .repeat 3
+        lda (source),y
+        and #$3F
+        sta dest,x
+        ; The top 2 bits are the next bits of the 4th color
+        lda (source),y
+        asl a
+        rol temp
+        asl a
+        rol temp
+        iny
+        inx
+        txa
+        and #3
+        bne :+
+        inx
+    :
+    .endrepeat
+    lda temp
+    sta dest,x
+    inx
+    txa
+    and #3
+    bne :+
+    inx
+:
+    cpy #{length}
+    bne group
+.endmacro
+"#, name = name, length = PACKED_LENGTH)
+}
@@ -0,0 +1,105 @@
+//! Rendering stages to images, so that changes to a stage can be looked at without building the
+//! ROM.
+//!
+//! Each metatile is drawn 16 pixels square from its four tiles, top left, top right, bottom left,
+//! then bottom right, in the colors of its background palette, looked up in the NES master
+//! palette.
+
+use super::serialize::{Metatile, Stage};
+use crate::palette;
+use crate::sprites::Tile;
+
+/// The size of a metatile, in pixels
+pub const METATILE_SIZE: usize = 16;
+
+/// The palette used when the stage's palettes have not been loaded: gray, in every palette
+const GRAY: [u8; 16] = [
+    0x0F, 0x00, 0x10, 0x30, 0x0F, 0x00, 0x10, 0x30, 0x0F, 0x00, 0x10, 0x30, 0x0F, 0x00, 0x10, 0x30,
+];
+
+/// The background palette of the stage, or gray if it has not been loaded.
+fn background_palette(stage: &Stage) -> [u8; 16] {
+    stage.background_palette.colors().cloned().unwrap_or(GRAY)
+}
+
+/// Draw a metatile into an image `width` pixels wide, with its top left corner at `left`, `top`.
+/// Tiles that are not resolved or not in `tiles` are left as they are.
+fn draw_metatile(pixels: &mut [[u8; 3]], width: usize, left: usize, top: usize, metatile: &Metatile, tiles: &[Tile], palette: &[u8; 16]) {
+    let base = (metatile.palette & 0x03) as usize * 4;
+    for (quarter, tile) in metatile.tiles.iter().enumerate() {
+        let tile = match tile.index().and_then(|tile| tiles.get(tile as usize)) {
+            Some(tile) => tile,
+            None => continue,
+        };
+        let left = left + (quarter % 2) * 8;
+        let top = top + (quarter / 2) * 8;
+        for (y, row) in tile.iter().enumerate() {
+            for (x, color) in row.enumerate() {
+                // Color 0 of every palette is the universal background color
+                let color = if color == 0 { palette[0] } else { palette[base + color as usize] };
+                pixels[(top + y) * width + left + x] = palette::rgb(color);
+            }
+        }
+    }
+}
+
+/// Render a single row with every metatile of the stage, in order.  `tiles` is the page of the
+/// pattern table the stage's tiles come from, and the tiles must already be resolved.  Returns
+/// the RGB pixels, row by row, along with the width.
+pub fn render_metatiles(stage: &Stage, tiles: &[Tile]) -> (Vec<[u8; 3]>, usize) {
+    let palette = background_palette(stage);
+    let width = stage.metatiles.len() * METATILE_SIZE;
+    let mut pixels = vec![palette::rgb(palette[0]); width * METATILE_SIZE];
+    for (index, metatile) in stage.metatiles.iter().enumerate() {
+        draw_metatile(&mut pixels, width, index * METATILE_SIZE, 0, metatile, tiles, &palette);
+    }
+    (pixels, width)
+}
+
+/// Render the whole stage as it is laid out in its data, whatever its orientation.  `tiles` is
+/// the page of the pattern table the stage's tiles come from, and the tiles must already be
+/// resolved.  Symbols that are not metatiles are left in the universal background color.
+/// Returns the RGB pixels, row by row, along with the width.
+///
+/// ```
+/// use nestools::palette;
+/// use nestools::sprites::Tile;
+/// use nestools::stage::preview::render_stage;
+/// use nestools::stage::serialize::Stage;
+///
+/// let stage: Stage = serde_yaml::from_str("
+/// background_palette: [0x0F, 0x16, 0x27, 0x30, 0x0F, 0x01, 0x11, 0x21, 0x0F, 0, 0, 0, 0x0F, 0, 0, 0]
+/// sprite_palette: [0x0F, 0, 0, 0, 0x0F, 0, 0, 0, 0x0F, 0, 0, 0, 0x0F, 0, 0, 0]
+/// metatiles:
+///   - {name: sky, symbol: '.', palette: 0, tiles: [0, 0, 0, 0]}
+///   - {name: ground, symbol: '=', palette: 1, tiles: [1, 1, 1, 1]}
+/// data: |
+///   ..=
+///   ===
+/// ").unwrap();
+///
+/// // A blank tile, and a tile of color 3
+/// let tiles = vec![
+///     Tile {name: None, data: [0; 16]},
+///     Tile {name: None, data: [0xFF; 16]},
+/// ];
+/// let (pixels, width) = render_stage(&stage, &tiles);
+/// assert_eq!((width, pixels.len() / width), (48, 32));
+/// assert_eq!(pixels[0], palette::rgb(0x0F));
+/// assert_eq!(pixels[47], palette::rgb(0x21));
+/// assert_eq!(pixels[16 * width], palette::rgb(0x21));
+/// ```
+pub fn render_stage(stage: &Stage, tiles: &[Tile]) -> (Vec<[u8; 3]>, usize) {
+    let palette = background_palette(stage);
+    let (columns, lines) = stage.dimensions();
+    let width = columns * METATILE_SIZE;
+    let mut pixels = vec![palette::rgb(palette[0]); width * lines * METATILE_SIZE];
+    for (y, line) in stage.data.lines().enumerate() {
+        for (x, symbol) in line.chars().take(columns).enumerate() {
+            if let Some(metatile) = stage.metatiles.iter().find(|metatile| metatile.symbol == symbol) {
+                draw_metatile(&mut pixels, width, x * METATILE_SIZE, y * METATILE_SIZE, metatile, tiles, &palette);
+            }
+        }
+    }
+    (pixels, width)
+}
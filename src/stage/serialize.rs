@@ -2,23 +2,67 @@
 //! files for export as compressed binary stages.
 
 use std::io::Write;
-use std::io::Result;
+use std::convert::TryFrom;
 use std::default::Default;
 use std::collections::HashMap;
 
+use super::Error;
+use super::codec::{self, Codec};
+use super::header::{self, Header, MusicRef};
+use super::palettes;
+use super::objects::{Object, ObjectKind, ObjectTypeRef, ObjectTypes, MAX_TYPES};
+use super::tiled::{TiledImport, TiledMap};
+use crate::palette;
+use crate::sprites::{Page, PatternTable};
+
+/// The symbols given to metatiles of decompiled stages, in order
+const DECOMPILED_SYMBOLS: &str = "0123456789abcdef";
+
+/// The metatile flags a stage has if it does not list its own
+pub const DEFAULT_FLAGS: [&str; 5] = ["solid", "hazard", "ladder", "water", "one_way"];
+
+/// The most flags a stage can have.  The metatile attribute byte holds the palette in its low 2
+/// bits, and the flags in the other 6.
+pub const MAX_FLAGS: usize = 6;
+
+/// The bit of the metatile attribute byte holding the first flag
+const FLAG_SHIFT: usize = 2;
+
+fn default_flags() -> Vec<String> {
+    DEFAULT_FLAGS.iter().map(|&flag| String::from(flag)).collect()
+}
+
 /// Orientation enum for setting orientation
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
 #[serde(deny_unknown_fields)]
 pub enum Orientation {
     #[serde(rename = "horizontal")]
+    #[default]
     Horizontal,
     #[serde(rename = "vertical")]
     Vertical,
 }
 
-impl Default for Orientation {
-    fn default() -> Orientation {
-        Orientation::Horizontal
+/// A single tile of a metatile, either by index or by name.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum TileRef {
+    /// The index of the tile in its page
+    Index(u8),
+
+    /// The name of the tile, as it is defined in spritesheetc headers without the prefix, like
+    /// `LEFT_ground_0`.  Resolved against a sprites manifest by
+    /// [`Stage::resolve_tiles`](struct.Stage.html#method.resolve_tiles).
+    Name(String),
+}
+
+impl TileRef {
+    /// The tile index, if the tile is given by index or has been resolved.
+    pub fn index(&self) -> Option<u8> {
+        match self {
+            TileRef::Index(index) => Some(*index),
+            TileRef::Name(_) => None,
+        }
     }
 }
 
@@ -32,10 +76,196 @@ pub struct Metatile {
     pub symbol: char,
 
     /// Actual tiles used
-    pub tiles: [u8; 4],
+    pub tiles: [TileRef; 4],
 
     /// Attribute table pallete section
     pub palette: u8,
+
+    /// Collision and behavior flags, from the stage's `flags`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub flags: Vec<String>,
+}
+
+/// Compression for the stage body, as described in [`codec`](../codec/index.html)
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[serde(rename = "nibble_rle")]
+    #[default]
+    NibbleRle,
+    #[serde(rename = "byte_rle")]
+    ByteRle,
+    #[serde(rename = "lz77")]
+    Lz77,
+    #[serde(rename = "dictionary")]
+    Dictionary,
+
+    /// Whichever codec gives the smallest body
+    #[serde(rename = "auto")]
+    Auto,
+}
+
+impl Compression {
+    /// The compression that always uses the given codec, or None if no compression does
+    pub fn from_codec(codec: &dyn Codec) -> Option<Compression> {
+        [Compression::NibbleRle, Compression::ByteRle, Compression::Lz77, Compression::Dictionary].iter()
+            .find(|compression| compression.codec().map(|known| known.id()) == Some(codec.id()))
+            .cloned()
+    }
+
+    /// The codec to use, or None for `Auto`
+    pub fn codec(self) -> Option<&'static dyn Codec> {
+        match self {
+            Compression::NibbleRle => Some(&codec::NibbleRle),
+            Compression::ByteRle => Some(&codec::ByteRle),
+            Compression::Lz77 => Some(&codec::Lz77),
+            Compression::Dictionary => Some(&codec::Dictionary),
+            Compression::Auto => None,
+        }
+    }
+}
+
+/// A compressed stage body, as built by [`Stage::compress`](struct.Stage.html#method.compress).
+pub struct Body {
+    /// The codec the body was compressed with
+    pub codec: &'static dyn Codec,
+
+    /// The compressed body of every screen, or the single body of a stage that is not split into
+    /// screens
+    pub screens: Vec<Vec<u8>>,
+}
+
+impl Body {
+    /// The total length of the body, in bytes
+    pub fn length(&self) -> usize {
+        self.screens.iter().map(Vec::len).sum()
+    }
+}
+
+/// How the stage's palettes are stored
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PaletteEncoding {
+    /// Both palettes, 32 bytes as they are
+    #[serde(rename = "raw")]
+    #[default]
+    Raw,
+
+    /// Both palettes packed into 19 bytes, as described in [`palettes`](../palettes/index.html)
+    #[serde(rename = "packed")]
+    Packed,
+}
+
+impl PaletteEncoding {
+    /// Whether this is the default `raw` encoding, which is left out when serializing
+    ///
+    /// ```
+    /// use nestools::stage::serialize::{PaletteEncoding, Stage};
+    ///
+    /// let mut stage: Stage = serde_yaml::from_str("
+    /// background_palette: [0x0F, 0, 0, 0, 0x0F, 0, 0, 0, 0x0F, 0, 0, 0, 0x0F, 0, 0, 0]
+    /// sprite_palette: [0x0F, 0, 0, 0, 0x0F, 0, 0, 0, 0x0F, 0, 0, 0, 0x0F, 0, 0, 0]
+    /// metatiles:
+    ///   - {name: sky, symbol: '.', palette: 0, tiles: [0, 0, 0, 0]}
+    /// data: '.'
+    /// ").unwrap();
+    /// assert!(!serde_yaml::to_string(&stage).unwrap().contains("palette_encoding"));
+    ///
+    /// stage.palette_encoding = PaletteEncoding::Packed;
+    /// assert!(serde_yaml::to_string(&stage).unwrap().contains("palette_encoding: packed"));
+    /// ```
+    pub fn is_raw(&self) -> bool {
+        *self == PaletteEncoding::Raw
+    }
+}
+
+/// The size of a single screen of a stage that is split into screens, in metatiles.  Defaults to
+/// a full NES screen of 16 by 15 metatiles.
+///
+/// ```
+/// use nestools::stage::serialize::Stage;
+///
+/// let stage: Stage = serde_yaml::from_str("
+/// screens: {width: 2, height: 2}
+/// background_palette: [0x0F, 0, 0, 0, 0x0F, 0, 0, 0, 0x0F, 0, 0, 0, 0x0F, 0, 0, 0]
+/// sprite_palette: [0x0F, 0, 0, 0, 0x0F, 0, 0, 0, 0x0F, 0, 0, 0, 0x0F, 0, 0, 0]
+/// metatiles:
+///   - {name: sky, symbol: '.', palette: 0, tiles: [0, 0, 0, 0]}
+///   - {name: ground, symbol: '=', palette: 0, tiles: [1, 1, 1, 1]}
+/// data: |
+///   ..==..
+///   ======
+/// ").unwrap();
+///
+/// let mut binary = Vec::new();
+/// stage.write_binary(&mut binary).unwrap();
+/// // 3 screens, their offsets, and the offset of the end, after the 2 metatiles
+/// assert_eq!(&binary[44..53], &[3, 0, 0, 4, 0, 5, 0, 9, 0]);
+///
+/// let parsed = Stage::parse(&binary, 2).unwrap();
+/// assert_eq!(parsed.screens, stage.screens);
+/// assert_eq!(parsed.data, "001100\n111111\n");
+///
+/// let short: Stage = serde_yaml::from_str("
+/// screens: {width: 4, height: 2}
+/// background_palette: [0x0F, 0, 0, 0, 0x0F, 0, 0, 0, 0x0F, 0, 0, 0, 0x0F, 0, 0, 0]
+/// sprite_palette: [0x0F, 0, 0, 0, 0x0F, 0, 0, 0, 0x0F, 0, 0, 0, 0x0F, 0, 0, 0]
+/// metatiles:
+///   - {name: sky, symbol: '.', palette: 0, tiles: [0, 0, 0, 0]}
+/// data: |
+///   ......
+///   ......
+/// ").unwrap();
+/// assert_eq!(short.validate().len(), 1);
+/// ```
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ScreenSize {
+    #[serde(default = "ScreenSize::default_width")]
+    pub width: usize,
+
+    #[serde(default = "ScreenSize::default_height")]
+    pub height: usize,
+}
+
+impl ScreenSize {
+    fn default_width() -> usize {
+        16
+    }
+
+    fn default_height() -> usize {
+        15
+    }
+}
+
+impl Default for ScreenSize {
+    fn default() -> ScreenSize {
+        ScreenSize {
+            width: ScreenSize::default_width(),
+            height: ScreenSize::default_height(),
+        }
+    }
+}
+
+/// A 16-byte stage palette, either inline or the name of a palette set.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum PaletteRef {
+    /// The 16 colors, inline
+    Colors([u8; 16]),
+
+    /// The name of a palette set in the stage's `palettes` file.  The background palette uses the
+    /// set's background half, and the sprite palette its sprite half.
+    Named(String),
+}
+
+impl PaletteRef {
+    /// The colors, if the palette is inline or has been resolved by
+    /// [`Stage::load_palettes`](struct.Stage.html#method.load_palettes).
+    pub fn colors(&self) -> Option<&[u8; 16]> {
+        match self {
+            PaletteRef::Colors(colors) => Some(colors),
+            PaletteRef::Named(_) => None,
+        }
+    }
 }
 
 /// Top level stage sheet type.  Holds all the data necessary to compile the stage file.
@@ -45,125 +275,1101 @@ pub struct Stage {
     #[serde(default)]
     pub orientation: Orientation,
 
+    /// How the stage body is compressed.  Defaults to `nibble_rle`.
+    #[serde(default)]
+    pub compression: Compression,
+
+    /// How the palettes are stored.  Defaults to `raw`.
+    #[serde(default, skip_serializing_if = "PaletteEncoding::is_raw")]
+    pub palette_encoding: PaletteEncoding,
+
+    /// If given, the stage body is split into screens of this size, each compressed on its own,
+    /// so that the stage can be loaded from any screen.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub screens: Option<ScreenSize>,
+
+    /// The music track played in the stage, by index or by name in the `tracks` file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub music: Option<MusicRef>,
+
+    /// Whether the stage can not be scrolled back toward its start
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub scroll_lock: bool,
+
+    /// The time limit of the stage, in seconds.  0 is no limit.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time_limit: Option<u16>,
+
+    /// A palettec input file, for palettes given by name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub palettes: Option<String>,
+
+    /// A spritesheetc input file, for tiles given by name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sprites: Option<String>,
+
+    /// The pattern table page the stage's tiles come from.  If not given, every tile given by name
+    /// must simply come from the same page, which becomes the stage's page once resolved.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub page: Option<Page>,
+
+    /// An object types file, for object types given by name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub object_types: Option<String>,
+
+    /// A music track list file, for music given by name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tracks: Option<String>,
+
+    /// The names of the metatile flags, in bit order, up to 6.  Defaults to
+    /// [`DEFAULT_FLAGS`](constant.DEFAULT_FLAGS.html).
+    #[serde(default = "default_flags")]
+    pub flags: Vec<String>,
+
     /// Background Palette definition
-    pub background_palette: [u8; 16],
+    pub background_palette: PaletteRef,
 
     /// Sprite Palette definition
-    pub sprite_palette: [u8; 16],
+    pub sprite_palette: PaletteRef,
 
     /// Metatiles, for specifying the sprites used to compose a tile
     pub metatiles: Vec<Metatile>,
 
+    /// A Tiled map to import the data and objects from, in place of the data
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tiled: Option<TiledImport>,
+
     /// The number of tiles to generate
+    #[serde(default)]
     pub data: String,
+
+    /// Enemies, items, the player start, and doors, placed on the stage
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub objects: Vec<Object>,
 }
 
-// Take a slice of chars and return a RLE-compressed version of it
-fn run_length_encode(chars: &[char], limit: u16) -> Vec<(char, u16)> {
-    let mut output = Vec::new();
-    if chars.len() > 0 {
-        let mut current = chars[0];
-        let mut count = 1;
-        for &c in &chars[1..] {
-            if c == current {
-                count += 1;
-                if count == limit {
-                    output.push((current, count));
-                    // Reset this character to ensure we restart at 1 for the next character
-                    current = '\0';
-                    count = 0;
+impl Stage {
+    /// Resolve every palette given by name from the `palettes` file, replacing it with its colors.
+    /// Does nothing if both palettes are inline.
+    pub fn load_palettes(&mut self) -> Result<(), palette::Error> {
+        let names: Vec<String> = [&self.background_palette, &self.sprite_palette].iter()
+            .filter_map(|palette| match palette {
+                PaletteRef::Named(name) => Some(name.clone()),
+                PaletteRef::Colors(_) => None,
+            })
+            .collect();
+        if names.is_empty() {
+            return Ok(());
+        }
+
+        let filename = match self.palettes {
+            Some(ref filename) => filename,
+            None => return Err(palette::Error::FormatError(format!(
+                "palette {} is given by name, but the stage has no palettes file", names[0]))),
+        };
+        let descriptions = palette::serialize::load(filename)?;
+        let find = |name: &str| -> Result<palette::Palette, palette::Error> {
+            match descriptions.iter().find(|description| description.name == name) {
+                Some(description) => description.build(),
+                None => Err(palette::Error::FormatError(format!("{}: no palette named {}", filename, name))),
+            }
+        };
+
+        if let PaletteRef::Named(ref name) = self.background_palette {
+            self.background_palette = PaletteRef::Colors(find(name)?.background());
+        }
+        if let PaletteRef::Named(ref name) = self.sprite_palette {
+            self.sprite_palette = PaletteRef::Colors(find(name)?.sprite());
+        }
+        Ok(())
+    }
+
+    /// The bit of the metatile attribute byte for the named flag, if the stage has it.
+    pub fn flag_bit(&self, name: &str) -> Option<u8> {
+        self.flags.iter()
+            .position(|flag| flag == name)
+            .filter(|&position| position < MAX_FLAGS)
+            .map(|position| 1 << (position + FLAG_SHIFT))
+    }
+
+    /// The attribute byte of a metatile: its palette in the low 2 bits, and its flags above it.
+    /// A flag that is not one of the stage's flags, or that does not fit in the byte, is an error.
+    ///
+    /// ```
+    /// use nestools::stage::serialize::Stage;
+    ///
+    /// let stage: Stage = serde_yaml::from_str("
+    /// flags: [solid, hazard, slippery]
+    /// background_palette: [0x0F, 0, 0, 0, 0x0F, 0, 0, 0, 0x0F, 0, 0, 0, 0x0F, 0, 0, 0]
+    /// sprite_palette: [0x0F, 0, 0, 0, 0x0F, 0, 0, 0, 0x0F, 0, 0, 0, 0x0F, 0, 0, 0]
+    /// metatiles:
+    ///   - {name: ice, symbol: '-', palette: 2, tiles: [0, 0, 0, 0], flags: [solid, slippery]}
+    ///   - {name: glue, symbol: '+', palette: 0, tiles: [0, 0, 0, 0], flags: [solid, sticky]}
+    /// data: '-+'
+    /// ").unwrap();
+    /// assert_eq!(stage.attribute(&stage.metatiles[0]).unwrap(), 0b00010110);
+    /// assert_eq!(
+    ///     stage.attribute(&stage.metatiles[1]).unwrap_err().to_string(),
+    ///     "metatile glue has flag sticky, which is not one of the stage's flags");
+    /// ```
+    pub fn attribute(&self, metatile: &Metatile) -> Result<u8, Error> {
+        let mut attribute = metatile.palette & 0x03;
+        for flag in &metatile.flags {
+            match self.flag_bit(flag) {
+                Some(bit) => attribute |= bit,
+                None if self.flags.contains(flag) => return Err(Error::FlagError(format!(
+                    "metatile {} has flag {}, but only the first {} flags fit in the attribute byte",
+                    metatile.name, flag, MAX_FLAGS))),
+                None => return Err(Error::FlagError(format!(
+                    "metatile {} has flag {}, which is not one of the stage's flags",
+                    metatile.name, flag))),
+            }
+        }
+        Ok(attribute)
+    }
+
+    /// Whether any metatile has a tile given by name.
+    pub fn has_named_tiles(&self) -> bool {
+        self.metatiles.iter().flat_map(|metatile| metatile.tiles.iter()).any(|tile| tile.index().is_none())
+    }
+
+    /// Resolve every tile given by name against a pattern table, replacing each with its index in
+    /// its page.  Returns every problem found: names that are not in the pattern table, and tiles
+    /// from a different page than the stage's.  A stage without a page takes the page of its
+    /// tiles.
+    ///
+    /// ```
+    /// use nestools::sprites::{Page, PatternTable};
+    /// use nestools::stage::serialize::Stage;
+    ///
+    /// let pattern_table = PatternTable::from_sheet_pattern_table(serde_yaml::from_str("
+    /// left:
+    ///   - {type: Fill, name: blank, value: 0, count: 1}
+    ///   - {type: Fill, name: ground, value: 1, count: 2}
+    /// right:
+    ///   - {type: Fill, name: player, value: 2, count: 1}
+    /// ").unwrap()).unwrap();
+    ///
+    /// let mut stage: Stage = serde_yaml::from_str("
+    /// background_palette: [0x0F, 0, 0, 0, 0x0F, 0, 0, 0, 0x0F, 0, 0, 0, 0x0F, 0, 0, 0]
+    /// sprite_palette: [0x0F, 0, 0, 0, 0x0F, 0, 0, 0, 0x0F, 0, 0, 0, 0x0F, 0, 0, 0]
+    /// metatiles:
+    ///   - {name: ground, symbol: '#', palette: 0, tiles: [LEFT_ground_0, LEFT_ground_1, 0, LEFT_blank_0]}
+    /// data: '#'
+    /// ").unwrap();
+    /// assert!(stage.resolve_tiles(&pattern_table).is_empty());
+    /// assert_eq!(stage.metatiles[0].tiles[1].index(), Some(2));
+    /// assert_eq!(stage.page, Some(Page::Left));
+    ///
+    /// let mut stage: Stage = serde_yaml::from_str("
+    /// page: left
+    /// background_palette: [0x0F, 0, 0, 0, 0x0F, 0, 0, 0, 0x0F, 0, 0, 0, 0x0F, 0, 0, 0]
+    /// sprite_palette: [0x0F, 0, 0, 0, 0x0F, 0, 0, 0, 0x0F, 0, 0, 0, 0x0F, 0, 0, 0]
+    /// metatiles:
+    ///   - {name: bad, symbol: '#', palette: 0, tiles: [LEFT_ground_2, RIGHT_player_0, 0, 0]}
+    /// data: '#'
+    /// ").unwrap();
+    /// assert_eq!(stage.resolve_tiles(&pattern_table).len(), 2);
+    /// ```
+    pub fn resolve_tiles(&mut self, pattern_table: &PatternTable) -> Vec<String> {
+        let mut names: HashMap<String, (Page, u8)> = HashMap::new();
+        for (page, tiles) in &[(Page::Left, &pattern_table.left), (Page::Right, &pattern_table.right)] {
+            for (index, tile) in tiles.iter().enumerate() {
+                if let Some(ref name) = tile.name {
+                    names.insert(format!("{}_{}", page, name), (*page, index as u8));
                 }
-            } else {
-                if count > 0 {
-                    output.push((current, count));
+            }
+        }
+
+        let mut problems = Vec::new();
+        let mut stage_page = self.page;
+        for metatile in &mut self.metatiles {
+            for tile in metatile.tiles.iter_mut() {
+                let name = match tile {
+                    TileRef::Name(name) => name.clone(),
+                    TileRef::Index(_) => continue,
+                };
+                let (page, index) = match names.get(&name) {
+                    Some(&found) => found,
+                    None => {
+                        problems.push(format!(
+                            "metatile {} uses tile {}, which is not in the sprites manifest",
+                            metatile.name, name));
+                        continue;
+                    },
+                };
+                match stage_page {
+                    Some(stage_page) if stage_page != page => {
+                        let reason = if self.page.is_some() { "the stage uses" } else { "its other tiles are from" };
+                        problems.push(format!(
+                            "metatile {} uses tile {} from the {} page, but {} the {} page",
+                            metatile.name, name, page, reason, stage_page));
+                        continue;
+                    },
+                    Some(_) => (),
+                    None => stage_page = Some(page),
                 }
-                current = c;
-                count = 1;
+                *tile = TileRef::Index(index);
             }
         }
-        if count > 0 {
-            output.push((current, count));
+        self.page = stage_page;
+        problems
+    }
+
+    /// Import the stage data and objects from the stage's Tiled map, if it has one.  The data is
+    /// replaced, and the objects are added to any the stage already has.
+    pub fn import_tiled(&mut self) -> Result<(), Error> {
+        let import = match self.tiled {
+            Some(ref import) => import.clone(),
+            None => return Ok(()),
+        };
+        let map = TiledMap::load(&import.map, import.layer.as_deref(), import.object_layer.as_deref())?;
+        self.import_map(&import, &map)
+    }
+
+    /// Import the stage data and objects from a loaded Tiled map, as
+    /// [`import_tiled`](#method.import_tiled).  Every GID in the map must be in the import's
+    /// tiles table or have a symbol in the map's tileset, and every object's type must be an
+    /// object kind.
+    ///
+    /// ```
+    /// use nestools::stage::serialize::Stage;
+    /// use nestools::stage::tiled::TiledMap;
+    ///
+    /// let mut stage: Stage = serde_yaml::from_str("
+    /// background_palette: [0x0F, 0, 0, 0, 0x0F, 0, 0, 0, 0x0F, 0, 0, 0, 0x0F, 0, 0, 0]
+    /// sprite_palette: [0x0F, 0, 0, 0, 0x0F, 0, 0, 0, 0x0F, 0, 0, 0, 0x0F, 0, 0, 0]
+    /// metatiles:
+    ///   - {name: sky, symbol: '.', palette: 0, tiles: [0, 0, 0, 0]}
+    ///   - {name: ground, symbol: '=', palette: 1, tiles: [1, 2, 3, 4]}
+    /// tiled:
+    ///   map: level.tmx
+    ///   tiles: {0: sky, 1: ground}
+    /// ").unwrap();
+    ///
+    /// let map = TiledMap::parse_tmx(r#"
+    /// <map width="3" height="2" tilewidth="16" tileheight="16">
+    ///  <layer name="terrain" width="3" height="2">
+    ///   <data encoding="csv">0,0,1,1,1,1</data>
+    ///  </layer>
+    ///  <objectgroup name="objects">
+    ///   <object name="walker" type="enemy" x="20" y="8">
+    ///    <properties><property name="parameter" type="int" value="2"/></properties>
+    ///   </object>
+    ///  </objectgroup>
+    /// </map>"#, None, None).unwrap();
+    ///
+    /// let import = stage.tiled.clone().unwrap();
+    /// stage.import_map(&import, &map).unwrap();
+    /// assert_eq!(stage.data, "..=\n===\n");
+    /// assert_eq!((stage.objects[0].x, stage.objects[0].y, stage.objects[0].parameter), (1, 0, 2));
+    /// ```
+    pub fn import_map(&mut self, import: &TiledImport, map: &TiledMap) -> Result<(), Error> {
+        let mut symbols: HashMap<u32, char> = HashMap::new();
+        for (&gid, name) in &import.tiles {
+            match self.metatiles.iter().find(|metatile| metatile.name == *name) {
+                Some(metatile) => symbols.insert(gid, metatile.symbol),
+                None => return Err(Error::TiledError(format!(
+                    "GID {} is mapped to metatile {}, which does not exist", gid, name))),
+            };
+        }
+
+        let mut data = String::new();
+        for (index, gid) in map.tiles.iter().enumerate() {
+            let at = format!("{}: tile at {}, {}", import.map, index % map.width, index / map.width);
+            match symbols.get(gid).or_else(|| map.symbols.get(gid)) {
+                Some(&symbol) if self.metatiles.iter().any(|metatile| metatile.symbol == symbol) => data.push(symbol),
+                Some(&symbol) => return Err(Error::TiledError(format!(
+                    "{} has symbol {:?}, which is not a metatile", at, symbol))),
+                None => return Err(Error::TiledError(format!(
+                    "{} has GID {}, which is not in the tiles table and has no symbol", at, gid))),
+            }
+            if (index + 1) % map.width == 0 {
+                data.push('\n');
+            }
+        }
+
+        let mut objects = Vec::new();
+        for tiled_object in &map.objects {
+            let at = format!("{}: object {:?} at {}, {}", import.map, tiled_object.name, tiled_object.x, tiled_object.y);
+            let kind = match ObjectKind::ALL.iter().find(|kind| kind.name() == tiled_object.class) {
+                Some(&kind) => kind,
+                None => return Err(Error::TiledError(format!(
+                    "{} has type {:?}, which is not an object kind", at, tiled_object.class))),
+            };
+            let x = (tiled_object.x / map.tile_width as f64).floor();
+            let y = (tiled_object.y / map.tile_height as f64).floor();
+            if x < 0.0 || y < 0.0 || x > 255.0 || y > 255.0 {
+                return Err(Error::TiledError(format!("{} is outside of the stage", at)));
+            }
+            let parameter = match tiled_object.properties.get("parameter") {
+                Some(parameter) => match parameter.parse() {
+                    Ok(parameter) => parameter,
+                    Err(_) => return Err(Error::TiledError(format!(
+                        "{} has parameter {}, which is not a byte", at, parameter))),
+                },
+                None => 0,
+            };
+            objects.push(Object {
+                kind,
+                object_type: match kind {
+                    _ if tiled_object.name.is_empty() => None,
+                    ObjectKind::PlayerStart => None,
+                    _ => Some(match tiled_object.name.parse() {
+                        Ok(index) => ObjectTypeRef::Index(index),
+                        Err(_) => ObjectTypeRef::Name(tiled_object.name.clone()),
+                    }),
+                },
+                x: x as u8,
+                y: y as u8,
+                parameter,
+            });
         }
+
+        self.data = data;
+        self.objects.extend(objects);
+        Ok(())
     }
-    output
-}
 
-impl Stage {
-    pub fn write_binary(&self, write: &mut dyn Write) -> Result<()> {
+    /// Resolve the music track given by name against the track list, replacing it with its
+    /// index.  Returns the problem found, if the name is not a track.
+    ///
+    /// ```
+    /// use nestools::stage::serialize::Stage;
+    ///
+    /// let mut stage: Stage = serde_yaml::from_str("
+    /// music: castle
+    /// scroll_lock: true
+    /// background_palette: [0x0F, 0, 0, 0, 0x0F, 0, 0, 0, 0x0F, 0, 0, 0, 0x0F, 0, 0, 0]
+    /// sprite_palette: [0x0F, 0, 0, 0, 0x0F, 0, 0, 0, 0x0F, 0, 0, 0, 0x0F, 0, 0, 0]
+    /// metatiles:
+    ///   - {name: sky, symbol: '.', palette: 0, tiles: [0, 0, 0, 0]}
+    /// data: '....'
+    /// ").unwrap();
+    /// let tracks = vec![String::from("overworld"), String::from("castle")];
+    /// assert!(stage.resolve_music(&tracks).is_empty());
+    ///
+    /// // The attribute byte has the header bit set, and the header follows it
+    /// let mut binary = Vec::new();
+    /// stage.write_binary(&mut binary).unwrap();
+    /// assert_eq!(&binary[..6], &[0x80, 1, 1, 1, 0, 0]);
+    ///
+    /// let parsed = Stage::parse(&binary, 1).unwrap();
+    /// assert_eq!(parsed.header(), stage.header());
+    /// ```
+    pub fn resolve_music(&mut self, tracks: &[String]) -> Vec<String> {
+        let name = match self.music {
+            Some(MusicRef::Name(ref name)) => name.clone(),
+            _ => return Vec::new(),
+        };
+        match tracks.iter().position(|track| *track == name) {
+            Some(index) => {
+                self.music = Some(MusicRef::Index(index as u8));
+                Vec::new()
+            },
+            None => vec![format!("music {} is not one of the tracks", name)],
+        }
+    }
+
+    /// The stage header, if the stage has one.  Music given by name must already be resolved.
+    pub fn header(&self) -> Option<Header> {
+        if self.music.is_none() && !self.scroll_lock && self.time_limit.is_none() {
+            return None;
+        }
+        Some(Header {
+            music: self.music.as_ref().and_then(MusicRef::index).unwrap_or(0),
+            scroll_lock: self.scroll_lock,
+            time_limit: self.time_limit.unwrap_or(0),
+        })
+    }
+
+    /// Whether any object has a type given by name.
+    pub fn has_named_object_types(&self) -> bool {
+        self.objects.iter().any(|object| object.id().is_none())
+    }
+
+    /// Resolve every object type given by name against the object types, replacing each with its
+    /// index within its kind.  Returns every problem found: names that are not types of the
+    /// object's kind.
+    ///
+    /// ```
+    /// use nestools::stage::objects::ObjectTypes;
+    /// use nestools::stage::serialize::Stage;
+    ///
+    /// let types: ObjectTypes = serde_yaml::from_str("
+    /// enemy: [walker, flyer]
+    /// item: [coin]
+    /// ").unwrap();
+    ///
+    /// let mut stage: Stage = serde_yaml::from_str("
+    /// background_palette: [0x0F, 0, 0, 0, 0x0F, 0, 0, 0, 0x0F, 0, 0, 0, 0x0F, 0, 0, 0]
+    /// sprite_palette: [0x0F, 0, 0, 0, 0x0F, 0, 0, 0, 0x0F, 0, 0, 0, 0x0F, 0, 0, 0]
+    /// metatiles:
+    ///   - {name: sky, symbol: '.', palette: 0, tiles: [0, 0, 0, 0]}
+    /// data: '....'
+    /// objects:
+    ///   - {kind: enemy, type: flyer, x: 2, y: 0}
+    ///   - {kind: item, type: flyer, x: 3, y: 0}
+    /// ").unwrap();
+    /// assert_eq!(stage.resolve_object_types(&types).len(), 1);
+    /// assert_eq!(stage.objects[0].id(), Some(0x41));
+    /// ```
+    pub fn resolve_object_types(&mut self, types: &ObjectTypes) -> Vec<String> {
+        let mut problems = Vec::new();
+        for object in &mut self.objects {
+            let name = match object.object_type {
+                Some(ObjectTypeRef::Name(ref name)) => name.clone(),
+                _ => continue,
+            };
+            match types.names(object.kind).iter().position(|type_name| *type_name == name) {
+                Some(index) => object.object_type = Some(ObjectTypeRef::Index(index as u8)),
+                None => problems.push(format!(
+                    "object at {}, {} has type {}, which is not one of the {} types",
+                    object.x, object.y, name, object.kind.name())),
+            }
+        }
+        problems
+    }
+
+    /// The width and height of the stage data, in metatiles.
+    pub fn dimensions(&self) -> (usize, usize) {
+        let width = self.data.lines().next().map(|line| line.chars().count()).unwrap_or(0);
+        (width, self.data.lines().count())
+    }
+
+    /// Build the object table: the number of objects, and then 4 bytes for each, sorted by scroll
+    /// position.  Each object is its position along the scroll direction (its `x` for a
+    /// horizontal stage, or its `y` for a vertical one), its position across it, its type id, and
+    /// its parameter.
+    fn object_table(&self) -> Vec<u8> {
+        let mut entries: Vec<[u8; 4]> = self.objects.iter()
+            .map(|object| {
+                let (scroll, across) = match self.orientation {
+                    Orientation::Horizontal => (object.x, object.y),
+                    Orientation::Vertical => (object.y, object.x),
+                };
+                [scroll, across, object.id().unwrap_or(0), object.parameter]
+            })
+            .collect();
+        entries.sort_by_key(|entry| (entry[0], entry[1]));
+
+        let mut table = vec![entries.len() as u8];
+        for entry in entries {
+            table.extend_from_slice(&entry);
+        }
+        table
+    }
+
+    /// The metatile index of every position of the stage, in storage order, along with the
+    /// length of a storage line.  Symbols that are not metatiles are skipped.
+    fn indices(&self) -> (Vec<u8>, usize) {
         let metatiles: HashMap<char, u8> = self.metatiles.iter()
             .enumerate()
             .map(|(i, metatile)| (metatile.symbol, i as u8))
-            .collect(); 
+            .collect();
 
-        // Stage attribute byte.  Determines orientation and musical track.  Currently, only
-        // orientation
-        let mut attribute_byte = match self.orientation {
-            Orientation::Horizontal => 0u8,
-            Orientation::Vertical => 0b1000000u8,
+        let mut iterators: Vec<_> = self.data.lines().map(|line| line.chars()).collect();
+        let mut chars = Vec::new();
+        let line_length = match &self.orientation {
+            // Build a vector of chars by transposing characters.
+            Orientation::Horizontal => {
+                'outer: loop {
+                    for iterator in &mut iterators {
+                        if let Some(c) = iterator.next() {
+                            chars.push(c);
+                        } else {
+                            break 'outer;
+                        }
+                    }
+                }
+                iterators.len()
+            },
+            // Do straight composition
+            Orientation::Vertical => {
+                let line_length = self.data.lines().next().map(|line| line.chars().count()).unwrap_or(0);
+                for iterator in iterators {
+                    chars.extend(iterator);
+                }
+                line_length
+            },
         };
 
-        write.write(&[attribute_byte])?;
+        let indices = chars.iter().filter_map(|c| metatiles.get(c).cloned()).collect();
+        (indices, line_length)
+    }
 
-        // Write out the palettes literally
-        // This could be eventually optimized a bit.  The NES only has 64 colors, so it's possible
-        // to make each palette item take 6 bits instead of 8, so the full set of 32 colors to take
-        // 24 bytes instead of 32.  Even fewer when you consider sprite palettes and the universal
-        // background color, which is just 25 colors total for a maximum necessary 19 bytes (1 for
-        // universal background and 12 colors for background and foreground palettes at 9 bytes a
-        // piece).
-        write.write(&self.background_palette)?;
-        write.write(&self.sprite_palette)?;
+    /// Build the compressed stage body.  A stage split into screens has a body for each screen,
+    /// and is otherwise a single body.  With `auto` compression, the codec giving the smallest
+    /// total is used for every screen, out of the codecs that can encode every screen.
+    pub fn compress(&self) -> Result<Body, Error> {
+        let (indices, line_length) = self.indices();
+        let screen_length = self.screens
+            .map(|screen| screen.width * screen.height)
+            .filter(|&length| length > 0);
+        let chunks: Vec<&[u8]> = match screen_length {
+            Some(length) => indices.chunks(length).collect(),
+            None => vec![&indices],
+        };
+        let encode = |codec: &'static dyn Codec| -> Result<Body, Error> {
+            Ok(Body {
+                codec,
+                screens: chunks.iter().map(|chunk| codec.encode(chunk, line_length)).collect::<Result<_, _>>()?,
+            })
+        };
+        match self.compression.codec() {
+            Some(codec) => encode(codec),
+            None => Ok(codec::CODECS.iter()
+                .filter_map(|&codec| encode(codec).ok())
+                .min_by_key(|body| (body.length(), body.codec.id()))
+                .expect("the run-length codecs can always encode")),
+        }
+    }
 
-        // Write count of metatiles, should not exceed 16
-        write.write(&[self.metatiles.len() as u8])?;
+    /// Validate the stage, collecting every problem found rather than stopping at the first.  An
+    /// empty list means the stage is valid and can be written.  Tiles given by name must already
+    /// be resolved, and palettes given by name already loaded.
+    ///
+    /// ```
+    /// use nestools::stage::Error;
+    /// use nestools::stage::serialize::Stage;
+    ///
+    /// let stage: Stage = serde_yaml::from_str("
+    /// background_palette: [0x0F, 0, 0, 0, 0x0F, 0, 0, 0, 0x0F, 0, 0, 0, 0x0F, 0, 0, 0]
+    /// sprite_palette: [0x0F, 0, 0, 0, 0x0F, 0, 0, 0, 0x0F, 0, 0, 0, 0x0F, 0, 0, 0]
+    /// metatiles:
+    ///   - {name: sky, symbol: '.', palette: 0, tiles: [0, 0, 0, 0]}
+    /// data: |
+    ///   ....
+    ///   ..x.
+    ///   ...
+    /// ").unwrap();
+    ///
+    /// let errors = stage.validate();
+    /// assert_eq!(errors.len(), 2);
+    /// match errors[0] {
+    ///     Error::SymbolError { line, column, symbol } => assert_eq!((line, column, symbol), (2, 3, 'x')),
+    ///     _ => panic!("expected a symbol error"),
+    /// }
+    /// match errors[1] {
+    ///     Error::LineLengthError { line, length, .. } => assert_eq!((line, length), (3, 3)),
+    ///     _ => panic!("expected a line length error"),
+    /// }
+    /// assert!(stage.write_binary(&mut Vec::new()).is_err());
+    /// ```
+    pub fn validate(&self) -> Vec<Error> {
+        match self.compress() {
+            Ok(body) => self.validate_body(Ok(&body)),
+            Err(err) => self.validate_body(Err(err)),
+        }
+    }
+
+    /// Validate the stage, as [`validate`](#method.validate), with its body already compressed by
+    /// [`compress`](#method.compress).
+    pub fn validate_compressed(&self, body: &Body) -> Vec<Error> {
+        self.validate_body(Ok(body))
+    }
+
+    /// Validate the stage along with its compressed body, or the error compressing it.
+    fn validate_body(&self, body: Result<&Body, Error>) -> Vec<Error> {
+        let mut errors = Vec::new();
+
+        for (name, palette) in &[("background", &self.background_palette), ("sprite", &self.sprite_palette)] {
+            let palette = match palette.colors() {
+                Some(palette) => palette,
+                None => {
+                    errors.push(Error::PaletteError(format!("{} palette is given by name, but was not loaded", name)));
+                    continue;
+                },
+            };
+            for (index, &color) in palette.iter().enumerate() {
+                if color > 0x3F {
+                    errors.push(Error::PaletteError(format!(
+                        "{} palette entry {} is {:#04X}, but NES colors only go up to 0x3F",
+                        name, index, color)));
+                }
+            }
+        }
+
+        if self.palette_encoding == PaletteEncoding::Packed {
+            if let (Some(background), Some(sprite)) = (self.background_palette.colors(), self.sprite_palette.colors()) {
+                if let Err(error) = palettes::pack(background, sprite) {
+                    errors.push(error);
+                }
+            }
+        }
+
+        if self.metatiles.len() > 16 {
+            errors.push(Error::MetatileCountError(self.metatiles.len()));
+        }
 
-        // Simple metatile information
+        if self.flags.len() > MAX_FLAGS {
+            errors.push(Error::FlagError(format!(
+                "stage has {} flags, but can not have more than {}", self.flags.len(), MAX_FLAGS)));
+        }
+        for (index, flag) in self.flags.iter().enumerate() {
+            if self.flags[..index].contains(flag) {
+                errors.push(Error::FlagError(format!("flag {} is listed more than once", flag)));
+            }
+        }
+
+        if let Some(MusicRef::Name(ref name)) = self.music {
+            errors.push(Error::MusicError(format!("music {} is given by name, but was not resolved", name)));
+        }
+
+        let mut symbols: Vec<char> = Vec::new();
+        let mut names: Vec<&str> = Vec::new();
         for metatile in &self.metatiles {
-            // TODO: compress this more.  Palette only needs 2 bits.  This may become a general
-            // attribute byte with a set of bits indicating other tile attributes, such as whether
-            // it is a ground or background tile, whether it deals damage, etc.
-            write.write(&[metatile.palette])?;
-            write.write(&metatile.tiles)?;
+            if symbols.contains(&metatile.symbol) {
+                errors.push(Error::DuplicateSymbolError(metatile.symbol));
+            } else {
+                symbols.push(metatile.symbol);
+            }
+            if names.contains(&metatile.name.as_str()) {
+                errors.push(Error::DuplicateNameError(metatile.name.clone()));
+            } else {
+                names.push(&metatile.name);
+            }
+            if metatile.palette > 3 {
+                errors.push(Error::PaletteError(format!(
+                    "metatile {} has palette {}, but the palette must be between 0 and 3",
+                    metatile.name, metatile.palette)));
+            }
+            for flag in &metatile.flags {
+                if !self.flags.contains(flag) {
+                    errors.push(Error::FlagError(format!(
+                        "metatile {} has flag {}, which is not one of the stage's flags",
+                        metatile.name, flag)));
+                }
+            }
+            for tile in &metatile.tiles {
+                if let TileRef::Name(name) = tile {
+                    errors.push(Error::TileError(format!(
+                        "metatile {} uses tile {} by name, but it was not resolved",
+                        metatile.name, name)));
+                }
+            }
         }
-        // Build list of chars for RLE
-        let mut iterators: Vec<_> = self.data.lines().map(|line| line.chars()).collect();
-        let mut chars = Vec::new();
-        match &self.orientation {
-            // Build a vector of chars by transposing characters.
-            Orientation::Horizontal => 'outer: loop {
-                for iterator in &mut iterators {
-                    if let Some(c) = iterator.next() {
-                        chars.push(c);
-                    } else {
-                        break 'outer;
+
+        let expected = self.data.lines().next().map(|line| line.chars().count()).unwrap_or(0);
+        for (line_number, line) in self.data.lines().enumerate() {
+            let mut length = 0;
+            for (column, c) in line.chars().enumerate() {
+                length += 1;
+                if !symbols.contains(&c) {
+                    errors.push(Error::SymbolError {
+                        line: line_number + 1,
+                        column: column + 1,
+                        symbol: c,
+                    });
+                }
+            }
+            if length != expected {
+                errors.push(Error::LineLengthError {
+                    line: line_number + 1,
+                    column: length.min(expected) + 1,
+                    length,
+                    expected,
+                });
+            }
+        }
+
+        let (width, height) = self.dimensions();
+        if self.objects.len() > 255 {
+            errors.push(Error::ObjectError(format!(
+                "stage has {} objects, but can not have more than 255", self.objects.len())));
+        }
+        if self.objects.iter().filter(|object| object.kind == ObjectKind::PlayerStart).count() > 1 {
+            errors.push(Error::ObjectError(String::from("stage has more than one player start")));
+        }
+        for object in &self.objects {
+            let at = format!("object at {}, {}", object.x, object.y);
+            if object.x as usize >= width || object.y as usize >= height {
+                errors.push(Error::ObjectError(format!(
+                    "{} is outside of the stage, which is {} by {}", at, width, height)));
+            }
+            match object.object_type {
+                Some(_) if object.kind == ObjectKind::PlayerStart => errors.push(Error::ObjectError(format!(
+                    "{} is a player start, which can not have a type", at))),
+                Some(ObjectTypeRef::Name(ref name)) => errors.push(Error::ObjectTypeError(format!(
+                    "{} has type {} by name, but it was not resolved", at, name))),
+                Some(ObjectTypeRef::Index(index)) if index as usize >= MAX_TYPES => errors.push(Error::ObjectError(format!(
+                    "{} has type {}, but types only go up to {}", at, index, MAX_TYPES - 1))),
+                _ => (),
+            }
+        }
+
+        let bodies = match body {
+            Ok(body) => &body.screens,
+            Err(err) => {
+                errors.push(err);
+                return errors;
+            },
+        };
+        match self.screens {
+            Some(screen) => {
+                let whole = match self.orientation {
+                    Orientation::Horizontal => screen.width > 0 && height == screen.height && width.is_multiple_of(screen.width),
+                    Orientation::Vertical => screen.height > 0 && width == screen.width && height.is_multiple_of(screen.height),
+                };
+                if !whole {
+                    let line = match self.orientation {
+                        Orientation::Horizontal => "row",
+                        Orientation::Vertical => "column",
+                    };
+                    errors.push(Error::ScreenError(format!(
+                        "stage is {} by {} metatiles, which is not a {} of whole {} by {} screens",
+                        width, height, line, screen.width, screen.height)));
+                }
+                if bodies.len() > 255 {
+                    errors.push(Error::ScreenError(format!(
+                        "stage has {} screens, but can not have more than 255", bodies.len())));
+                }
+                for (index, body) in bodies.iter().enumerate() {
+                    if body.len() > 255 {
+                        errors.push(Error::ScreenError(format!(
+                            "screen {} compresses to {} bytes, but can not exceed 255", index, body.len())));
                     }
                 }
+                let total: usize = bodies.iter().map(Vec::len).sum();
+                if total > 0xFFFF {
+                    errors.push(Error::ScreenError(format!(
+                        "screens compress to {} bytes, but can not exceed 65535", total)));
+                }
             },
-            // Do straight composition
-            Orientation::Vertical => for iterator in iterators {
-                for c in iterator {
-                    chars.push(c);
+            None => {
+                let body_length = bodies[0].len();
+                if body_length > 255 {
+                    errors.push(Error::BodyLengthError(body_length));
                 }
             },
         }
 
-        // Run-length encode
-        let encoded = run_length_encode(&chars, 16);
-        let mut outbytes = Vec::new();
-        for (c, count) in encoded {
-            if let Some(&index) = metatiles.get(&c) {
-                // Stage bytes are written in two nibbles.  The first is length - 1, and the second
-                // is the index of the metatile in the stage header
-                let outbyte: u8 = (count as u8) - 1 << 4 | 0x0F & index as u8;
-                outbytes.push(outbyte);
+        errors
+    }
+
+    /// Write the binary stage.  The stage is validated first, and the first problem found is
+    /// returned without writing anything.
+    pub fn write_binary(&self, write: &mut dyn Write) -> Result<(), Error> {
+        let body = self.compress()?;
+        if let Some(error) = self.validate_compressed(&body).into_iter().next() {
+            return Err(error);
+        }
+        self.write_binary_unchecked(write, &body)
+    }
+
+    /// Write the binary stage with its compressed body, without validating it, for callers that
+    /// have already validated it themselves.
+    pub(crate) fn write_binary_unchecked(&self, write: &mut dyn Write, body: &Body) -> Result<(), Error> {
+        let (codec, bodies) = (body.codec, &body.screens);
+
+        let header = self.header();
+
+        // Stage attribute byte.  Determines whether the stage has a header, its orientation,
+        // whether it is split into screens, whether its palettes are packed, and the body
+        // compression codec in the low 2 bits
+        let attribute_byte = match header {
+            Some(_) => header::ATTRIBUTE_BIT,
+            None => 0u8,
+        } | match self.orientation {
+            Orientation::Horizontal => 0u8,
+            Orientation::Vertical => 0b1000000u8,
+        } | match self.screens {
+            Some(_) => 0b100000u8,
+            None => 0u8,
+        } | match self.palette_encoding {
+            PaletteEncoding::Raw => 0u8,
+            PaletteEncoding::Packed => palettes::ATTRIBUTE_BIT,
+        } | codec.id();
+
+        write.write_all(&[attribute_byte])?;
+
+        // The header, with the music track and the rest of the stage's settings
+        if let Some(header) = header {
+            write.write_all(&header.to_bytes())?;
+        }
+
+        // Write out the palettes, either literally or packed into 6 bits a color with the
+        // universal background color only stored once
+        if let (Some(background), Some(sprite)) = (self.background_palette.colors(), self.sprite_palette.colors()) {
+            match self.palette_encoding {
+                PaletteEncoding::Raw => {
+                    write.write_all(background)?;
+                    write.write_all(sprite)?;
+                },
+                PaletteEncoding::Packed => write.write_all(&palettes::pack(background, sprite)?)?,
             }
         }
 
-        // Write stage body compressed length in bytes (to allow entering the map from the other side) 
-        write.write(&[outbytes.len() as u8])?;
+        // Write count of metatiles, should not exceed 16
+        write.write_all(&[self.metatiles.len() as u8])?;
+
+        // Simple metatile information, with the palette and flags packed into an attribute byte
+        for metatile in &self.metatiles {
+            write.write_all(&[self.attribute(metatile)?])?;
+            for tile in metatile.tiles.iter().filter_map(TileRef::index) {
+                write.write_all(&[tile])?;
+            }
+        }
+
+        match self.screens {
+            Some(_) => {
+                // Write the screen count, then the offset of every screen from the start of the
+                // first, and then the offset of the end, so the runtime can jump to any screen
+                write.write_all(&[bodies.len() as u8])?;
+                let mut offset = 0;
+                for body in bodies {
+                    write.write_all(&(offset as u16).to_le_bytes())?;
+                    offset += body.len();
+                }
+                write.write_all(&(offset as u16).to_le_bytes())?;
+            },
+            None => {
+                // Write stage body compressed length in bytes (to allow entering the map from the other side) 
+                write.write_all(&[bodies[0].len() as u8])?;
+            },
+        }
 
         // Write stage body
-        write.write(&outbytes)?;
+        for body in bodies {
+            write.write_all(body)?;
+        }
+
+        // Write the object table, in the order the objects scroll into view
+        write.write_all(&self.object_table())?;
         Ok(())
     }
+
+    /// Parse a binary stage, as written by [`write_binary`](#method.write_binary), back into a
+    /// stage.  The binary does not hold the stage's dimensions, so `size` gives the number of data
+    /// lines of a horizontal stage (its height), or the length of each data line of a vertical
+    /// stage (its width), in metatiles.  Metatiles get generated names and symbols.
+    ///
+    /// ```
+    /// use nestools::stage::serialize::Stage;
+    ///
+    /// let stage: Stage = serde_yaml::from_str("
+    /// background_palette: [0x0F, 0x30, 0x10, 0x00, 0x0F, 0x1A, 0x2A, 0x0A, 0x0F, 0, 0, 0, 0x0F, 0, 0, 0]
+    /// sprite_palette: [0x0F, 0x16, 0x27, 0x30, 0x0F, 0, 0, 0, 0x0F, 0, 0, 0, 0x0F, 0, 0, 0]
+    /// metatiles:
+    ///   - {name: sky, symbol: '.', palette: 0, tiles: [0, 0, 0, 0]}
+    ///   - {name: ground, symbol: '=', palette: 1, tiles: [1, 2, 3, 4]}
+    /// data: |
+    ///   ...=
+    ///   ..==
+    ///   ====
+    /// ").unwrap();
+    ///
+    /// let mut binary = Vec::new();
+    /// stage.write_binary(&mut binary).unwrap();
+    ///
+    /// let parsed = Stage::parse(&binary, 3).unwrap();
+    /// assert_eq!(parsed.data, "0001\n0011\n1111\n");
+    /// assert_eq!(parsed.metatiles[1].tiles[3].index(), Some(4));
+    ///
+    /// let mut round_trip = Vec::new();
+    /// parsed.write_binary(&mut round_trip).unwrap();
+    /// assert_eq!(binary, round_trip);
+    ///
+    /// // A player start can not have a type, so its low type bits must be clear
+    /// *binary.last_mut().unwrap() = 1;
+    /// binary.extend_from_slice(&[0, 0, 0x05, 0]);
+    /// assert_eq!(
+    ///     Stage::parse(&binary, 3).unwrap_err().to_string(),
+    ///     "object 0 is a player start, but has type 5");
+    /// ```
+    pub fn parse(bytes: &[u8], size: usize) -> Result<Stage, Error> {
+        let mut position = 0;
+        let mut take = |count: usize, what: &str| -> Result<&[u8], Error> {
+            if position + count > bytes.len() {
+                return Err(Error::FormatError(format!(
+                    "stage ends at byte {} in the middle of the {}",
+                    bytes.len(), what)));
+            }
+            position += count;
+            Ok(&bytes[(position - count)..position])
+        };
+
+        let attribute = take(1, "attribute byte")?[0];
+        if attribute & !(0x63 | header::ATTRIBUTE_BIT | palettes::ATTRIBUTE_BIT) != 0 {
+            return Err(Error::FormatError(format!(
+                "attribute byte {:#04X} has unknown bits set", attribute)));
+        }
+        let orientation = if attribute & 0x40 == 0 {
+            Orientation::Horizontal
+        } else {
+            Orientation::Vertical
+        };
+        let codec = codec::codec(attribute & 0x03).expect("every 2-bit codec id exists");
+        let header = match attribute & header::ATTRIBUTE_BIT {
+            0 => None,
+            _ => {
+                let bytes = take(header::LENGTH, "header")?;
+                let bytes = <&[u8; header::LENGTH]>::try_from(bytes).expect("take gives the length asked for");
+                Some(Header::from_bytes(bytes)?)
+            },
+        };
+
+        let (palette_encoding, background_palette, sprite_palette) = if attribute & palettes::ATTRIBUTE_BIT == 0 {
+            let mut background_palette = [0; 16];
+            background_palette.copy_from_slice(take(16, "background palette")?);
+            let mut sprite_palette = [0; 16];
+            sprite_palette.copy_from_slice(take(16, "sprite palette")?);
+            (PaletteEncoding::Raw, background_palette, sprite_palette)
+        } else {
+            let bytes = take(palettes::PACKED_LENGTH, "packed palettes")?;
+            let bytes = <&[u8; palettes::PACKED_LENGTH]>::try_from(bytes).expect("take gives the length asked for");
+            let (background_palette, sprite_palette) = palettes::unpack(bytes);
+            (PaletteEncoding::Packed, background_palette, sprite_palette)
+        };
+
+        let count = take(1, "metatile count")?[0] as usize;
+        if count > DECOMPILED_SYMBOLS.len() {
+            return Err(Error::FormatError(format!(
+                "stage has {} metatiles, but can not have more than 16", count)));
+        }
+        // The flag names are lost, so the default names are used, with generated names for the
+        // flag bits past them
+        let mut flags = default_flags();
+        let mut metatiles = Vec::new();
+        for (index, symbol) in DECOMPILED_SYMBOLS.chars().take(count).enumerate() {
+            let metatile = take(5, "metatile table")?;
+            let used = (0..MAX_FLAGS).filter(|bit| metatile[0] & 1 << (bit + FLAG_SHIFT) != 0);
+            let mut metatile_flags = Vec::new();
+            for bit in used {
+                while flags.len() <= bit {
+                    flags.push(format!("flag_{}", flags.len()));
+                }
+                metatile_flags.push(flags[bit].clone());
+            }
+            metatiles.push(Metatile {
+                name: format!("metatile_{}", index),
+                symbol,
+                tiles: [
+                    TileRef::Index(metatile[1]),
+                    TileRef::Index(metatile[2]),
+                    TileRef::Index(metatile[3]),
+                    TileRef::Index(metatile[4]),
+                ],
+                palette: metatile[0] & 0x03,
+                flags: metatile_flags,
+            });
+        }
+
+        let (indices, screens) = if attribute & 0x20 == 0 {
+            let length = take(1, "body length")?[0] as usize;
+            (codec.decode(take(length, "body")?)?, None)
+        } else {
+            let screen_count = take(1, "screen count")?[0] as usize;
+            let offsets: Vec<usize> = take((screen_count + 1) * 2, "screen offset table")?
+                .chunks(2)
+                .map(|offset| u16::from_le_bytes([offset[0], offset[1]]) as usize)
+                .collect();
+            if offsets[0] != 0 || offsets.windows(2).any(|pair| pair[0] > pair[1]) {
+                return Err(Error::FormatError(String::from("screen offset table is out of order")));
+            }
+            let body = take(offsets[screen_count], "screens")?;
+
+            let mut indices = Vec::new();
+            let mut screen_length = None;
+            for (index, pair) in offsets.windows(2).enumerate() {
+                let screen = codec.decode(&body[pair[0]..pair[1]])?;
+                if *screen_length.get_or_insert(screen.len()) != screen.len() {
+                    return Err(Error::FormatError(format!(
+                        "screen {} has {} metatiles, but the first screen has {}",
+                        index, screen.len(), screen_length.unwrap_or(0))));
+                }
+                indices.extend(screen);
+            }
+
+            // The screen is as long as the stage in one direction, so its other side follows
+            let screen_length = screen_length.unwrap_or(0);
+            if size == 0 || !screen_length.is_multiple_of(size) {
+                return Err(Error::FormatError(format!(
+                    "screens have {} metatiles, which is not a whole number of lines of {}",
+                    screen_length, size)));
+            }
+            let screen = match orientation {
+                Orientation::Horizontal => ScreenSize { width: screen_length / size, height: size },
+                Orientation::Vertical => ScreenSize { width: size, height: screen_length / size },
+            };
+            (indices, Some(screen))
+        };
+
+        let object_count = take(1, "object count")?[0] as usize;
+        let mut objects = Vec::new();
+        for entry in take(object_count * 4, "object table")?.chunks(4) {
+            let (x, y) = match orientation {
+                Orientation::Horizontal => (entry[0], entry[1]),
+                Orientation::Vertical => (entry[1], entry[0]),
+            };
+            let kind = ObjectKind::from_id(entry[2]);
+            let object_type = match (kind, entry[2] & !kind.id()) {
+                (ObjectKind::PlayerStart, 0) => None,
+                (ObjectKind::PlayerStart, index) => return Err(Error::FormatError(format!(
+                    "object {} is a player start, but has type {}", objects.len(), index))),
+                (_, index) => Some(ObjectTypeRef::Index(index)),
+            };
+            objects.push(Object {
+                kind,
+                object_type,
+                x,
+                y,
+                parameter: entry[3],
+            });
+        }
+
+        if position != bytes.len() {
+            return Err(Error::FormatError(format!(
+                "stage has {} extra bytes after the object table", bytes.len() - position)));
+        }
+
+        if let Some(&index) = indices.iter().find(|&&index| index as usize >= count) {
+            return Err(Error::FormatError(format!(
+                "body uses metatile {}, but there are only {}", index, count)));
+        }
+        if size == 0 || !indices.len().is_multiple_of(size) {
+            return Err(Error::FormatError(format!(
+                "body has {} metatiles, which is not a whole number of lines of {}",
+                indices.len(), size)));
+        }
+
+        let symbols: Vec<char> = DECOMPILED_SYMBOLS.chars().collect();
+        let symbol = |index: usize| symbols[indices[index] as usize];
+        let other = indices.len() / size;
+        let mut data = String::new();
+        match orientation {
+            // Horizontal stages are stored column by column, with size lines
+            Orientation::Horizontal => for line in 0..size {
+                data.extend((0..other).map(|column| symbol(column * size + line)));
+                data.push('\n');
+            },
+            Orientation::Vertical => for line in 0..other {
+                data.extend((0..size).map(|column| symbol(line * size + column)));
+                data.push('\n');
+            },
+        }
+
+        Ok(Stage {
+            orientation,
+            compression: Compression::from_codec(codec).expect("every codec has a compression"),
+            palette_encoding,
+            screens,
+            // The music is always kept, so that a header of all zeros is still written back
+            music: header.map(|header| MusicRef::Index(header.music)),
+            scroll_lock: header.map(|header| header.scroll_lock).unwrap_or(false),
+            time_limit: header.map(|header| header.time_limit).filter(|&limit| limit != 0),
+            palettes: None,
+            sprites: None,
+            page: None,
+            object_types: None,
+            tracks: None,
+            tiled: None,
+            flags,
+            background_palette: PaletteRef::Colors(background_palette),
+            sprite_palette: PaletteRef::Colors(sprite_palette),
+            metatiles,
+            data,
+            objects,
+        })
+    }
 }
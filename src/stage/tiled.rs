@@ -0,0 +1,601 @@
+//! Importing stages from [Tiled](https://www.mapeditor.org/) maps, in either the `.tmx` XML
+//! format or the JSON format.
+//!
+//! A stage with a [`TiledImport`](struct.TiledImport.html) has its data built from a tile layer
+//! of the map, with each tile's global id (GID) mapped to a metatile by a table in the stage, and
+//! its objects from an object layer.  Only finite, orthogonal maps with CSV or XML tile data are
+//! supported; set the layer format to CSV in Tiled's map properties.
+//!
+//! Stages can also be exported to a TMX map with [`export_tmx`](fn.export_tmx.html), along with
+//! a tileset image of their metatiles from
+//! [`preview::render_metatiles`](../preview/fn.render_metatiles.html).  The tileset keeps each
+//! metatile's symbol as a custom property, so an exported map imports back without a tiles table.
+//!
+//! Each Tiled object becomes a stage [`Object`](../objects/struct.Object.html): its type (or
+//! class) is the object kind, like `enemy`, its name is the object type, like `walker`, and an
+//! integer `parameter` custom property is its parameter.  Its position is rounded down to the
+//! metatile it is in, with the map's tile size as the metatile size.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+
+use xmltree::{Element, XMLNode};
+
+use super::Error;
+use super::objects::{Object, ObjectTypeRef};
+use super::preview::METATILE_SIZE;
+use super::serialize::Stage;
+
+/// The bits of a GID that flip or rotate the tile, rather than pick it.  Background tiles can not
+/// be flipped on the NES, so these must be clear.
+const FLIP_FLAGS: u32 = 0xF000_0000;
+
+/// The Tiled import configuration of a stage.
+///
+/// ```yaml
+/// tiled:
+///   map: level1.tmx
+///   layer: terrain
+///   tiles:
+///     0: sky
+///     1: ground
+///     2: brick
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TiledImport {
+    /// The map file.  Files ending in `.json` or `.tmj` are read as JSON, and anything else as
+    /// TMX.
+    pub map: String,
+
+    /// The name of the tile layer to use.  Defaults to the first tile layer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub layer: Option<String>,
+
+    /// The name of the object layer to use.  Defaults to the first object layer, if there is one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub object_layer: Option<String>,
+
+    /// The name of the metatile for each GID.  GID 0 is an empty tile.  GIDs that are not given
+    /// use the `symbol` custom property of their tile in the map's tileset, as exported maps have.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tiles: BTreeMap<u32, String>,
+}
+
+/// A single object of a Tiled object layer.
+#[derive(Debug, Clone, Default)]
+pub struct TiledObject {
+    pub name: String,
+
+    /// The type, or class in newer versions of Tiled
+    pub class: String,
+
+    /// The position of the object's top left corner, in pixels
+    pub x: f64,
+    pub y: f64,
+
+    /// Custom properties, as strings
+    pub properties: HashMap<String, String>,
+}
+
+/// A loaded Tiled map, with a single tile layer and a single object layer.
+#[derive(Debug, Clone, Default)]
+pub struct TiledMap {
+    /// The size of the tile layer, in tiles
+    pub width: usize,
+    pub height: usize,
+
+    /// The size of a single tile, in pixels
+    pub tile_width: usize,
+    pub tile_height: usize,
+
+    /// The GID of every tile, row by row.  None of them are flipped or rotated.
+    pub tiles: Vec<u32>,
+
+    pub objects: Vec<TiledObject>,
+
+    /// The stage symbol of each GID, from the `symbol` custom property of the tiles of the map's
+    /// embedded tilesets
+    pub symbols: HashMap<u32, char>,
+}
+
+/// A single Tiled layer, as found while walking the map and its groups
+enum Layer {
+    Tiles {
+        name: String,
+        width: usize,
+        height: usize,
+        tiles: Vec<u32>,
+    },
+    Objects {
+        name: String,
+        objects: Vec<TiledObject>,
+    },
+}
+
+fn format_error(message: String) -> Error {
+    Error::TiledError(message)
+}
+
+/// The custom properties of a TMX element, as strings
+fn tmx_properties(element: &Element) -> HashMap<String, String> {
+    let mut properties = HashMap::new();
+    if let Some(list) = element.get_child("properties") {
+        for property in list.children.iter().filter_map(XMLNode::as_element) {
+            let value = match property.attributes.get("value") {
+                Some(value) => value.clone(),
+                None => property.get_text().unwrap_or_default().into_owned(),
+            };
+            properties.insert(property.attributes.get("name").cloned().unwrap_or_default(), value);
+        }
+    }
+    properties
+}
+
+/// Add the symbol of a tileset tile, if it has one
+fn add_symbol(symbols: &mut HashMap<u32, char>, gid: u32, symbol: Option<&String>) -> Result<(), Error> {
+    if let Some(symbol) = symbol {
+        let mut chars = symbol.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => symbols.insert(gid, c),
+            _ => return Err(format_error(format!("tile {} has symbol {:?}, which is not a single character", gid, symbol))),
+        };
+    }
+    Ok(())
+}
+
+/// Parse a number, naming what it is if it fails
+fn number<T: std::str::FromStr>(value: &str, what: &str) -> Result<T, Error> {
+    match value.trim().parse() {
+        Ok(value) => Ok(value),
+        Err(_) => Err(format_error(format!("{} {:?} is not a number", what, value))),
+    }
+}
+
+impl TiledMap {
+    /// Load a map from a file, picking the format by extension.
+    pub fn load(filename: &str, layer: Option<&str>, object_layer: Option<&str>) -> Result<TiledMap, Error> {
+        let text = match fs::read_to_string(filename) {
+            Ok(text) => text,
+            Err(err) => return Err(format_error(format!("{}: {}", filename, err))),
+        };
+        let result = if filename.ends_with(".json") || filename.ends_with(".tmj") {
+            TiledMap::parse_json(&text, layer, object_layer)
+        } else {
+            TiledMap::parse_tmx(&text, layer, object_layer)
+        };
+        result.map_err(|err| format_error(format!("{}: {}", filename, err)))
+    }
+
+    /// Build the map from every layer found, picking the named layers or the first of each.
+    fn from_layers(header: TiledMap, layers: Vec<Layer>, layer: Option<&str>, object_layer: Option<&str>) -> Result<TiledMap, Error> {
+        let mut map = header;
+        let mut found_tiles = false;
+        let mut found_objects = false;
+        for found in layers {
+            match found {
+                Layer::Tiles { name, width, height, tiles } => {
+                    if found_tiles || layer.map(|layer| layer != name).unwrap_or(false) {
+                        continue;
+                    }
+                    if tiles.len() != width * height {
+                        return Err(format_error(format!(
+                            "layer {} has {} tiles, but is {} by {}", name, tiles.len(), width, height)));
+                    }
+                    if let Some(index) = tiles.iter().position(|gid| gid & FLIP_FLAGS != 0) {
+                        return Err(format_error(format!(
+                            "layer {} has a flipped or rotated tile at ({}, {}), which can not be shown",
+                            name, index % width, index / width)));
+                    }
+                    map.width = width;
+                    map.height = height;
+                    map.tiles = tiles;
+                    found_tiles = true;
+                },
+                Layer::Objects { name, objects } => {
+                    if found_objects || object_layer.map(|layer| layer != name).unwrap_or(false) {
+                        continue;
+                    }
+                    map.objects = objects;
+                    found_objects = true;
+                },
+            }
+        }
+
+        if !found_tiles {
+            return Err(format_error(match layer {
+                Some(layer) => format!("there is no tile layer named {}", layer),
+                None => String::from("there is no tile layer"),
+            }));
+        }
+        if let (Some(layer), false) = (object_layer, found_objects) {
+            return Err(format_error(format!("there is no object layer named {}", layer)));
+        }
+        if map.tile_width == 0 || map.tile_height == 0 {
+            return Err(format_error(String::from("the map's tile size is 0")));
+        }
+        Ok(map)
+    }
+
+    /// Parse a map in the TMX XML format.
+    ///
+    /// ```
+    /// use nestools::stage::tiled::TiledMap;
+    ///
+    /// let map = TiledMap::parse_tmx(r#"<?xml version="1.0" encoding="UTF-8"?>
+    /// <map version="1.10" orientation="orthogonal" width="3" height="2" tilewidth="16" tileheight="16" infinite="0">
+    ///  <layer id="1" name="terrain" width="3" height="2">
+    ///   <data encoding="csv">
+    /// 1,1,2,
+    /// 2,2,2
+    /// </data>
+    ///  </layer>
+    ///  <objectgroup id="2" name="objects">
+    ///   <object id="1" name="walker" type="enemy" x="32" y="8"/>
+    ///  </objectgroup>
+    /// </map>"#, None, None).unwrap();
+    /// assert_eq!((map.width, map.height), (3, 2));
+    /// assert_eq!(map.tiles, [1, 1, 2, 2, 2, 2]);
+    /// assert_eq!(map.objects[0].class, "enemy");
+    ///
+    /// // The high bit of a GID flips the tile horizontally
+    /// let flipped = TiledMap::parse_tmx(r#"<?xml version="1.0" encoding="UTF-8"?>
+    /// <map version="1.10" orientation="orthogonal" width="2" height="1" tilewidth="16" tileheight="16" infinite="0">
+    ///  <layer id="1" name="terrain" width="2" height="1">
+    ///   <data encoding="csv">1,2147483650</data>
+    ///  </layer>
+    /// </map>"#, None, None);
+    /// match flipped {
+    ///     Err(err) => assert_eq!(err.to_string(), "layer terrain has a flipped or rotated tile at (1, 0), which can not be shown"),
+    ///     Ok(_) => panic!("flipped tiles can not be imported"),
+    /// }
+    /// ```
+    pub fn parse_tmx(text: &str, layer: Option<&str>, object_layer: Option<&str>) -> Result<TiledMap, Error> {
+        let root = match Element::parse(text.as_bytes()) {
+            Ok(root) => root,
+            Err(err) => return Err(format_error(err.to_string())),
+        };
+        let attribute = |element: &Element, name: &str| -> Result<String, Error> {
+            match element.attributes.get(name) {
+                Some(value) => Ok(value.clone()),
+                None => Err(format_error(format!("{} is missing its {} attribute", element.name, name))),
+            }
+        };
+
+        if root.attributes.get("infinite").map(|infinite| infinite == "1").unwrap_or(false) {
+            return Err(format_error(String::from("infinite maps are not supported")));
+        }
+        let mut header = TiledMap {
+            tile_width: number(&attribute(&root, "tilewidth")?, "tile width")?,
+            tile_height: number(&attribute(&root, "tileheight")?, "tile height")?,
+            ..TiledMap::default()
+        };
+        for tileset in root.children.iter().filter_map(XMLNode::as_element).filter(|child| child.name == "tileset") {
+            let first: u32 = number(&attribute(tileset, "firstgid")?, "first GID")?;
+            for tile in tileset.children.iter().filter_map(XMLNode::as_element).filter(|child| child.name == "tile") {
+                let id: u32 = number(&attribute(tile, "id")?, "tile id")?;
+                add_symbol(&mut header.symbols, first + id, tmx_properties(tile).get("symbol"))?;
+            }
+        }
+
+        fn walk(element: &Element, layers: &mut Vec<Layer>) -> Result<(), Error> {
+            for child in element.children.iter().filter_map(XMLNode::as_element) {
+                let name = child.attributes.get("name").cloned().unwrap_or_default();
+                match child.name.as_str() {
+                    "group" => walk(child, layers)?,
+                    "layer" => {
+                        let data = match child.get_child("data") {
+                            Some(data) => data,
+                            None => return Err(format_error(format!("layer {} has no data", name))),
+                        };
+                        let tiles = match data.attributes.get("encoding").map(String::as_str) {
+                            Some("csv") => data.get_text().unwrap_or_default()
+                                .split(',')
+                                .filter(|gid| !gid.trim().is_empty())
+                                .map(|gid| number::<u32>(gid, "tile"))
+                                .collect::<Result<Vec<u32>, Error>>()?,
+                            None => data.children.iter()
+                                .filter_map(XMLNode::as_element)
+                                .map(|tile| match tile.attributes.get("gid") {
+                                    Some(gid) => number::<u32>(gid, "tile"),
+                                    None => Ok(0),
+                                })
+                                .collect::<Result<Vec<u32>, Error>>()?,
+                            Some(encoding) => return Err(format_error(format!(
+                                "layer {} uses {} encoding, but only CSV and XML are supported", name, encoding))),
+                        };
+                        layers.push(Layer::Tiles {
+                            width: number(child.attributes.get("width").map(String::as_str).unwrap_or(""), "layer width")?,
+                            height: number(child.attributes.get("height").map(String::as_str).unwrap_or(""), "layer height")?,
+                            name,
+                            tiles,
+                        });
+                    },
+                    "objectgroup" => {
+                        let mut objects = Vec::new();
+                        for object in child.children.iter().filter_map(XMLNode::as_element).filter(|object| object.name == "object") {
+                            let get = |name: &str| object.attributes.get(name).cloned().unwrap_or_default();
+                            let mut y: f64 = number(&get("y"), "object y")?;
+                            // Tile objects are placed by their bottom left corner
+                            if object.attributes.contains_key("gid") {
+                                y -= number::<f64>(&get("height"), "object height")?;
+                            }
+                            objects.push(TiledObject {
+                                name: get("name"),
+                                class: object.attributes.get("type").or_else(|| object.attributes.get("class")).cloned().unwrap_or_default(),
+                                x: number(&get("x"), "object x")?,
+                                y,
+                                properties: tmx_properties(object),
+                            });
+                        }
+                        layers.push(Layer::Objects { name, objects });
+                    },
+                    _ => (),
+                }
+            }
+            Ok(())
+        }
+
+        let mut layers = Vec::new();
+        walk(&root, &mut layers)?;
+        TiledMap::from_layers(header, layers, layer, object_layer)
+    }
+
+    /// Parse a map in the JSON format.
+    ///
+    /// ```
+    /// use nestools::stage::tiled::TiledMap;
+    ///
+    /// let map = TiledMap::parse_json(r#"{
+    ///   "width": 2, "height": 2, "tilewidth": 16, "tileheight": 16, "infinite": false,
+    ///   "layers": [
+    ///     {"type": "tilelayer", "name": "terrain", "width": 2, "height": 2, "data": [0, 1, 1, 1]},
+    ///     {"type": "objectgroup", "name": "objects", "objects": [
+    ///       {"name": "", "type": "player_start", "x": 0, "y": 0},
+    ///       {"name": "coin", "class": "item", "x": 16, "y": 0,
+    ///        "properties": [{"name": "parameter", "type": "int", "value": 3}]}
+    ///     ]}
+    ///   ]
+    /// }"#, None, None).unwrap();
+    /// assert_eq!(map.tiles, [0, 1, 1, 1]);
+    /// assert_eq!(map.objects[1].class, "item");
+    /// assert_eq!(map.objects[1].properties["parameter"], "3");
+    /// ```
+    pub fn parse_json(text: &str, layer: Option<&str>, object_layer: Option<&str>) -> Result<TiledMap, Error> {
+        #[derive(Deserialize)]
+        struct JsonProperty {
+            name: String,
+            value: serde_json::Value,
+        }
+
+        #[derive(Deserialize)]
+        struct JsonObject {
+            #[serde(default)]
+            name: String,
+            #[serde(default, rename = "type")]
+            object_type: String,
+            #[serde(default)]
+            class: String,
+            x: f64,
+            y: f64,
+            #[serde(default)]
+            height: f64,
+            #[serde(default)]
+            gid: Option<u32>,
+            #[serde(default)]
+            properties: Vec<JsonProperty>,
+        }
+
+        #[derive(Deserialize)]
+        struct JsonLayer {
+            #[serde(rename = "type")]
+            layer_type: String,
+            #[serde(default)]
+            name: String,
+            #[serde(default)]
+            width: usize,
+            #[serde(default)]
+            height: usize,
+            #[serde(default)]
+            encoding: Option<String>,
+            #[serde(default)]
+            data: serde_json::Value,
+            #[serde(default)]
+            objects: Vec<JsonObject>,
+            #[serde(default)]
+            layers: Vec<JsonLayer>,
+        }
+
+        #[derive(Deserialize)]
+        struct JsonTile {
+            id: u32,
+            #[serde(default)]
+            properties: Vec<JsonProperty>,
+        }
+
+        #[derive(Deserialize)]
+        struct JsonTileset {
+            firstgid: u32,
+            #[serde(default)]
+            tiles: Vec<JsonTile>,
+        }
+
+        #[derive(Deserialize)]
+        struct JsonMap {
+            tilewidth: usize,
+            tileheight: usize,
+            #[serde(default)]
+            infinite: bool,
+            layers: Vec<JsonLayer>,
+            #[serde(default)]
+            tilesets: Vec<JsonTileset>,
+        }
+
+        // Properties are kept as strings, like TMX has them
+        fn properties(properties: Vec<JsonProperty>) -> HashMap<String, String> {
+            properties.into_iter()
+                .map(|property| {
+                    let value = match property.value {
+                        serde_json::Value::String(value) => value,
+                        value => value.to_string(),
+                    };
+                    (property.name, value)
+                })
+                .collect()
+        }
+
+        let json: JsonMap = match serde_json::from_str(text) {
+            Ok(json) => json,
+            Err(err) => return Err(format_error(err.to_string())),
+        };
+        if json.infinite {
+            return Err(format_error(String::from("infinite maps are not supported")));
+        }
+
+        fn walk(json_layers: Vec<JsonLayer>, layers: &mut Vec<Layer>) -> Result<(), Error> {
+            for json_layer in json_layers {
+                match json_layer.layer_type.as_str() {
+                    "group" => walk(json_layer.layers, layers)?,
+                    "tilelayer" => {
+                        if let Some(encoding) = json_layer.encoding.filter(|encoding| encoding != "csv") {
+                            return Err(format_error(format!(
+                                "layer {} uses {} encoding, but only CSV is supported", json_layer.name, encoding)));
+                        }
+                        let tiles: Vec<u32> = match serde_json::from_value(json_layer.data) {
+                            Ok(tiles) => tiles,
+                            Err(err) => return Err(format_error(format!("layer {}: {}", json_layer.name, err))),
+                        };
+                        layers.push(Layer::Tiles {
+                            name: json_layer.name,
+                            width: json_layer.width,
+                            height: json_layer.height,
+                            tiles,
+                        });
+                    },
+                    "objectgroup" => layers.push(Layer::Objects {
+                        name: json_layer.name,
+                        objects: json_layer.objects.into_iter()
+                            .map(|object| TiledObject {
+                                class: if object.object_type.is_empty() { object.class } else { object.object_type },
+                                name: object.name,
+                                x: object.x,
+                                // Tile objects are placed by their bottom left corner
+                                y: if object.gid.is_some() { object.y - object.height } else { object.y },
+                                properties: properties(object.properties),
+                            })
+                            .collect(),
+                    }),
+                    _ => (),
+                }
+            }
+            Ok(())
+        }
+
+        let mut header = TiledMap {
+            tile_width: json.tilewidth,
+            tile_height: json.tileheight,
+            ..TiledMap::default()
+        };
+        for tileset in json.tilesets {
+            for tile in tileset.tiles {
+                add_symbol(&mut header.symbols, tileset.firstgid + tile.id, properties(tile.properties).get("symbol"))?;
+            }
+        }
+        let mut layers = Vec::new();
+        walk(json.layers, &mut layers)?;
+        TiledMap::from_layers(header, layers, layer, object_layer)
+    }
+}
+
+/// Escape a string for an XML attribute
+fn escape(value: &str) -> String {
+    value.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Export a stage as a TMX map, with an embedded tileset using the tileset image `image`, as
+/// rendered by [`preview::render_metatiles`](../preview/fn.render_metatiles.html).  Each tile of
+/// the tileset keeps its metatile's name and symbol as custom properties, so the map imports back
+/// into exactly the same stage data.  The objects are given separately, so their types can be
+/// kept by name from before they were resolved.
+///
+/// ```
+/// use nestools::stage::serialize::Stage;
+/// use nestools::stage::tiled::{export_tmx, TiledMap};
+///
+/// let stage: Stage = serde_yaml::from_str("
+/// background_palette: [0x0F, 0, 0, 0, 0x0F, 0, 0, 0, 0x0F, 0, 0, 0, 0x0F, 0, 0, 0]
+/// sprite_palette: [0x0F, 0, 0, 0, 0x0F, 0, 0, 0, 0x0F, 0, 0, 0, 0x0F, 0, 0, 0]
+/// metatiles:
+///   - {name: sky, symbol: '.', palette: 0, tiles: [0, 0, 0, 0]}
+///   - {name: ground, symbol: '=', palette: 1, tiles: [1, 2, 3, 4]}
+/// data: |
+///   ..=
+///   ===
+/// objects:
+///   - {kind: enemy, type: walker, x: 1, y: 0, parameter: 2}
+/// ").unwrap();
+///
+/// let tmx = export_tmx(&stage, &stage.objects, "level.png");
+/// let map = TiledMap::parse_tmx(&tmx, None, None).unwrap();
+/// assert_eq!(map.tiles, [1, 1, 2, 2, 2, 2]);
+/// assert_eq!(map.symbols[&2], '=');
+/// assert_eq!((map.objects[0].name.as_str(), map.objects[0].x), ("walker", 16.0));
+/// ```
+pub fn export_tmx(stage: &Stage, objects: &[Object], image: &str) -> String {
+    let (width, height) = stage.dimensions();
+    let count = stage.metatiles.len();
+    let mut tmx = String::new();
+    tmx.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    tmx.push_str(&format!(
+        "<map version=\"1.8\" orientation=\"orthogonal\" renderorder=\"right-down\" width=\"{}\" height=\"{}\" tilewidth=\"{size}\" tileheight=\"{size}\" infinite=\"0\" nextlayerid=\"3\" nextobjectid=\"{}\">\n",
+        width, height, objects.len() + 1, size = METATILE_SIZE));
+    tmx.push_str(&format!(
+        " <tileset firstgid=\"1\" name=\"metatiles\" tilewidth=\"{size}\" tileheight=\"{size}\" tilecount=\"{count}\" columns=\"{count}\">\n",
+        size = METATILE_SIZE, count = count));
+    tmx.push_str(&format!(
+        "  <image source=\"{}\" width=\"{}\" height=\"{}\"/>\n",
+        escape(image), count * METATILE_SIZE, METATILE_SIZE));
+    for (index, metatile) in stage.metatiles.iter().enumerate() {
+        tmx.push_str(&format!("  <tile id=\"{}\">\n   <properties>\n", index));
+        tmx.push_str(&format!("    <property name=\"metatile\" value=\"{}\"/>\n", escape(&metatile.name)));
+        tmx.push_str(&format!("    <property name=\"symbol\" value=\"{}\"/>\n", escape(&metatile.symbol.to_string())));
+        tmx.push_str("   </properties>\n  </tile>\n");
+    }
+    tmx.push_str(" </tileset>\n");
+
+    tmx.push_str(&format!(" <layer id=\"1\" name=\"terrain\" width=\"{}\" height=\"{}\">\n  <data encoding=\"csv\">\n", width, height));
+    let rows: Vec<String> = stage.data.lines()
+        .map(|line| {
+            let gids: Vec<String> = line.chars()
+                .map(|symbol| match stage.metatiles.iter().position(|metatile| metatile.symbol == symbol) {
+                    Some(index) => (index + 1).to_string(),
+                    None => String::from("0"),
+                })
+                .collect();
+            gids.join(",")
+        })
+        .collect();
+    tmx.push_str(&rows.join(",\n"));
+    tmx.push_str("\n</data>\n </layer>\n");
+
+    tmx.push_str(" <objectgroup id=\"2\" name=\"objects\">\n");
+    for (index, object) in objects.iter().enumerate() {
+        let name = match object.object_type {
+            Some(ObjectTypeRef::Index(index)) => index.to_string(),
+            Some(ObjectTypeRef::Name(ref name)) => name.clone(),
+            None => String::new(),
+        };
+        tmx.push_str(&format!(
+            "  <object id=\"{}\" name=\"{}\" type=\"{}\" x=\"{}\" y=\"{}\" width=\"{size}\" height=\"{size}\">\n",
+            index + 1, escape(&name), object.kind.name(),
+            object.x as usize * METATILE_SIZE, object.y as usize * METATILE_SIZE, size = METATILE_SIZE));
+        tmx.push_str(&format!(
+            "   <properties>\n    <property name=\"parameter\" type=\"int\" value=\"{}\"/>\n   </properties>\n  </object>\n",
+            object.parameter));
+    }
+    tmx.push_str(" </objectgroup>\n</map>\n");
+    tmx
+}